@@ -6,16 +6,17 @@ extern crate env_logger;
 extern crate kexlinux;
 extern crate syslinux_conf;
 
-fn kexlinux_from_mount(matches: &clap::ArgMatches)
-        -> Result<kexlinux::KexLinux, kexlinux::KexLinuxError> {
-    let root_dir = matches.value_of("ROOT DIR").unwrap();
-    let root_dir = std::path::PathBuf::from(root_dir);
+fn root_from_mount(matches: &clap::ArgMatches) -> std::path::PathBuf {
+    std::path::PathBuf::from(matches.value_of("ROOT DIR").unwrap())
+}
 
-    match matches.value_of("CONF FILE PATH") {
+fn registry_from_mount(matches: &clap::ArgMatches)
+        -> Result<kexlinux::Registry, kexlinux::KexLinuxError> {
+    let mut registry = kexlinux::Registry::empty();
+    let mut source = match matches.value_of("CONF FILE PATH") {
         Some(conf_path) => {
             let conf_path = std::path::PathBuf::from(conf_path);
-            kexlinux::KexLinux::from_local_conf_file_path(
-                root_dir, conf_path)
+            kexlinux::SyslinuxSource::with_conf_file_path(conf_path)
         }
 
         None => {
@@ -27,15 +28,36 @@ fn kexlinux_from_mount(matches: &clap::ArgMatches)
                         "extlinux" => syslinux_conf::LocalConfType::ExtLinux,
                         _ => panic!("This will never happen"),
                     };
-                    kexlinux::KexLinux::from_local_type(root_dir, conf_type)
+                    kexlinux::SyslinuxSource::with_type(conf_type)
                 }
 
                 None => {
-                    kexlinux::KexLinux::from_local(root_dir)
-                }
+                    registry = kexlinux::Registry::new();
+                    kexlinux::SyslinuxSource::new()
+                },
             }
         }
-    }
+    };
+
+    source.set_verifier(try!(verifier(matches, "verify conf")));
+    registry.register(Box::new(source));
+    Ok(registry)
+}
+
+// Built-in sources, with syslinux one verifying configuration.
+fn registry_from_dev(matches: &clap::ArgMatches)
+        -> Result<kexlinux::Registry, kexlinux::KexLinuxError> {
+    let mut source = kexlinux::SyslinuxSource::new();
+    source.set_verifier(try!(verifier(matches, "verify conf")));
+    let mut registry = kexlinux::Registry::new();
+    registry.register(Box::new(source));
+    Ok(registry)
+}
+
+fn kexlinux_from_mount(matches: &clap::ArgMatches)
+        -> Result<kexlinux::KexLinux, kexlinux::KexLinuxError> {
+    kexlinux::KexLinux::from_root(&root_from_mount(matches),
+                                  &try!(registry_from_mount(matches)))
 }
 
 fn kexlinux_from_dev(matches: &clap::ArgMatches)
@@ -43,9 +65,535 @@ fn kexlinux_from_dev(matches: &clap::ArgMatches)
     match matches.value_of("BOOT DEVICE") {
         Some(boot_dev) => {
             let boot_dev = std::path::PathBuf::from(boot_dev);
-            kexlinux::KexLinux::from_device_path(boot_dev)
+            kexlinux::KexLinux::from_device_path_with_registry(
+                boot_dev, &try!(registry_from_dev(matches)))
+        },
+        None => kexlinux::KexLinux::auto_with_registry(
+            &try!(registry_from_dev(matches))),
+    }
+}
+
+fn mount_cmd<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("mount")
+        .arg(clap::Arg::with_name("type")
+            .help("Type of syslinux configuration. Only for autodetect.")
+            .short("t")
+            .long("type")
+            .value_name("TYPE")
+            .takes_value(true)
+            .possible_values(&["syslinux", "isolinux", "extlinux"]))
+        .arg(clap::Arg::with_name("ROOT DIR")
+            .help("Path to the root directory of the boot device.")
+            .required(true)
+            .index(1))
+        .arg(clap::Arg::with_name("CONF FILE PATH")
+            .help("Path to the configuration file. Will be autodetected if \
+                   omitted.")
+            .index(2))
+        .group(clap::ArgGroup::with_name("detection")
+            .arg("type")
+            .arg("CONF FILE PATH"))
+}
+
+fn dev_cmd<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("dev")
+        .arg(clap::Arg::with_name("BOOT DEVICE")
+            .help("Path to boot device.")
+            .index(1))
+}
+
+fn json_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("json")
+        .help("Print output in JSON format.")
+        .long("json")
+}
+
+fn state_dir_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("state dir")
+        .help("Writable directory for boot state, such as one-shot entry.")
+        .long("state-dir")
+        .value_name("DIR")
+        .takes_value(true)
+        .default_value(kexlinux::DEFAULT_STATE_DIR)
+}
+
+fn verify_kernel_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("verify kernel")
+        .help("Check kernel signatures: Authenticode signature of EFI stub \
+               kernels, detached signature in \"<kernel>.sig\" otherwise. \
+               With \"enforce\", kernels without valid signature are not \
+               booted.")
+        .long("verify-kernel")
+        .value_name("MODE")
+        .takes_value(true)
+        .possible_values(&["off", "warn", "enforce"])
+        .default_value("off")
+}
+
+fn verify_conf_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("verify conf")
+        .help("Check detached signatures of the configuration file and \
+               all files it includes, in \"<file>.sig\". With \
+               \"enforce\", configuration without valid signatures is \
+               not used.")
+        .long("verify-conf")
+        .value_name("MODE")
+        .takes_value(true)
+        .possible_values(&["off", "warn", "enforce"])
+        .default_value("off")
+}
+
+fn cert_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("cert")
+        .help("Trusted certificate file (PEM or DER) or directory with \
+               them. Defaults to the certificates in /etc/kexlinux/certs.")
+        .long("cert")
+        .value_name("PATH")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+}
+
+// Verifier with policy from the argument. Certificates are only read when
+// signatures are checked.
+fn verifier(matches: &clap::ArgMatches, policy_arg: &str)
+        -> Result<kexlinux::Verifier, kexlinux::KexLinuxError> {
+    let policy = match matches.value_of(policy_arg) {
+        Some("warn") => kexlinux::Policy::Warn,
+        Some("enforce") => kexlinux::Policy::Enforce,
+        _ => return Ok(kexlinux::Verifier::default()),
+    };
+
+    let paths = match matches.values_of("cert") {
+        Some(paths) => paths.map(std::path::PathBuf::from).collect(),
+        None => vec![std::path::PathBuf::from(kexlinux::DEFAULT_CERT_DIR)],
+    };
+    let mut verifier = kexlinux::Verifier::new(policy);
+    let mut count = 0;
+    for path in &paths {
+        match verifier.add_certs(path) {
+            Ok(added) => count += added,
+            Err(err) => {
+                error!("Unable to read trusted certificates from {:?}", path);
+                return Err(kexlinux::KexLinuxError::from(err));
+            },
+        }
+    }
+    if count == 0 {
+        warn!("No trusted certificates, no signature will be valid");
+    }
+    Ok(verifier)
+}
+
+fn state_store(matches: &clap::ArgMatches) -> kexlinux::StateStore {
+    kexlinux::StateStore::new(std::path::PathBuf::from(
+        matches.value_of("state dir").unwrap()))
+}
+
+fn set_next_cmd<'a, 'b>() -> clap::App<'a, 'b> {
+    let args = |cmd: clap::App<'a, 'b>| cmd
+        .arg(state_dir_arg())
+        .arg(clap::Arg::with_name("label")
+            .help("Name of the label.")
+            .short("l")
+            .long("label")
+            .value_name("NAME")
+            .takes_value(true)
+            .required(true));
+
+    clap::SubCommand::with_name("set-next")
+        .about("Boot the given label on the next run from the same boot \
+                device only.")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(args(mount_cmd())
+            .about("Use label from already mounted boot device."))
+        .subcommand(args(dev_cmd())
+            .about("Use label from specified or automatically detected boot \
+                   device."))
+}
+
+fn run_set_next(matches: &clap::ArgMatches) -> i32 {
+    let (kexlinux, matches) =
+            if let Some(matches) = matches.subcommand_matches("mount") {
+        (kexlinux_from_mount(matches), matches)
+    } else if let Some(matches) = matches.subcommand_matches("dev") {
+        (kexlinux_from_dev(matches), matches)
+    } else {
+        panic!("This will never happen")
+    };
+
+    let kexlinux = match kexlinux {
+        Ok(kexlinux) => kexlinux,
+        Err(_) => {
+            error!("Unable to initialize kexlinux");
+            return 1
+        },
+    };
+
+    let label = matches.value_of("label").unwrap();
+    if kexlinux.get_conf().get(label).is_none() {
+        clap::Error::with_description(
+            &kexlinux.get_conf().not_found(label),
+            clap::ErrorKind::InvalidValue).exit()
+    }
+
+    let source = kexlinux.get_source().key();
+    match state_store(matches).set_next_entry(&source, label) {
+        Ok(_) => 0,
+        Err(_) => {
+            error!("Unable to set next label");
+            1
+        },
+    }
+}
+
+fn mark_good_cmd<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("mark-good")
+        .about("Reset boot counter of the label booted last. Should be run \
+               from the booted system once it is known to work.")
+        .arg(state_dir_arg())
+}
+
+fn run_mark_good(matches: &clap::ArgMatches) -> i32 {
+    match kexlinux::KexLinux::mark_good(&state_store(matches)) {
+        Ok(Some(name)) => {
+            println!("Label \"{}\" marked good", name);
+            0
+        },
+        Ok(None) => {
+            println!("No boot counter to reset");
+            0
+        },
+        Err(_) => {
+            error!("Unable to reset boot counter");
+            1
+        },
+    }
+}
+
+fn check_cmd<'a, 'b>() -> clap::App<'a, 'b> {
+    let args = |cmd: clap::App<'a, 'b>| cmd
+        .arg(json_arg())
+        .arg(clap::Arg::with_name("strict")
+            .help("Treat warnings as errors.")
+            .long("strict"));
+
+    clap::SubCommand::with_name("check")
+        .about("Check boot configuration for problems. Exits with non-zero \
+               code if errors were found.")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(args(mount_cmd())
+            .about("Check configuration on already mounted boot device."))
+        .subcommand(args(dev_cmd())
+            .about("Check configuration on specified or automatically \
+                   detected boot device."))
+}
+
+fn list_cmd<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("list")
+        .about("List labels that would be available for booting.")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(mount_cmd()
+            .about("List labels on already mounted boot device.")
+            .arg(json_arg())
+            .arg(verify_kernel_arg())
+            .arg(verify_conf_arg())
+            .arg(cert_arg()))
+        .subcommand(dev_cmd()
+            .about("List labels on specified or automatically detected boot \
+                   device.")
+            .arg(json_arg())
+            .arg(verify_kernel_arg())
+            .arg(verify_conf_arg())
+            .arg(cert_arg()))
+}
+
+fn print_list(kexlinux: &kexlinux::KexLinux) {
+    let source = kexlinux.get_source();
+    match (&source.device, &source.fs_type) {
+        (&Some(ref device), &Some(ref fs_type)) => println!(
+            "Source: {} ({}) at {}", device.to_string_lossy(), fs_type,
+            source.root.to_string_lossy()),
+        _ => println!("Source: {}", source.root.to_string_lossy()),
+    }
+
+    let conf = kexlinux.get_conf();
+    for entry in &conf.entries {
+        let mut marks = Vec::new();
+        if Some(&entry.name) == conf.default_name.as_ref() {
+            marks.push("default");
+        }
+        if entry.name == conf.ontimeout_name {
+            marks.push("ontimeout");
+        }
+        if Some(&entry.name) == conf.onerror_name.as_ref() {
+            marks.push("onerror");
+        }
+        if entry.hidden {
+            marks.push("hidden");
+        }
+        if entry.disabled {
+            marks.push("disabled");
+        }
+        if entry.passwd.is_some() {
+            marks.push("protected");
+        }
+        if entry.counter.as_ref().map(|counter| counter.is_bad())
+                .unwrap_or(false) {
+            marks.push("bad");
+        }
+
+        println!("");
+        match marks.is_empty() {
+            true => println!("Label \"{}\"", entry.name),
+            false => println!("Label \"{}\" [{}]", entry.name,
+                              marks.join(", ")),
+        }
+        if let Some(ref title) = entry.title {
+            println!("  Title: {}", title);
+        }
+        println!("  Kernel: {}", entry.kernel.to_string_lossy());
+        match kexlinux.kernel_signature(entry) {
+            kexlinux::Status::Unchecked => (),
+            status => println!("  Signature: {}", status),
+        }
+        for initrd in &entry.initrds {
+            println!("  Initrd: {}", initrd.to_string_lossy());
+        }
+        if let Some(ref cmdline) = entry.cmdline {
+            println!("  Append: {}", cmdline);
+        }
+        if let Some(ref devicetree) = entry.devicetree {
+            println!("  Devicetree: {}", devicetree.to_string_lossy());
+        }
+    }
+}
+
+fn run_list(matches: &clap::ArgMatches) -> i32 {
+    let (kexlinux, matches) =
+            if let Some(matches) = matches.subcommand_matches("mount") {
+        (kexlinux_from_mount(matches), matches)
+    } else if let Some(matches) = matches.subcommand_matches("dev") {
+        (kexlinux_from_dev(matches), matches)
+    } else {
+        panic!("This will never happen")
+    };
+
+    let kexlinux = kexlinux.and_then(|mut kexlinux| {
+        kexlinux.set_verifier(try!(verifier(matches, "verify kernel")));
+        Ok(kexlinux)
+    });
+    match kexlinux {
+        Ok(kexlinux) => {
+            if matches.is_present("json") {
+                println!("{}", kexlinux.to_json());
+            } else {
+                print_list(&kexlinux);
+            }
+            0
+        },
+        Err(_) => {
+            error!("Unable to initialize kexlinux");
+            1
+        },
+    }
+}
+
+fn run_check(matches: &clap::ArgMatches) -> i32 {
+    let (report, matches) =
+            if let Some(matches) = matches.subcommand_matches("mount") {
+        (registry_from_mount(matches).and_then(
+            |registry| registry.check(&root_from_mount(matches))),
+         matches)
+    } else if let Some(matches) = matches.subcommand_matches("dev") {
+        (registry_from_dev(matches).and_then(
+            |registry| kexlinux_from_dev(matches).and_then(
+                |kexlinux| kexlinux.check(&registry))),
+         matches)
+    } else {
+        panic!("This will never happen")
+    };
+
+    let report = match report {
+        Ok(report) => report,
+        Err(_) => {
+            error!("Unable to check configuration");
+            return 2
         },
-        None => kexlinux::KexLinux::auto(),
+    };
+
+    if matches.is_present("json") {
+        println!("{}", report.to_json());
+    } else {
+        println!("{}", report);
+    }
+
+    let failed = report.has_errors() || (
+        matches.is_present("strict") &&
+            report.count(kexlinux::Severity::Warning) > 0);
+    match failed {
+        true => 1,
+        false => 0,
+    }
+}
+
+// Arguments common for all boot subcommands.
+fn boot_args<'a, 'b>(cmd: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    cmd
+        .arg(clap::Arg::with_name("label")
+            .help("Boot label with the given name instead of the one that \
+                   would be booted on timeout. MENU PASSWD is only asked for \
+                   in the menu, not for labels chosen here.")
+            .short("l")
+            .long("label")
+            .value_name("NAME")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("default")
+            .help("Boot DEFAULT label instead of the one that would be booted \
+                   on timeout.")
+            .long("default"))
+        .arg(clap::Arg::with_name("index")
+            .help("Boot label by zero-based position in the output of \
+                   list, hidden labels included.")
+            .long("index")
+            .value_name("N")
+            .takes_value(true)
+            .validator(|v| v.parse::<usize>().map(|_| ())
+                .map_err(|_| String::from("should be a non-negative number"))))
+        .group(clap::ArgGroup::with_name("selection")
+            .arg("label")
+            .arg("default")
+            .arg("index"))
+        .arg(state_dir_arg())
+        .arg(clap::Arg::with_name("save default")
+            .help("Make the booted label default for the next run from the \
+                   same boot device.")
+            .long("save-default"))
+        .arg(clap::Arg::with_name("menu")
+            .help("Show boot menu on the terminal. Selected label is \
+                   highlighted initially.")
+            .short("m")
+            .long("menu"))
+        .arg(clap::Arg::with_name("append")
+            .help("Add parameters to the end of the kernel command line \
+                   (before \"--\"). May be repeated.")
+            .long("append")
+            .value_name("PARAMS")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true))
+        .arg(clap::Arg::with_name("prepend")
+            .help("Add parameters to the beginning of the kernel command \
+                   line. May be repeated.")
+            .long("prepend")
+            .value_name("PARAMS")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true))
+        .arg(clap::Arg::with_name("remove")
+            .help("Remove parameters with the given name, or only exact \
+                   matches if value is given. May be repeated.")
+            .long("remove")
+            .value_name("KEY[=VALUE]")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("cmdline")
+            .help("Replace the kernel command line of the label. Unlike \
+                   editing in the menu, no MENU MASTER PASSWD is asked for.")
+            .long("cmdline")
+            .value_name("CMDLINE")
+            .takes_value(true)
+            .allow_hyphen_values(true))
+        .arg(clap::Arg::with_name("fallback")
+            .help("Label to try if booting the selected label and ONERROR \
+                   label fails. May be repeated.")
+            .long("fallback")
+            .value_name("LABEL")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("dry run")
+            .help("Print what would be booted and how, without loading or \
+                   executing the kernel and without changing boot state.")
+            .long("dry-run"))
+        .arg(clap::Arg::with_name("load only")
+            .help("Only load the kernel, it is executed by the next \
+                   \"systemctl kexec\" or \"kexec --exec\".")
+            .long("load-only"))
+        .arg(clap::Arg::with_name("exec via")
+            .help("Execute the loaded kernel through the init system, so \
+                   services are stopped and filesystems unmounted first: \
+                   run \"systemctl kexec\" or signal PID 1.")
+            .long("exec-via")
+            .value_name("METHOD")
+            .takes_value(true)
+            .possible_values(&["systemctl", "signal"])
+            .conflicts_with("load only"))
+        .arg(clap::Arg::with_name("shutdown")
+            .help("Before executing the kernel, sync, stop all other \
+                   processes and unmount filesystems. For use as the last \
+                   stage of initramfs.")
+            .long("shutdown")
+            .conflicts_with_all(&["load only", "exec via"]))
+        .arg(clap::Arg::with_name("network down")
+            .help("Also bring network interfaces down before executing the \
+                   kernel.")
+            .long("network-down")
+            .requires("shutdown"))
+        .arg(verify_kernel_arg())
+        .arg(verify_conf_arg())
+        .arg(cert_arg())
+        .arg(clap::Arg::with_name("kexec tool")
+            .help("Load and execute kernel with kexec tool instead of \
+                   kexec_file_load(2) system call. Always used for labels \
+                   with FDT or DEVICETREE.")
+            .long("kexec-tool"))
+}
+
+fn print_dry_run(dry_run: &kexlinux::DryRun) {
+    let source = &dry_run.source;
+    match (&source.device, &source.fs_type) {
+        (&Some(ref device), &Some(ref fs_type)) => println!(
+            "Source: {} ({}) at {}", device.to_string_lossy(), fs_type,
+            source.root.to_string_lossy()),
+        _ => println!("Source: {}", source.root.to_string_lossy()),
+    }
+    println!("Label: \"{}\"", dry_run.name);
+    match dry_run.kernel_format {
+        Some(ref format) => println!("Kernel: {} ({})",
+                                     dry_run.kernel.to_string_lossy(), format),
+        None => println!("Kernel: {}", dry_run.kernel.to_string_lossy()),
+    }
+    println!("Kernel type: {}", dry_run.kernel_info.format);
+    if let Some(ref version) = dry_run.kernel_info.version {
+        println!("Kernel version: {}", version);
+    }
+    if dry_run.kernel_signature != kexlinux::Status::Unchecked {
+        println!("Signature: {}", dry_run.kernel_signature);
+    }
+    for initrd in &dry_run.initrds {
+        println!("Initrd: {}", initrd.to_string_lossy());
+    }
+    if let Some(ref cmdline) = dry_run.cmdline {
+        println!("Cmdline: {}", cmdline);
+    }
+    if let Some(ref devicetree) = dry_run.devicetree {
+        println!("Devicetree: {}", devicetree.to_string_lossy());
+    }
+    for overlay in &dry_run.devicetree_overlays {
+        println!("Overlay: {}", overlay.to_string_lossy());
+    }
+    if !dry_run.fallback.is_empty() {
+        println!("Fallback: {}", dry_run.fallback.join(", "));
+    }
+
+    println!("");
+    println!("Would run:");
+    for invocation in &dry_run.invocation {
+        println!("  {}", invocation);
     }
 }
 
@@ -55,44 +603,38 @@ fn main() {
     let matches = clap::App::new("kexlinux")
         .about("Userspace bootloader for Linux using kexec")
         .version(env!("CARGO_PKG_VERSION"))
-        .subcommand(clap::SubCommand::with_name("mount")
-            .about("Boot from already mounted boot device.")
-            .arg(clap::Arg::with_name("type")
-                .help("Type of syslinux configuration. Only for autodetect.")
-                .short("t")
-                .long("type")
-                .value_name("TYPE")
-                .takes_value(true)
-                .possible_values(&["syslinux", "isolinux", "extlinux"]))
-            .arg(clap::Arg::with_name("ROOT DIR")
-                .help("Path to the root directory of the boot device.")
-                .required(true)
-                .index(1))
-            .arg(clap::Arg::with_name("CONF FILE PATH")
-                .help("Path to the configuration file. Will be autodetected if \
-                       omitted.")
-                .index(2))
-            .group(clap::ArgGroup::with_name("detection")
-                .arg("type")
-                .arg("CONF FILE PATH")))
-        .subcommand(clap::SubCommand::with_name("dev")
+        .subcommand(boot_args(mount_cmd())
+            .about("Boot from already mounted boot device."))
+        .subcommand(boot_args(dev_cmd())
             .about("Boot from specified device or automatically detect boot \
-                   device.")
-            .arg(clap::Arg::with_name("BOOT DEVICE")
-                .help("Path to boot device.")
-                .index(1)))
+                   device."))
+        .subcommand(check_cmd())
+        .subcommand(list_cmd())
+        .subcommand(set_next_cmd())
+        .subcommand(mark_good_cmd())
         .get_matches();
 
-    let kexlinux = if let Some(matches) = matches.subcommand_matches("mount") {
-        kexlinux_from_mount(matches)
+    if let Some(matches) = matches.subcommand_matches("check") {
+        std::process::exit(run_check(matches))
+    } else if let Some(matches) = matches.subcommand_matches("list") {
+        std::process::exit(run_list(matches))
+    } else if let Some(matches) = matches.subcommand_matches("set-next") {
+        std::process::exit(run_set_next(matches))
+    } else if let Some(matches) = matches.subcommand_matches("mark-good") {
+        std::process::exit(run_mark_good(matches))
+    }
+
+    let (kexlinux, matches) =
+            if let Some(matches) = matches.subcommand_matches("mount") {
+        (kexlinux_from_mount(matches), matches)
     } else if let Some(matches) = matches.subcommand_matches("dev") {
-        kexlinux_from_dev(matches)
+        (kexlinux_from_dev(matches), matches)
     } else {
         error!("No command");
         std::process::exit(1)
     };
 
-    let kexlinux = match kexlinux {
+    let mut kexlinux = match kexlinux {
         Ok(kexlinux) => kexlinux,
         Err(_) => {
             // TODO: Log actual reason.
@@ -101,7 +643,132 @@ fn main() {
         },
     };
 
-    if let Err(_) = kexlinux::KexLinux::boot(&kexlinux.get_conf().ontimeout) {
+    match verifier(matches, "verify kernel") {
+        Ok(verifier) => kexlinux.set_verifier(verifier),
+        Err(_) => std::process::exit(1),
+    }
+
+    let selection = if let Some(label) = matches.value_of("label") {
+        kexlinux::Selection::Label(String::from(label))
+    } else if let Some(index) = matches.value_of("index") {
+        kexlinux::Selection::Index(index.parse().unwrap())
+    } else if matches.is_present("default") {
+        kexlinux::Selection::Default
+    } else {
+        kexlinux::Selection::Auto
+    };
+
+    let store = state_store(matches);
+    if kexlinux.apply_boot_counting(&store).is_err() {
+        warn!("Unable to read boot counters from {:?}", store.dir());
+    }
+
+    // Saved and one-shot entries are used only if nothing was selected
+    // explicitly, one-shot entry wins. It is consumed only when used, so an
+    // explicit selection leaves it for the next run. Entries without tries
+    // left are not booted automatically unless selected as one-shot.
+    if let kexlinux::Selection::Auto = selection {
+        if matches.is_present("save default") &&
+                kexlinux.apply_saved_entry(&store).is_err() {
+            warn!("Unable to read saved entry from {:?}", store.dir());
+        }
+        kexlinux.replace_bad_default();
+    }
+    let dry_run = matches.is_present("dry run");
+    let failed = match (&selection, dry_run) {
+        (&kexlinux::Selection::Auto, false) => {
+            kexlinux.apply_next_entry(&store).is_err()
+        },
+        (&kexlinux::Selection::Auto, true) => {
+            kexlinux.preview_next_entry(&store).is_err()
+        },
+        _ => false,
+    };
+    if failed {
+        warn!("Unable to read one-shot entry from {:?}", store.dir());
+    }
+
+    let entry = match kexlinux.get_conf().select(&selection) {
+        Ok(entry) => entry,
+        Err(_) => {
+            let description = match selection {
+                kexlinux::Selection::Label(ref label) => {
+                    kexlinux.get_conf().not_found(label)
+                },
+                _ => String::from("Unable to select label"),
+            };
+            clap::Error::with_description(
+                &description, clap::ErrorKind::InvalidValue).exit()
+        },
+    };
+
+    let chosen;
+    let entry = match matches.is_present("menu") {
+        true => {
+            let highlight = match selection {
+                kexlinux::Selection::Auto => None,
+                _ => Some(entry.name.as_str()),
+            };
+            chosen = match kexlinux.menu(highlight) {
+                Ok(entry) => entry,
+                Err(_) => {
+                    error!("Unable to show boot menu");
+                    std::process::exit(1)
+                },
+            };
+            &chosen
+        },
+        false => entry,
+    };
+
+    let values = |name| matches.values_of(name)
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+    let options = kexlinux::BootOptions{
+        fallback: values("fallback"),
+        cmdline: kexlinux::CmdlineEdit{
+            replace: matches.value_of("cmdline").map(String::from),
+            remove: values("remove"),
+            prepend: values("prepend"),
+            append: values("append"),
+        },
+        save_default: match matches.is_present("save default") {
+            true => Some(store.clone()),
+            false => None,
+        },
+        boot_count: Some(store.clone()),
+        backend: match matches.is_present("kexec tool") {
+            true => kexlinux::Backend::Tool,
+            false => kexlinux::Backend::Native,
+        },
+        exec: match (matches.is_present("load only"),
+                     matches.value_of("exec via")) {
+            (true, _) => kexlinux::ExecMode::LoadOnly,
+            (false, Some("systemctl")) => kexlinux::ExecMode::Systemctl,
+            (false, Some("signal")) => kexlinux::ExecMode::SignalInit,
+            (false, _) => kexlinux::ExecMode::Immediate,
+        },
+        shutdown: match matches.is_present("shutdown") {
+            true => Some(kexlinux::Shutdown{
+                network_down: matches.is_present("network down"),
+                ..Default::default()
+            }),
+            false => None,
+        },
+    };
+
+    if dry_run {
+        match kexlinux.dry_run(entry, &options) {
+            Ok(dry_run) => print_dry_run(&dry_run),
+            Err(_) => {
+                error!("Unable to resolve \"{}\"", entry.name);
+                std::process::exit(1)
+            },
+        }
+        return;
+    }
+
+    if let Err(_) = kexlinux.boot_with_options(entry, &options) {
         // TODO: Log actual reason.
         error!("Unable to kexec");
         std::process::exit(1)
@@ -1,5 +1,6 @@
 use std;
 
+extern crate libc;
 extern crate mnt;
 extern crate tempdir;
 
@@ -173,14 +174,17 @@ impl Iterator for BlockDevs {
 pub struct FS {
     pub dev: BlockDev,
     pub fs_type: String,
+    pub uuid: Option<String>,
 }
 
 impl FS {
-    fn parse_blkid_output(out: String) -> Result<String, BlockDevError> {
+    fn parse_blkid_output(out: String)
+            -> Result<(String, Option<String>), BlockDevError> {
         #[derive(Default)]
         struct BlkIDInfo {
             usage: Option<String>,
             fs_type: Option<String>,
+            uuid: Option<String>,
         }
 
         impl BlkIDInfo {
@@ -192,6 +196,7 @@ impl FS {
                         match key {
                             "USAGE" => self.usage   = Some(String::from(value)),
                             "TYPE"  => self.fs_type = Some(String::from(value)),
+                            "UUID"  => self.uuid    = Some(String::from(value)),
                             _ => {
                                 debug!("blkid gave unexpected key \"{}\"", key)
                             },
@@ -210,17 +215,19 @@ impl FS {
             debug!("Not filesystem");
             Err(BlockDevError{})
         } else {
-            info.fs_type.ok_or(BlockDevError{})
+            Ok((try!(info.fs_type.ok_or(BlockDevError{})), info.uuid))
         }
     }
 
-    fn get_fs_type(dev: &BlockDev) -> Result<String, BlockDevError> {
+    fn get_fs_type(dev: &BlockDev)
+            -> Result<(String, Option<String>), BlockDevError> {
         debug!("Probing {:?} with blkid...", dev.path);
 
         let mut cmd = std::process::Command::new(CMD_BLKID);
         cmd.arg("-p");                            // Bypass cache.
         cmd.args(&["-o", "export"]);              // Output in KEY=value format.
-        cmd.args(&["-s", "USAGE", "-s", "TYPE"]); // Show only these tags.
+        cmd.args(&["-s", "USAGE", "-s", "TYPE",   // Show only these tags.
+                   "-s", "UUID"]);
         cmd.arg(try!(dev.path.to_str().ok_or(BlockDevError{})));
         cmd.stdin(std::process::Stdio::null());
 
@@ -253,8 +260,10 @@ impl FS {
             return Err(BlockDevError{})
         }
 
+        let (fs_type, uuid) = try!(FS::get_fs_type(&dev));
         let fs = FS{
-            fs_type: try!(FS::get_fs_type(&dev)),
+            fs_type: fs_type,
+            uuid: uuid,
             dev: dev,
         };
         debug!("Found FS \"{}\" on device \"{}\"", fs.fs_type, fs.dev.name);
@@ -262,6 +271,54 @@ impl FS {
     }
 }
 
+/// UUID of the filesystem containing the path, e.g. already mounted boot
+/// device.
+pub fn fs_uuid(path: &std::path::Path) -> Option<String> {
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = match path.metadata() {
+        Ok(metadata) => metadata.dev(),
+        Err(_) => return None,
+    };
+    let major = libc::major(dev);
+    let minor = libc::minor(dev);
+
+    let uevent_path = std::path::PathBuf::from(
+        format!("/sys/dev/block/{}:{}/uevent", major, minor));
+    let mut uevent = String::new();
+    if std::fs::File::open(&uevent_path)
+            .and_then(|mut file| file.read_to_string(&mut uevent))
+            .is_err() {
+        debug!("{:?} is not on a block device", path);
+        return None;
+    }
+    let dev_name = match uevent.lines()
+            .find(|line| line.starts_with("DEVNAME=")) {
+        Some(line) => &line["DEVNAME=".len()..],
+        None => return None,
+    };
+
+    let mut cmd = std::process::Command::new(CMD_BLKID);
+    cmd.args(&["-o", "value", "-s", "UUID"]);
+    cmd.arg(format!("/dev/{}", dev_name));
+    cmd.stdin(std::process::Stdio::null());
+    match cmd.output() {
+        Ok(ref output) if output.status.success() => {
+            let uuid = String::from_utf8_lossy(&output.stdout).trim()
+                .to_string();
+            match uuid.is_empty() {
+                true => None,
+                false => Some(uuid),
+            }
+        },
+        _ => {
+            debug!("Unable to get UUID of {:?}", dev_name);
+            None
+        },
+    }
+}
+
 pub fn get_filesystems<BlockDevIter>(block_devs: BlockDevIter) -> Vec<FS>
         where BlockDevIter: Iterator<Item=BlockDev> {
     use std::iter::FromIterator;
@@ -269,6 +326,7 @@ pub fn get_filesystems<BlockDevIter>(block_devs: BlockDevIter) -> Vec<FS>
         |dev| FS::from_dev(dev).ok()))
 }
 
+#[derive(Debug)]
 pub struct Mount {
     temp_dir: Option<tempdir::TempDir>,
     mount_path: std::path::PathBuf,
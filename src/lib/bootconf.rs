@@ -0,0 +1,388 @@
+use std;
+
+extern crate strsim;
+
+use bootcount;
+use check;
+use kexlinux::KexLinuxError;
+
+/// Single bootable entry, independent of configuration format.
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    /// Unique name used to reference the entry (syslinux label name, BLS
+    /// entry id, etc.).
+    pub name: String,
+    /// Human-readable title, if the format provides one.
+    pub title: Option<String>,
+
+    pub kernel: std::path::PathBuf,
+    pub initrds: Vec<std::path::PathBuf>,
+    pub cmdline: Option<String>,
+    pub devicetree: Option<std::path::PathBuf>,
+    /// Directory to choose devicetree from, if `devicetree` is not set.
+    pub devicetree_dir: Option<std::path::PathBuf>,
+    pub devicetree_overlays: Vec<std::path::PathBuf>,
+    /// Bitmask of syslinux SYSAPPEND options to add to the command line
+    /// when loading.
+    pub sysappend: u32,
+
+    /// Longer description shown by menu front-ends.
+    pub help: Option<String>,
+    /// Entry is bootable by name, but not shown in menu.
+    pub hidden: bool,
+    /// Entry is shown in menu, but can not be selected.
+    pub disabled: bool,
+    /// Password hash required to boot the entry from menu.
+    pub passwd: Option<String>,
+    /// Boot assessment counter, `None` if the entry is not counted.
+    pub counter: Option<bootcount::Counter>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MenuItem {
+    /// Reference to the entry by name.
+    Entry(String),
+    Separator,
+    Submenu(Submenu),
+}
+
+#[derive(Debug, Clone)]
+pub struct Submenu {
+    pub label: Option<String>,
+    pub title: Option<String>,
+    /// Password hash required to open the submenu.
+    pub passwd: Option<String>,
+    pub items: Vec<MenuItem>,
+}
+
+/// Serial console the menu is shown on.
+#[derive(Debug, Clone)]
+pub struct SerialConsole {
+    pub device: std::path::PathBuf,
+    pub baud: u32,
+    /// Hardware (RTS/CTS) flow control.
+    pub flow_control: bool,
+}
+
+/// Format-neutral boot configuration produced by a `ConfSource`.
+#[derive(Debug, Clone)]
+pub struct BootConf {
+    /// Seconds before the menu boots ONTIMEOUT entry, cancelled by any key.
+    pub timeout: Option<f64>,
+    /// Seconds before the menu boots ONTIMEOUT entry, can not be cancelled.
+    pub total_timeout: Option<f64>,
+
+    /// Entry to boot when nothing else is selected. Always exists in
+    /// `entries`.
+    pub ontimeout_name: String,
+    pub onerror_name: Option<String>,
+
+    pub default_name: Option<String>,
+    pub entries: Vec<BootEntry>,
+
+    /// Title of the top-level menu.
+    pub title: Option<String>,
+    /// Menu structure. Empty if the format has no menu of its own, in that
+    /// case all entries should be shown in order.
+    pub menu: Vec<MenuItem>,
+    /// Password hash which unlocks every entry and is required to edit
+    /// command lines in menu.
+    pub master_passwd: Option<String>,
+
+    pub serial: Option<SerialConsole>,
+    /// Menu is shown on the local console.
+    pub local_console: bool,
+}
+
+impl BootConf {
+    pub fn get(&self, name: &str) -> Option<&BootEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    pub fn ontimeout(&self) -> &BootEntry {
+        self.get(&self.ontimeout_name)
+            .expect("ontimeout entry should always exist")
+    }
+
+    pub fn onerror(&self) -> Option<&BootEntry> {
+        self.onerror_name.as_ref().and_then(|name| self.get(name))
+    }
+
+    /// Make the entry both default and booted on timeout, e.g. for one-shot
+    /// selection. Returns `false` if there is no such entry.
+    pub fn set_default(&mut self, name: &str) -> bool {
+        match self.get(name).is_some() {
+            true => {
+                self.default_name = Some(String::from(name));
+                self.ontimeout_name = String::from(name);
+                true
+            },
+            false => false,
+        }
+    }
+
+    /// Entry to boot by default instead of the ONTIMEOUT one if it has no
+    /// tries left: ONERROR, then the first selectable entry with tries left.
+    pub fn replace_bad_default(&mut self) -> Option<String> {
+        let is_good = |entry: &BootEntry| !entry.disabled && !entry.counter
+            .as_ref().map(|counter| counter.is_bad()).unwrap_or(false);
+        if is_good(self.ontimeout()) {
+            return None;
+        }
+
+        let replacement = self.onerror().into_iter()
+            .chain(self.entries.iter())
+            .find(|entry| is_good(entry))
+            .map(|entry| entry.name.clone());
+        match replacement {
+            Some(name) => {
+                warn!("Entry \"{}\" has no tries left, using \"{}\"",
+                      self.ontimeout_name, name);
+                self.set_default(&name);
+                Some(name)
+            },
+            None => {
+                warn!("All entries have no tries left");
+                None
+            },
+        }
+    }
+
+    // Entry name most similar to the given one, for "did you mean"
+    // messages. Uses the same metric and threshold as clap.
+    fn suggest(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .map(|entry| (strsim::jaro_winkler(name, &entry.name), &entry.name))
+            .filter(|&(confidence, _)| confidence > 0.8)
+            .fold(None, |best: Option<(f64, &String)>, candidate| match best {
+                Some(best) if best.0 >= candidate.0 => Some(best),
+                _ => Some(candidate),
+            })
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// Message for the entry missing in configuration, with the closest
+    /// name if there is one.
+    pub fn not_found(&self, name: &str) -> String {
+        match self.suggest(name) {
+            Some(suggestion) => format!(
+                "Entry \"{}\" not found, did you mean \"{}\"?", name,
+                suggestion),
+            None => format!("Entry \"{}\" not found", name),
+        }
+    }
+
+    /// Find entry to boot.
+    pub fn select(&self, selection: &Selection)
+            -> Result<&BootEntry, KexLinuxError> {
+        match *selection {
+            Selection::Auto => Ok(self.ontimeout()),
+
+            Selection::Default => match self.default_name {
+                Some(ref name) => self.get(name).ok_or(KexLinuxError{}),
+                None => {
+                    warn!("No default entry, using \"{}\"",
+                          self.ontimeout_name);
+                    Ok(self.ontimeout())
+                },
+            },
+
+            Selection::Label(ref name) => match self.get(name) {
+                Some(entry) => Ok(entry),
+                None => {
+                    error!("{}", self.not_found(name));
+                    Err(KexLinuxError{})
+                },
+            },
+
+            Selection::Index(index) => match self.entries.get(index) {
+                Some(entry) => Ok(entry),
+                None => {
+                    error!("Entry index {} out of range, there are only {} \
+                            entries", index, self.entries.len());
+                    Err(KexLinuxError{})
+                },
+            },
+        }
+    }
+}
+
+/// How to choose entry to boot.
+#[derive(Debug, Clone)]
+pub enum Selection {
+    /// Entry that would be booted on timeout.
+    Auto,
+    /// Entry marked as default in configuration.
+    Default,
+    /// Entry with the given name.
+    Label(String),
+    /// Entry by zero-based position in `BootConf::entries`, as listed by
+    /// `kexlinux list`. Hidden entries are included, labels that can not be
+    /// booted are not.
+    Index(usize),
+}
+
+/// Source of boot entries on a mounted root (syslinux configuration, BLS
+/// snippets, etc.).
+pub trait ConfSource {
+    /// Short unique name of the source, used to configure probing order.
+    fn name(&self) -> &str;
+
+    /// Try to read boot configuration from the given root directory. Should
+    /// fail if the root does not contain configuration of this format.
+    fn read(&self, root: &std::path::Path) -> Result<BootConf, KexLinuxError>;
+
+    /// Check configuration on the given root for problems. Should fail only
+    /// if the root does not contain configuration of this format or it can
+    /// not be parsed at all.
+    fn check(&self, root: &std::path::Path)
+            -> Result<check::Report, KexLinuxError> {
+        Ok(check::check_boot_conf(&try!(self.read(root))))
+    }
+}
+
+/// Ordered set of configuration sources.
+pub struct Registry {
+    sources: Vec<Box<ConfSource>>,
+}
+
+impl Registry {
+    /// Registry without any sources.
+    pub fn empty() -> Registry {
+        Registry{
+            sources: Vec::new(),
+        }
+    }
+
+    /// Registry with all built-in sources.
+    pub fn new() -> Registry {
+        use syslinux;
+
+        let mut registry = Registry::empty();
+        registry.register(Box::new(syslinux::SyslinuxSource::new()));
+        registry
+    }
+
+    /// Add source to the end of the probing order. Source with the same
+    /// name is replaced in place.
+    pub fn register(&mut self, source: Box<ConfSource>) {
+        match self.sources.iter().position(|s| s.name() == source.name()) {
+            Some(index) => self.sources[index] = source,
+            None => self.sources.push(source),
+        }
+    }
+
+    /// Reorder sources. Sources listed in `names` go first in the given
+    /// order, sources not listed are dropped.
+    pub fn set_order(&mut self, names: &[&str]) -> Result<(), KexLinuxError> {
+        let mut sources = std::mem::replace(&mut self.sources, Vec::new());
+        for name in names {
+            match sources.iter().position(|s| s.name() == *name) {
+                Some(index) => self.sources.push(sources.remove(index)),
+                None => {
+                    error!("Unknown configuration source \"{}\"", name);
+                    return Err(KexLinuxError{});
+                },
+            }
+        }
+
+        for source in sources {
+            debug!("Configuration source \"{}\" disabled", source.name());
+        }
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.sources.iter().map(|s| s.name()).collect()
+    }
+
+    /// Try all sources in order and return configuration from the first
+    /// one that succeeds.
+    pub fn read(&self, root: &std::path::Path)
+            -> Result<BootConf, KexLinuxError> {
+        for source in &self.sources {
+            debug!("Trying configuration source \"{}\" on {:?}...",
+                   source.name(), root);
+            match source.read(root) {
+                Ok(conf) => {
+                    info!("Using \"{}\" configuration from {:?}",
+                          source.name(), root);
+                    return Ok(conf)
+                },
+                Err(_) => (),  // continue
+            }
+        }
+
+        debug!("No usable configuration found on {:?}", root);
+        Err(KexLinuxError{})
+    }
+
+    /// Check configuration using the first source that recognizes it.
+    pub fn check(&self, root: &std::path::Path)
+            -> Result<check::Report, KexLinuxError> {
+        for source in &self.sources {
+            match source.check(root) {
+                Ok(report) => {
+                    info!("Checked \"{}\" configuration on {:?}",
+                          source.name(), root);
+                    return Ok(report)
+                },
+                Err(_) => (),  // continue
+            }
+        }
+
+        error!("No configuration found on {:?}", root);
+        Err(KexLinuxError{})
+    }
+}
+
+#[cfg(test)]
+pub fn test_conf() -> BootConf {
+    let entry = |name: &str| BootEntry{
+        name: String::from(name),
+        title: None,
+
+        kernel: std::path::PathBuf::from("/vmlinuz"),
+        initrds: Vec::new(),
+        cmdline: Some(String::from("quiet")),
+        devicetree: None,
+        devicetree_dir: None,
+        devicetree_overlays: Vec::new(),
+        sysappend: 0,
+
+        help: None,
+        hidden: false,
+        disabled: false,
+        passwd: None,
+        counter: None,
+    };
+
+    BootConf{
+        timeout: Some(30.0),
+        total_timeout: None,
+
+        ontimeout_name: String::from("linux"),
+        onerror_name: None,
+
+        default_name: None,
+        entries: vec![entry("linux"), entry("rescue")],
+
+        title: None,
+        menu: Vec::new(),
+        master_passwd: None,
+
+        serial: None,
+        local_console: true,
+    }
+}
+
+#[test]
+fn suggests_similar_names() {
+    let conf = test_conf();
+    assert_eq!(conf.not_found("linx"),
+               "Entry \"linx\" not found, did you mean \"linux\"?");
+    // Jaro-Winkler similarity is 0.82 and 0.79, threshold is 0.8.
+    assert_eq!(conf.suggest("secure"), Some("rescue"));
+    assert_eq!(conf.suggest("lixxx"), None);
+    assert_eq!(conf.not_found("memtest"), "Entry \"memtest\" not found");
+}
@@ -0,0 +1,207 @@
+// Boot assessment like in systemd-boot: an entry whose name ends with
+// "+LEFT" or "+LEFT-DONE" is tried LEFT more times before it is considered
+// bad. Syslinux labels can not be renamed, so counters are kept in a sidecar
+// file in the state store, together with size and modification time of the
+// kernel they were counted for. A new kernel behind the same label starts
+// with the counters from its name again.
+
+use std;
+
+use state;
+
+// Followed by filesystem UUID of the boot source.
+const KEY_SIDECAR_PREFIX: &'static str = "boot-count.";
+// Entry booted with decremented counter, waiting for `mark-good`.
+const KEY_PENDING: &'static str = "boot-count-pending";
+
+// Sidecar value of entries marked good.
+const GOOD: &'static str = "good";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counter {
+    pub left: u32,
+    pub done: u32,
+}
+
+impl Counter {
+    /// Counter from entry name, e.g. "linux+3-1".
+    pub fn from_name(name: &str) -> Option<Counter> {
+        parse_name(name).map(|(_, left, done)| Counter{
+            left: left,
+            done: done,
+        })
+    }
+
+    /// No tries left, entry should not be booted by default.
+    pub fn is_bad(&self) -> bool {
+        self.left == 0
+    }
+}
+
+/// Split name into base and counters.
+pub fn parse_name(name: &str) -> Option<(&str, u32, u32)> {
+    let pos = match name.rfind('+') {
+        Some(pos) => pos,
+        None => return None,
+    };
+    let (base, counters) = (&name[..pos], &name[pos + 1..]);
+
+    let mut parts = counters.splitn(2, '-');
+    let left = match parts.next().map(|left| left.parse::<u32>()) {
+        Some(Ok(left)) => left,
+        _ => return None,
+    };
+    let done = match parts.next().map(|done| done.parse::<u32>()) {
+        Some(Ok(done)) => done,
+        Some(Err(_)) => return None,
+        None => 0,
+    };
+    Some((base, left, done))
+}
+
+/// Identity of the kernel counters apply to, "SIZE.MTIME". "-" if the
+/// kernel can not be read.
+pub fn kernel_id(path: &std::path::Path) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    match std::fs::metadata(path) {
+        Ok(metadata) => format!("{}.{}", metadata.len(), metadata.mtime()),
+        Err(_) => String::from("-"),
+    }
+}
+
+/// Sidecar counters by entry name, with kernel identity. Counters are
+/// `None` for entries marked good.
+pub type Sidecar =
+    std::collections::HashMap<String, (String, Option<(u32, u32)>)>;
+
+pub fn read_sidecar(store: &state::StateStore, uuid: &str)
+        -> Result<Sidecar, state::StateError> {
+    let text = try!(store.get(&format!("{}{}", KEY_SIDECAR_PREFIX, uuid)))
+        .unwrap_or_default();
+
+    let mut sidecar = Sidecar::new();
+    for line in text.lines() {
+        let values: Vec<&str> = line.split_whitespace().collect();
+        let counters = match values.len() {
+            0 => continue,
+            3 if values[2] == GOOD => None,
+            4 => match (values[2].parse(), values[3].parse()) {
+                (Ok(left), Ok(done)) => Some((left, done)),
+                _ => {
+                    warn!("Invalid boot count line \"{}\"", line);
+                    continue
+                },
+            },
+            _ => {
+                warn!("Invalid boot count line \"{}\"", line);
+                continue
+            },
+        };
+        sidecar.insert(String::from(values[0]),
+                       (String::from(values[1]), counters));
+    }
+    Ok(sidecar)
+}
+
+/// Sidecar counters of the entry, if they were counted for kernel `id`.
+pub fn lookup<'a>(sidecar: &'a Sidecar, name: &str, id: &str)
+        -> Option<&'a Option<(u32, u32)>> {
+    match sidecar.get(name) {
+        Some(&(ref counted, ref counters)) if counted == id => Some(counters),
+        _ => None,
+    }
+}
+
+fn write_sidecar(store: &state::StateStore, uuid: &str, sidecar: &Sidecar)
+        -> Result<(), state::StateError> {
+    let mut names: Vec<&String> = sidecar.keys().collect();
+    names.sort();
+
+    let lines: Vec<String> = names.into_iter()
+        .map(|name| match sidecar[name] {
+            (ref id, Some((left, done))) => {
+                format!("{} {} {} {}", name, id, left, done)
+            },
+            (ref id, None) => format!("{} {} {}", name, id, GOOD),
+        })
+        .collect();
+    store.set(&format!("{}{}", KEY_SIDECAR_PREFIX, uuid), &lines.join("\n"))
+}
+
+/// Decrement counter of the entry about to be booted and remember it for
+/// `mark_good()`. `id` is `kernel_id()` of the entry kernel.
+pub fn count_boot(store: &state::StateStore, uuid: &str, name: &str,
+                  id: &str, counter: &Counter)
+        -> Result<(), state::StateError> {
+    let counters = (counter.left.saturating_sub(1),
+                    counter.done.saturating_add(1));
+    info!("Boot counter of \"{}\": {} tries left", name, counters.0);
+
+    let mut sidecar = try!(read_sidecar(store, uuid));
+    sidecar.insert(String::from(name), (String::from(id), Some(counters)));
+    try!(write_sidecar(store, uuid, &sidecar));
+
+    store.set(KEY_PENDING, &format!("{}\n{}\n{}", uuid, name, id))
+}
+
+/// Boot counted by `count_boot()`, as (UUID, entry name, kernel identity).
+pub fn pending(store: &state::StateStore)
+        -> Result<Option<(String, String, String)>, state::StateError> {
+    let text = match try!(store.get(KEY_PENDING)) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let mut lines = text.lines().map(String::from);
+    match (lines.next(), lines.next(), lines.next()) {
+        (Some(uuid), Some(name), Some(id)) => Ok(Some((uuid, name, id))),
+        _ => {
+            warn!("Invalid pending boot count \"{}\"", text);
+            Ok(None)
+        },
+    }
+}
+
+pub fn clear_pending(store: &state::StateStore)
+        -> Result<(), state::StateError> {
+    store.remove(KEY_PENDING)
+}
+
+/// Mark sidecar counter of the entry good, as long as it boots kernel
+/// `id`.
+pub fn mark_good(store: &state::StateStore, uuid: &str, name: &str,
+                 id: &str)
+        -> Result<(), state::StateError> {
+    let mut sidecar = try!(read_sidecar(store, uuid));
+    sidecar.insert(String::from(name), (String::from(id), None));
+    write_sidecar(store, uuid, &sidecar)
+}
+
+#[test]
+fn counts_in_names() {
+    assert_eq!(parse_name("linux+3-1"), Some(("linux", 3, 1)));
+    assert_eq!(parse_name("linux+3"), Some(("linux", 3, 0)));
+    assert_eq!(parse_name("linux-5.10+x"), None);
+    assert_eq!(parse_name("linux"), None);
+}
+
+#[test]
+fn resets_good_mark_for_new_kernel() {
+    extern crate tempdir;
+
+    let dir = tempdir::TempDir::new("kexlinux-bootcount").unwrap();
+    let store = state::StateStore::new(dir.path().to_path_buf());
+    let counter = Counter::from_name("linux+3").unwrap();
+
+    count_boot(&store, "uuid", "linux+3", "100.1", &counter).unwrap();
+    assert_eq!(pending(&store).unwrap(),
+               Some((String::from("uuid"), String::from("linux+3"),
+                     String::from("100.1"))));
+    let sidecar = read_sidecar(&store, "uuid").unwrap();
+    assert_eq!(lookup(&sidecar, "linux+3", "100.1"), Some(&Some((2, 1))));
+
+    mark_good(&store, "uuid", "linux+3", "100.1").unwrap();
+    let sidecar = read_sidecar(&store, "uuid").unwrap();
+    assert_eq!(lookup(&sidecar, "linux+3", "100.1"), Some(&None));
+    assert_eq!(lookup(&sidecar, "linux+3", "200.2"), None);
+}
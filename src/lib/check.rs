@@ -0,0 +1,227 @@
+use std;
+
+extern crate serde_json;
+
+use bootconf;
+use inspect;
+use kexec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// Single problem found in boot configuration.
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    /// Label the issue is related to, `None` for global issues.
+    pub label: Option<String>,
+    pub message: String,
+}
+
+/// Result of the configuration check.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report::default()
+    }
+
+    pub fn push(&mut self, severity: Severity, label: Option<&str>,
+                message: String) {
+        self.issues.push(Issue{
+            severity: severity,
+            label: label.map(String::from),
+            message: message,
+        });
+    }
+
+    pub fn error(&mut self, label: Option<&str>, message: String) {
+        self.push(Severity::Error, label, message)
+    }
+
+    pub fn warning(&mut self, label: Option<&str>, message: String) {
+        self.push(Severity::Warning, label, message)
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues.iter().filter(|issue| issue.severity == severity).count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.count(Severity::Error) > 0
+    }
+
+    /// Check that file exists and is readable.
+    pub fn check_file(&mut self, label: &str, what: &str,
+                      path: &std::path::Path) {
+        match std::fs::File::open(path).and_then(|file| file.metadata()) {
+            Ok(ref metadata) if metadata.is_file() => (),
+            Ok(_) => self.error(Some(label), format!(
+                "{} {:?} is not a regular file", what, path)),
+            Err(err) => self.error(Some(label), format!(
+                "{} {:?} is not readable: {}", what, path, err)),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "errors": self.count(Severity::Error),
+            "warnings": self.count(Severity::Warning),
+            "issues": self.issues.iter().map(|issue| json!({
+                "severity": issue.severity.as_str(),
+                "label": issue.label,
+                "message": issue.message,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for issue in &self.issues {
+            match issue.label {
+                Some(ref label) => try!(writeln!(
+                    f, "{}: \"{}\": {}", issue.severity.as_str(), label,
+                    issue.message)),
+                None => try!(writeln!(
+                    f, "{}: {}", issue.severity.as_str(), issue.message)),
+            }
+        }
+        write!(f, "{} error(s), {} warning(s)", self.count(Severity::Error),
+               self.count(Severity::Warning))
+    }
+}
+
+/// Check format-neutral configuration: files referenced by entries should
+/// exist, kernels should run on this machine, command lines should not be
+/// empty.
+pub fn check_boot_conf(conf: &bootconf::BootConf) -> Report {
+    let mut report = Report::new();
+    for entry in &conf.entries {
+        check_entry(&mut report, entry);
+    }
+
+    for (what, name) in vec![("DEFAULT", &conf.default_name),
+                             ("ONERROR", &conf.onerror_name)] {
+        if let Some(ref name) = *name {
+            if conf.get(name).is_none() {
+                report.error(None, format!(
+                    "{} points to unknown entry \"{}\"", what, name));
+            }
+        }
+    }
+    report
+}
+
+pub fn check_entry(report: &mut Report, entry: &bootconf::BootEntry) {
+    report.check_file(&entry.name, "Kernel", &entry.kernel);
+    if entry.kernel.is_file() {
+        // As booted without kexec tool.
+        let result = inspect::inspect_path(&entry.kernel).and_then(|info| {
+            info.check(entry.cmdline.as_ref().map(|s| s.as_str()),
+                       kexec::Backend::default())
+        });
+        if let Err(err) = result {
+            report.error(Some(&entry.name), format!(
+                "Kernel {:?}: {}", entry.kernel, err));
+        }
+    }
+    for initrd in &entry.initrds {
+        report.check_file(&entry.name, "Initrd", initrd);
+    }
+    if let Some(ref devicetree) = entry.devicetree {
+        report.check_file(&entry.name, "Devicetree", devicetree);
+    }
+    for overlay in &entry.devicetree_overlays {
+        report.check_file(&entry.name, "Devicetree overlay", overlay);
+    }
+
+    match entry.cmdline {
+        Some(ref cmdline) if !cmdline.trim().is_empty() => (),
+        _ => report.warning(Some(&entry.name),
+                            String::from("Empty kernel command line")),
+    }
+}
+
+#[test]
+fn reports_problems() {
+    extern crate tempdir;
+    use std::io::Write;
+
+    let root = tempdir::TempDir::new("kexlinux-test").unwrap();
+    let kernel = root.path().join("vmlinuz");
+    std::fs::File::create(&kernel).unwrap().write_all(&[0u8; 512]).unwrap();
+    let entry = |name: &str, kernel: &std::path::Path, cmdline: Option<&str>|
+            bootconf::BootEntry{
+        name: String::from(name),
+        title: None,
+
+        kernel: kernel.to_path_buf(),
+        initrds: vec![root.path().join("missing.img")],
+        cmdline: cmdline.map(String::from),
+        devicetree: None,
+        devicetree_dir: None,
+        devicetree_overlays: Vec::new(),
+        sysappend: 0,
+
+        help: None,
+        hidden: false,
+        disabled: false,
+        passwd: None,
+        counter: None,
+    };
+    let mut good = entry("good", &kernel, Some("quiet"));
+    good.initrds.clear();
+    let conf = bootconf::BootConf{
+        timeout: None,
+        total_timeout: None,
+
+        ontimeout_name: String::from("good"),
+        onerror_name: None,
+
+        default_name: Some(String::from("unknown")),
+        entries: vec![
+            good,
+            entry("bad", &root.path().join("missing"), None),
+        ],
+
+        title: None,
+        menu: Vec::new(),
+        master_passwd: None,
+
+        serial: None,
+        local_console: true,
+    };
+
+    let report = check_boot_conf(&conf);
+    let issues: Vec<(Severity, Option<&str>)> = report.issues.iter()
+        .map(|issue| (issue.severity,
+                      issue.label.as_ref().map(|label| label.as_str())))
+        .collect();
+    assert_eq!(issues, vec![
+        (Severity::Error, Some("bad")),
+        (Severity::Error, Some("bad")),
+        (Severity::Warning, Some("bad")),
+        (Severity::Error, None),
+    ]);
+    assert!(report.issues[0].message.starts_with("Kernel"));
+    assert!(report.issues[1].message.starts_with("Initrd"));
+    assert!(report.issues[3].message.contains("DEFAULT"));
+    assert_eq!(report.count(Severity::Error), 3);
+    assert!(report.has_errors());
+}
@@ -0,0 +1,135 @@
+// Kernel command line editing. Splitting follows next_arg() from the kernel's
+// lib/cmdline.c: parameters are separated by whitespace outside of double
+// quotes, quotes may wrap the whole parameter or only the value.
+
+use std;
+
+// Parameters after this one are passed to init.
+const INIT_SEPARATOR: &'static str = "--";
+
+/// Split command line into parameters, keeping quotes.
+pub fn split(cmdline: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut param = String::new();
+    let mut in_quote = false;
+
+    for c in cmdline.chars() {
+        if c.is_whitespace() && !in_quote {
+            if !param.is_empty() {
+                params.push(std::mem::replace(&mut param, String::new()));
+            }
+            continue;
+        }
+
+        if c == '"' {
+            in_quote = !in_quote;
+        }
+        param.push(c);
+    }
+    if !param.is_empty() {
+        params.push(param);
+    }
+
+    params
+}
+
+/// Parameter name without quotes.
+pub fn key(param: &str) -> String {
+    param.splitn(2, '=').next().unwrap_or("").replace("\"", "")
+}
+
+fn normalize(param: &str) -> String {
+    param.replace("\"", "")
+}
+
+/// Modifications of the kernel command line.
+#[derive(Debug, Clone, Default)]
+pub struct CmdlineEdit {
+    /// New command line instead of the configured one.
+    pub replace: Option<String>,
+    /// Parameters to remove: "key" removes all parameters with that name,
+    /// "key=value" removes only exact matches.
+    pub remove: Vec<String>,
+    pub prepend: Vec<String>,
+    /// Parameters to add before init arguments ("--").
+    pub append: Vec<String>,
+}
+
+impl CmdlineEdit {
+    pub fn is_empty(&self) -> bool {
+        self.replace.is_none() && self.remove.is_empty() &&
+            self.prepend.is_empty() && self.append.is_empty()
+    }
+
+    fn is_removed(&self, param: &str) -> bool {
+        self.remove.iter().any(|remove| match remove.contains('=') {
+            true => normalize(remove) == normalize(param),
+            false => key(remove) == key(param),
+        })
+    }
+
+    pub fn apply(&self, cmdline: Option<&str>) -> Option<String> {
+        if self.is_empty() {
+            return cmdline.map(String::from);
+        }
+
+        let base = match self.replace {
+            Some(ref replace) => Some(replace.as_str()),
+            None => cmdline,
+        };
+        let params = split(base.unwrap_or(""));
+        let (kernel_params, init_params) = match params.iter()
+                .position(|param| param == INIT_SEPARATOR) {
+            Some(pos) => (&params[..pos], &params[pos..]),
+            None => (&params[..], &params[params.len()..]),
+        };
+
+        let mut result: Vec<String> = self.prepend.iter()
+            .flat_map(|param| split(param))
+            .collect();
+        result.extend(kernel_params.iter()
+            .filter(|param| !self.is_removed(param))
+            .cloned());
+        result.extend(self.append.iter().flat_map(|param| split(param)));
+        result.extend(init_params.iter().cloned());
+
+        match result.is_empty() {
+            true => None,
+            false => Some(result.join(" ")),
+        }
+    }
+}
+
+#[test]
+fn splits_with_kernel_quoting() {
+    assert_eq!(split("  root=/dev/sda1 \"quoted param\" key=\"a b\"  quiet "),
+               vec!["root=/dev/sda1", "\"quoted param\"", "key=\"a b\"",
+                    "quiet"]);
+    assert_eq!(key("key=\"a b\""), "key");
+    assert_eq!(key("\"key=a b\""), "key");
+}
+
+#[test]
+fn applies_edits() {
+    let edit = CmdlineEdit{
+        replace: None,
+        remove: vec![String::from("quiet"), String::from("console=tty0")],
+        prepend: vec![String::from("earlyprintk=serial")],
+        append: vec![String::from("console=ttyS0,115200"),
+                     String::from("systemd.unit=rescue.target")],
+    };
+    assert_eq!(
+        edit.apply(Some("root=/dev/sda1 quiet console=tty0 console=tty1 \
+                         -- single")),
+        Some(String::from("earlyprintk=serial root=/dev/sda1 console=tty1 \
+                           console=ttyS0,115200 systemd.unit=rescue.target \
+                           -- single")));
+
+    let edit = CmdlineEdit{
+        replace: Some(String::from("root=/dev/sdb1")),
+        ..CmdlineEdit::default()
+    };
+    assert_eq!(edit.apply(Some("root=/dev/sda1 quiet")),
+               Some(String::from("root=/dev/sdb1")));
+    assert_eq!(CmdlineEdit::default().apply(None), None);
+}
@@ -0,0 +1,272 @@
+// Kernels which kexec_file_load(2) does not take directly: images compressed
+// as a whole (arm64 and riscv Image.gz etc.) and EFI zboot images, where a PE
+// stub carries the compressed kernel as payload. These are decompressed into
+// anonymous memory file before loading.
+
+use std;
+
+extern crate flate2;
+extern crate xz2;
+extern crate zstd;
+
+use initrd;
+
+const GZIP_MAGIC: &'static [u8] = b"\x1f\x8b";
+const ZSTD_MAGIC: &'static [u8] = b"\x28\xb5\x2f\xfd";
+const XZ_MAGIC: &'static [u8] = b"\xfd7zXZ\x00";
+
+// Header of EFI zboot images, see drivers/firmware/efi/libstub/zboot-header.S.
+const ZBOOT_MZ_MAGIC: &'static [u8] = b"MZ";
+const ZBOOT_MAGIC: &'static [u8] = b"zimg";
+const ZBOOT_MAGIC_OFFSET: usize = 4;
+const ZBOOT_PAYLOAD_OFFSET: usize = 8;
+const ZBOOT_PAYLOAD_SIZE: usize = 12;
+const ZBOOT_COMP_TYPE: usize = 24;
+const ZBOOT_COMP_TYPE_END: usize = 0x38;
+
+// TODO: Detailed errors.
+#[derive(Debug)]
+pub struct DecompressError {}
+
+impl std::convert::From<std::io::Error> for DecompressError {
+    fn from(_: std::io::Error) -> DecompressError { DecompressError{} }
+}
+
+impl std::convert::From<initrd::InitrdError> for DecompressError {
+    fn from(_: initrd::InitrdError) -> DecompressError { DecompressError{} }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    fn from_magic(data: &[u8]) -> Option<Compression> {
+        if data.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if data.starts_with(XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    // Compression type of zboot header. Makefile.zboot writes tool name
+    // with options, e.g. "zstd22" or "xzkern".
+    fn from_name(name: &str) -> Option<Compression> {
+        if name.starts_with("gzip") {
+            Some(Compression::Gzip)
+        } else if name.starts_with("zstd") {
+            Some(Compression::Zstd)
+        } else if name.starts_with("xz") {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        }
+    }
+}
+
+/// Where the compressed kernel is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Whole file is compressed.
+    Compressed(Compression),
+    /// EFI zboot image with compressed payload.
+    Zboot(Compression),
+}
+
+impl Format {
+    pub fn compression(&self) -> Compression {
+        match *self {
+            Format::Compressed(compression) => compression,
+            Format::Zboot(compression) => compression,
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Format::Compressed(compression) => {
+                write!(f, "{} compressed", compression.name())
+            },
+            Format::Zboot(compression) => {
+                write!(f, "EFI zboot with {} payload", compression.name())
+            },
+        }
+    }
+}
+
+fn get_u32(data: &[u8], offset: usize) -> usize {
+    data[offset..offset + 4].iter().rev()
+        .fold(0, |value, &byte| value << 8 | byte as usize)
+}
+
+// Format and compressed data, `None` for kernels loadable as is.
+fn detect(data: &[u8]) -> Result<Option<(Format, &[u8])>, DecompressError> {
+    if let Some(compression) = Compression::from_magic(data) {
+        return Ok(Some((Format::Compressed(compression), data)));
+    }
+
+    let zboot = data.len() >= ZBOOT_COMP_TYPE_END &&
+        data.starts_with(ZBOOT_MZ_MAGIC) &&
+        &data[ZBOOT_MAGIC_OFFSET..ZBOOT_MAGIC_OFFSET + 4] == ZBOOT_MAGIC;
+    if !zboot {
+        return Ok(None);
+    }
+
+    let name = &data[ZBOOT_COMP_TYPE..ZBOOT_COMP_TYPE_END];
+    let name = String::from_utf8_lossy(
+        &name[..name.iter().position(|&c| c == 0).unwrap_or(name.len())])
+        .into_owned();
+    let compression = match Compression::from_name(&name) {
+        Some(compression) => compression,
+        None => {
+            error!("Unsupported EFI zboot compression \"{}\"", name);
+            return Err(DecompressError{});
+        },
+    };
+
+    let offset = get_u32(data, ZBOOT_PAYLOAD_OFFSET);
+    let size = get_u32(data, ZBOOT_PAYLOAD_SIZE);
+    match offset.checked_add(size).and_then(|end| data.get(offset..end)) {
+        Some(payload) => Ok(Some((Format::Zboot(compression), payload))),
+        None => {
+            error!("EFI zboot payload is out of image");
+            Err(DecompressError{})
+        },
+    }
+}
+
+fn decompress_to<W: std::io::Write>(compression: Compression, data: &[u8],
+                                    output: &mut W)
+        -> Result<u64, DecompressError> {
+    let result = match compression {
+        Compression::Gzip => {
+            std::io::copy(&mut flate2::read::GzDecoder::new(data), output)
+        },
+        Compression::Zstd => zstd::stream::read::Decoder::new(data)
+            .and_then(|mut decoder| std::io::copy(&mut decoder, output)),
+        Compression::Xz => {
+            std::io::copy(&mut xz2::read::XzDecoder::new(data), output)
+        },
+    };
+    match result {
+        Ok(size) => Ok(size),
+        Err(err) => {
+            error!("Unable to decompress {} data: {}", compression.name(),
+                   err);
+            Err(DecompressError{})
+        },
+    }
+}
+
+/// Kernel in anonymous memory file, decompressed if needed.
+#[derive(Debug)]
+pub struct Kernel {
+    file: std::fs::File,
+    format: Option<Format>,
+}
+
+impl Kernel {
+    /// Kernel read from `path` as `data`, copied as is if it is not
+    /// compressed. Unlike the file, the copy can not change once `data`
+    /// was verified.
+    pub fn load(path: &std::path::Path, data: &[u8])
+            -> Result<Kernel, DecompressError> {
+        use std::io::{Seek, Write};
+
+        let detected = try!(detect(data));
+        let mut file = try!(initrd::Initrd::memfd("kexlinux-kernel"));
+        match detected {
+            Some((format, compressed)) => {
+                let size = try!(decompress_to(format.compression(),
+                                              compressed, &mut file));
+                info!("Kernel {:?} is {}, {} bytes decompressed", path,
+                      format, size);
+            },
+            None => try!(file.write_all(data)),
+        }
+        try!(file.seek(std::io::SeekFrom::Start(0)));
+
+        Ok(Kernel{
+            file: file,
+            format: detected.map(|(format, _)| format),
+        })
+    }
+
+    pub fn file(&self) -> &std::fs::File {
+        &self.file
+    }
+
+    /// `None` if the kernel was not compressed.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// Path usable by other processes while this object is alive.
+    pub fn path(&self) -> std::path::PathBuf {
+        use std::os::unix::io::AsRawFd;
+        std::path::PathBuf::from(format!("/proc/{}/fd/{}", std::process::id(),
+                                         self.file.as_raw_fd()))
+    }
+}
+
+#[test]
+fn decompresses_zboot_payload() {
+    use std::io::Write;
+
+    let kernel = b"ARM\x64 kernel image".repeat(100);
+    let mut encoder = flate2::write::GzEncoder::new(
+        Vec::new(), flate2::Compression::default());
+    encoder.write_all(&kernel).unwrap();
+    let gzip = encoder.finish().unwrap();
+    let zstd = zstd::stream::encode_all(&kernel[..], 22).unwrap();
+    let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 9);
+    encoder.write_all(&kernel).unwrap();
+    let xz = encoder.finish().unwrap();
+
+    // Names as written by Makefile.zboot.
+    let payloads = [
+        ("gzip", Compression::Gzip, &gzip),
+        ("zstd22", Compression::Zstd, &zstd),
+        ("xzkern", Compression::Xz, &xz),
+    ];
+    for &(name, compression, compressed) in payloads.iter() {
+        let mut image = vec![0u8; 0x40];
+        image[..2].copy_from_slice(ZBOOT_MZ_MAGIC);
+        image[ZBOOT_MAGIC_OFFSET..ZBOOT_MAGIC_OFFSET + 4]
+            .copy_from_slice(ZBOOT_MAGIC);
+        image[ZBOOT_PAYLOAD_OFFSET] = 0x40;
+        for i in 0..4 {
+            image[ZBOOT_PAYLOAD_SIZE + i] = (compressed.len() >> (i * 8)) as u8;
+        }
+        image[ZBOOT_COMP_TYPE..ZBOOT_COMP_TYPE + name.len()]
+            .copy_from_slice(name.as_bytes());
+        image.extend_from_slice(compressed);
+
+        let (format, payload) = detect(&image).unwrap().unwrap();
+        assert_eq!(format, Format::Zboot(compression));
+        let mut output = Vec::new();
+        decompress_to(format.compression(), payload, &mut output).unwrap();
+        assert_eq!(output, kernel);
+
+        assert_eq!(detect(compressed).unwrap().unwrap().0,
+                   Format::Compressed(compression));
+    }
+
+    assert!(detect(b"MZ\0\0not zboot").unwrap().is_none());
+}
@@ -0,0 +1,319 @@
+use std;
+
+extern crate tempdir;
+
+use bootconf;
+
+const PATHS_MACHINE_COMPATIBLE: &'static [&'static str] = &[
+    "/proc/device-tree/compatible",
+    "/sys/firmware/devicetree/base/compatible",
+];
+
+const CMD_FDTOVERLAY: &'static str = "fdtoverlay";
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Vendor subdirectories are common in FDTDIR (e.g. "rockchip/*.dtb").
+const MAX_SEARCH_DEPTH: usize = 2;
+
+// TODO: Detailed errors.
+#[derive(Debug)]
+pub struct DevicetreeError {}
+
+impl std::convert::From<std::io::Error> for DevicetreeError {
+    fn from(_: std::io::Error) -> DevicetreeError { DevicetreeError{} }
+}
+
+/// Resolved devicetree blob ready to be passed to kexec. Temporary files
+/// are removed when dropped.
+#[derive(Debug)]
+pub struct Devicetree {
+    path: std::path::PathBuf,
+    // Keeps blob with applied overlays alive.
+    _temp_dir: Option<tempdir::TempDir>,
+}
+
+impl Devicetree {
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+fn read_file(path: &std::path::Path) -> Result<Vec<u8>, DevicetreeError> {
+    use std::io::prelude::*;
+    let mut buf = Vec::new();
+    let mut file = try!(std::fs::File::open(path));
+    try!(file.read_to_end(&mut buf));
+    Ok(buf)
+}
+
+fn split_string_list(data: &[u8]) -> Vec<String> {
+    data.split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32, DevicetreeError> {
+    match data.get(offset..offset + 4) {
+        Some(b) => Ok(((b[0] as u32) << 24) | ((b[1] as u32) << 16) |
+                      ((b[2] as u32) << 8) | (b[3] as u32)),
+        None => Err(DevicetreeError{}),
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Read "compatible" property of the root node from flattened devicetree.
+pub fn dtb_compatible(dtb: &[u8]) -> Result<Vec<String>, DevicetreeError> {
+    if try!(be32(dtb, 0)) != FDT_MAGIC {
+        return Err(DevicetreeError{});
+    }
+    let off_struct = try!(be32(dtb, 8)) as usize;
+    let off_strings = try!(be32(dtb, 12)) as usize;
+
+    let mut offset = off_struct;
+    let mut depth = 0;
+    loop {
+        let token = try!(be32(dtb, offset));
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                depth += 1;
+                if depth > 1 {
+                    // Root properties always go before subnodes.
+                    break;
+                }
+                let name_len = try!(dtb.get(offset..)
+                    .and_then(|s| s.iter().position(|b| *b == 0))
+                    .ok_or(DevicetreeError{}));
+                offset = align4(offset + name_len + 1);
+            },
+
+            FDT_PROP => {
+                let len = try!(be32(dtb, offset)) as usize;
+                let name_offset = try!(be32(dtb, offset + 4)) as usize;
+                offset += 8;
+
+                let name_start = off_strings + name_offset;
+                let name = try!(dtb.get(name_start..)
+                    .and_then(|s| s.split(|b| *b == 0).next())
+                    .ok_or(DevicetreeError{}));
+                if name == b"compatible" {
+                    let value = try!(dtb.get(offset..offset + len)
+                        .ok_or(DevicetreeError{}));
+                    return Ok(split_string_list(value));
+                }
+                offset = align4(offset + len);
+            },
+
+            FDT_NOP => (),
+
+            FDT_END_NODE | FDT_END => break,
+
+            _ => return Err(DevicetreeError{}),
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+/// Compatible strings of the running machine, most specific first.
+pub fn machine_compatible() -> Vec<String> {
+    for path in PATHS_MACHINE_COMPATIBLE {
+        if let Ok(data) = read_file(std::path::Path::new(path)) {
+            return split_string_list(&data);
+        }
+    }
+    Vec::new()
+}
+
+fn collect_dtbs(dir: &std::path::Path, depth: usize,
+                dtbs: &mut Vec<std::path::PathBuf>) {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            if depth < MAX_SEARCH_DEPTH {
+                collect_dtbs(&path, depth + 1, dtbs);
+            }
+        } else if path.extension().map(|ext| ext == "dtb").unwrap_or(false) {
+            dtbs.push(path);
+        }
+    }
+}
+
+/// Find devicetree blob matching the machine in the directory. Blob
+/// matches if its compatible list contains the most specific compatible
+/// string of the machine. Blobs where it goes first are preferred.
+pub fn find_in_dir(dir: &std::path::Path, machine_compatible: &[String])
+        -> Option<std::path::PathBuf> {
+    let machine = match machine_compatible.first() {
+        Some(machine) => machine,
+        None => {
+            warn!("Machine compatible string is unknown, unable to choose \
+                   devicetree from {:?}", dir);
+            return None;
+        },
+    };
+
+    let mut dtbs = Vec::new();
+    collect_dtbs(dir, 0, &mut dtbs);
+    dtbs.sort();
+
+    let mut fallback = None;
+    for dtb in dtbs {
+        let compatible = match read_file(&dtb).and_then(
+                |data| dtb_compatible(&data)) {
+            Ok(compatible) => compatible,
+            Err(_) => {
+                debug!("Unable to read compatible from {:?}, skipping", dtb);
+                continue;
+            },
+        };
+
+        if compatible.first() == Some(machine) {
+            return Some(dtb);
+        } else if fallback.is_none() && compatible.contains(machine) {
+            fallback = Some(dtb);
+        }
+    }
+
+    fallback
+}
+
+fn apply_overlays(base: &std::path::Path,
+                  overlays: &[std::path::PathBuf])
+        -> Result<Devicetree, DevicetreeError> {
+    let temp_dir = try!(tempdir::TempDir::new("kexlinux-dtb"));
+    let path = temp_dir.path().join("devicetree.dtb");
+
+    let mut cmd = std::process::Command::new(CMD_FDTOVERLAY);
+    cmd.arg("-i").arg(base);
+    cmd.arg("-o").arg(&path);
+    cmd.args(overlays);
+    cmd.stdin(std::process::Stdio::null());
+
+    let output = match cmd.output() {
+        Ok(output) => output,
+        Err(err) => {
+            error!("Unable to run {:?} to apply devicetree overlays, it is \
+                    part of dtc: {}", CMD_FDTOVERLAY, err);
+            return Err(DevicetreeError::from(err));
+        },
+    };
+    match output.status.success() {
+        true => Ok(Devicetree{
+            path: path,
+            _temp_dir: Some(temp_dir),
+        }),
+        false => {
+            use std::os::unix::process::ExitStatusExt;
+            error!("fdtoverlay command ({:?}) failed with return code \
+                    {:?} || signal {:?}", cmd, output.status.code(),
+                   output.status.signal());
+            error!("stdout: \"{}\"",
+                   String::from_utf8_lossy(&output.stdout));
+            error!("stderr: \"{}\"",
+                   String::from_utf8_lossy(&output.stderr));
+            Err(DevicetreeError{})
+        },
+    }
+}
+
+/// Base devicetree of the entry: explicit blob or blob from directory
+/// matching the machine, without overlays.
+pub fn find_base(entry: &bootconf::BootEntry)
+        -> Result<Option<std::path::PathBuf>, DevicetreeError> {
+    match (&entry.devicetree, &entry.devicetree_dir) {
+        (&Some(ref devicetree), _) => Ok(Some(devicetree.clone())),
+        (&None, &Some(ref devicetree_dir)) => {
+            match find_in_dir(devicetree_dir, &machine_compatible()) {
+                Some(devicetree) => Ok(Some(devicetree)),
+                None => {
+                    error!("No devicetree for this machine in {:?}",
+                           devicetree_dir);
+                    Err(DevicetreeError{})
+                },
+            }
+        },
+        (&None, &None) => {
+            if !entry.devicetree_overlays.is_empty() {
+                warn!("Devicetree overlays without base devicetree ignored");
+            }
+            Ok(None)
+        },
+    }
+}
+
+/// Resolve devicetree for the entry: base devicetree and overlays applied
+/// on top. Overlays are applied by external `fdtoverlay` command from dtc.
+pub fn resolve(entry: &bootconf::BootEntry)
+        -> Result<Option<Devicetree>, DevicetreeError> {
+    let base = match try!(find_base(entry)) {
+        Some(base) => base,
+        None => return Ok(None),
+    };
+
+    if entry.devicetree_overlays.is_empty() {
+        Ok(Some(Devicetree{
+            path: base,
+            _temp_dir: None,
+        }))
+    } else {
+        apply_overlays(&base, &entry.devicetree_overlays).map(Some)
+    }
+}
+
+#[test]
+fn reads_root_compatible() {
+    // Root node with "compatible" and "model", and one empty subnode.
+    let mut dtb: Vec<u8> = Vec::new();
+    let push32 = |dtb: &mut Vec<u8>, v: u32| {
+        dtb.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8,
+                                (v >> 8) as u8, v as u8]);
+    };
+    let strings = b"model\0compatible\0";
+    let compatible = b"pine64,rockpro64\0rockchip,rk3399\0";
+
+    let mut structure: Vec<u8> = Vec::new();
+    push32(&mut structure, FDT_BEGIN_NODE);
+    push32(&mut structure, 0);  // Empty name, padded.
+    push32(&mut structure, FDT_PROP);
+    push32(&mut structure, 4);
+    push32(&mut structure, 0);
+    structure.extend_from_slice(b"abc\0");
+    push32(&mut structure, FDT_PROP);
+    push32(&mut structure, compatible.len() as u32);
+    push32(&mut structure, 6);
+    structure.extend_from_slice(compatible);
+    while structure.len() % 4 != 0 {
+        structure.push(0);
+    }
+    push32(&mut structure, FDT_END_NODE);
+    push32(&mut structure, FDT_END);
+
+    let off_struct = 40;
+    let off_strings = off_struct + structure.len();
+    for v in &[FDT_MAGIC, 0, off_struct as u32, off_strings as u32,
+               0, 17, 16, 0, strings.len() as u32, structure.len() as u32] {
+        push32(&mut dtb, *v);
+    }
+    dtb.extend_from_slice(&structure);
+    dtb.extend_from_slice(strings);
+
+    assert_eq!(dtb_compatible(&dtb).unwrap(),
+               vec![String::from("pine64,rockpro64"),
+                    String::from("rockchip,rk3399")]);
+}
@@ -0,0 +1,93 @@
+use std;
+
+extern crate libc;
+
+// Each archive should start at 4-byte boundary, kernel skips zeroes
+// between archives.
+const ARCHIVE_ALIGN: u64 = 4;
+
+// TODO: Detailed errors.
+#[derive(Debug)]
+pub struct InitrdError {}
+
+impl std::convert::From<std::io::Error> for InitrdError {
+    fn from(_: std::io::Error) -> InitrdError { InitrdError{} }
+}
+
+/// Initramfs assembled in anonymous memory file.
+#[derive(Debug)]
+pub struct Initrd {
+    file: std::fs::File,
+}
+
+impl Initrd {
+    /// Empty anonymous memory file.
+    pub fn memfd(name: &str) -> Result<std::fs::File, InitrdError> {
+        use std::os::unix::io::FromRawFd;
+
+        let name = try!(std::ffi::CString::new(name)
+            .map_err(|_| InitrdError{}));
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        match fd {
+            -1 => {
+                error!("memfd_create() failed: {}",
+                       std::io::Error::last_os_error());
+                Err(InitrdError{})
+            },
+            fd => Ok(unsafe { std::fs::File::from_raw_fd(fd) }),
+        }
+    }
+
+    /// Concatenate initrds in order, aligning every archive.
+    pub fn concat(paths: &[std::path::PathBuf]) -> Result<Initrd, InitrdError> {
+        use std::io::prelude::*;
+
+        let mut file = try!(Initrd::memfd("kexlinux-initrd"));
+        let mut size = 0u64;
+        for path in paths {
+            let padding = (ARCHIVE_ALIGN - size % ARCHIVE_ALIGN) % ARCHIVE_ALIGN;
+            try!(file.write_all(&[0u8; 4][..padding as usize]));
+            size += padding;
+
+            debug!("Appending initrd {:?} at offset {}", path, size);
+            let mut initrd = try!(std::fs::File::open(path));
+            size += try!(std::io::copy(&mut initrd, &mut file));
+        }
+        try!(file.seek(std::io::SeekFrom::Start(0)));
+
+        debug!("Initramfs of {} bytes assembled from {} files",
+               size, paths.len());
+        Ok(Initrd{
+            file: file,
+        })
+    }
+
+    pub fn file(&self) -> &std::fs::File {
+        &self.file
+    }
+
+    /// Path usable by other processes while this object is alive.
+    pub fn path(&self) -> std::path::PathBuf {
+        use std::os::unix::io::AsRawFd;
+        std::path::PathBuf::from(format!("/proc/{}/fd/{}", std::process::id(),
+                                         self.file.as_raw_fd()))
+    }
+}
+
+#[test]
+fn concatenates_with_alignment() {
+    extern crate tempdir;
+    use std::io::prelude::*;
+
+    let dir = tempdir::TempDir::new("kexlinux-test").unwrap();
+    let first = dir.path().join("first.img");
+    let second = dir.path().join("second.img");
+    std::fs::File::create(&first).unwrap().write_all(b"12345").unwrap();
+    std::fs::File::create(&second).unwrap().write_all(b"abc").unwrap();
+
+    let initrd = Initrd::concat(&[first, second]).unwrap();
+    let mut data = Vec::new();
+    std::fs::File::open(initrd.path()).unwrap()
+        .read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"12345\0\0\0abc");
+}
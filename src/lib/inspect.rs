@@ -0,0 +1,528 @@
+// Identification of kernel images, so a truncated or wrong architecture
+// kernel is rejected with a clear message instead of failing inside kexec.
+// Compressed images should be decompressed first, see decompress.
+
+use std;
+
+extern crate libc;
+
+use decompress;
+use kexec;
+
+// Setup header of bzImage, see Documentation/x86/boot.rst.
+const BZ_SETUP_SECTS: usize = 0x1f1;
+const BZ_SYSSIZE: usize = 0x1f4;
+const BZ_HEADER: usize = 0x202;
+const BZ_VERSION: usize = 0x206;
+const BZ_KERNEL_VERSION: usize = 0x20e;
+const BZ_XLOADFLAGS: usize = 0x236;
+const BZ_CMDLINE_SIZE: usize = 0x238;
+const BZ_MAGIC: &'static [u8] = b"HdrS";
+// Default of protocols before 2.06.
+const BZ_DEFAULT_CMDLINE_SIZE: u32 = 255;
+const XLF_KERNEL_64: u16 = 0x0001;
+
+// Header of arm64 and RISC-V Image, see Documentation/arm64/booting.rst.
+const IMAGE_HEADER_SIZE: usize = 0x40;
+const IMAGE_MAGIC_OFFSET: usize = 0x38;
+const ARM64_MAGIC: &'static [u8] = b"ARM\x64";
+const RISCV_MAGIC: &'static [u8] = b"RSC\x05";
+
+const ELF_MAGIC: &'static [u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2MSB: u8 = 2;
+const PT_LOAD: u64 = 1;
+
+const PE_MZ_MAGIC: &'static [u8] = b"MZ";
+const PE_OFFSET: usize = 0x3c;
+const PE_MAGIC: &'static [u8] = b"PE\0\0";
+const PE_SECTION_SIZE: usize = 40;
+// Sections of unified kernel image.
+const UKI_LINUX_SECTION: &'static str = ".linux";
+const UKI_UNAME_SECTION: &'static str = ".uname";
+
+const LINUX_VERSION: &'static [u8] = b"Linux version ";
+
+#[derive(Debug)]
+pub struct InspectError {
+    message: String,
+}
+
+impl InspectError {
+    fn new(message: String) -> InspectError {
+        InspectError{
+            message: message,
+        }
+    }
+}
+
+impl std::fmt::Display for InspectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::convert::From<std::io::Error> for InspectError {
+    fn from(err: std::io::Error) -> InspectError {
+        InspectError::new(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelFormat {
+    BzImage {
+        protocol: u16,
+        xloadflags: u16,
+        cmdline_size: u32,
+    },
+    Arm64Image,
+    RiscvImage,
+    Elf,
+    /// PE image with EFI stub.
+    Pe,
+    /// Unified kernel image: PE with kernel, initrd and command line in
+    /// sections.
+    Uki,
+    Unknown,
+}
+
+impl std::fmt::Display for KernelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            KernelFormat::BzImage{protocol, xloadflags, cmdline_size} => write!(
+                f, "bzImage (boot protocol {}.{:02}, xloadflags {:#x}, \
+                    cmdline_size {})",
+                protocol >> 8, protocol & 0xff, xloadflags, cmdline_size),
+            KernelFormat::Arm64Image => write!(f, "arm64 Image"),
+            KernelFormat::RiscvImage => write!(f, "RISC-V Image"),
+            KernelFormat::Elf => write!(f, "ELF"),
+            KernelFormat::Pe => write!(f, "PE/EFI stub"),
+            KernelFormat::Uki => write!(f, "UKI"),
+            KernelFormat::Unknown => write!(f, "unknown format"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelInfo {
+    pub format: KernelFormat,
+    /// Architecture in the style of `uname -m`, with "x86" for all 32-bit
+    /// x86 variants and "arm" for 32-bit ARM. `None` if not known.
+    pub arch: Option<&'static str>,
+    /// Embedded version string.
+    pub version: Option<String>,
+}
+
+// kexec tool loads 32-bit x86 kernels on x86_64 too, kexec_file_load(2)
+// takes only 64-bit ones.
+fn compatible(arch: &str, machine: &str, backend: kexec::Backend) -> bool {
+    match (arch, machine, backend) {
+        ("x86", "x86_64", kexec::Backend::Tool) => true,
+        (arch, machine, _) => arch == machine,
+    }
+}
+
+impl KernelInfo {
+    /// Check that the kernel can be loaded by `backend` and run on this
+    /// machine with the command line.
+    pub fn check(&self, cmdline: Option<&str>, backend: kexec::Backend)
+            -> Result<(), InspectError> {
+        if self.format == KernelFormat::Uki {
+            return Err(InspectError::new(String::from(
+                "UKI can not be loaded by kexec, kernel and initrd have to be \
+                 extracted from it")));
+        }
+
+        let machine = machine();
+        if let (Some(arch), Some(ref machine)) = (self.arch, machine) {
+            if !compatible(arch, machine, backend) {
+                return Err(InspectError::new(format!(
+                    "kernel is for {}, this machine is {}", arch, machine)));
+            }
+        }
+
+        if let KernelFormat::BzImage{cmdline_size, ..} = self.format {
+            let length = cmdline.map(|cmdline| cmdline.len()).unwrap_or(0);
+            if length > cmdline_size as usize {
+                return Err(InspectError::new(format!(
+                    "command line is {} bytes long, kernel supports only {}",
+                    length, cmdline_size)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for KernelInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        try!(write!(f, "{}", self.format));
+        if let Some(arch) = self.arch {
+            try!(write!(f, " for {}", arch));
+        }
+        if let Some(ref version) = self.version {
+            try!(write!(f, ", version \"{}\"", version));
+        }
+        Ok(())
+    }
+}
+
+fn normalize_arch(machine: &str) -> String {
+    match machine {
+        "i386" | "i486" | "i586" | "i686" => String::from("x86"),
+        "arm64" => String::from("aarch64"),
+        "ppc64" | "ppc64le" => String::from("powerpc64"),
+        machine if machine.starts_with("arm") => String::from("arm"),
+        machine => String::from(machine),
+    }
+}
+
+/// Architecture of the running kernel.
+pub fn machine() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    let machine = unsafe { std::ffi::CStr::from_ptr(uts.machine.as_ptr()) };
+    Some(normalize_arch(&machine.to_string_lossy()))
+}
+
+// Integer of `size` bytes, `None` if out of data.
+fn get(data: &[u8], offset: usize, size: usize, big_endian: bool)
+        -> Option<u64> {
+    let bytes = match offset.checked_add(size)
+            .and_then(|end| data.get(offset..end)) {
+        Some(bytes) => bytes,
+        None => return None,
+    };
+    let fold = |value: u64, &byte: &u8| value << 8 | byte as u64;
+    Some(match big_endian {
+        true => bytes.iter().fold(0, fold),
+        false => bytes.iter().rev().fold(0, fold),
+    })
+}
+
+fn get_le(data: &[u8], offset: usize, size: usize) -> Option<u64> {
+    get(data, offset, size, false)
+}
+
+fn truncated(what: &str) -> InspectError {
+    InspectError::new(format!("{} is truncated", what))
+}
+
+// String up to NUL or end of line.
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&c| c == 0 || c == b'\n')
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+// "Linux version ..." banner of uncompressed kernels.
+fn find_banner(data: &[u8]) -> Option<String> {
+    data.windows(LINUX_VERSION.len())
+        .position(|window| window == LINUX_VERSION)
+        .map(|pos| {
+            let start = pos + LINUX_VERSION.len();
+            c_string(&data[start..std::cmp::min(start + 256, data.len())])
+        })
+}
+
+fn inspect_bzimage(data: &[u8]) -> Result<KernelInfo, InspectError> {
+    let protocol = try!(get_le(data, BZ_VERSION, 2)
+        .ok_or_else(|| truncated("bzImage header"))) as u16;
+    let setup_sects = match data[BZ_SETUP_SECTS] {
+        0 => 4,
+        sects => sects as usize,
+    };
+    if protocol >= 0x0204 {
+        let syssize = try!(get_le(data, BZ_SYSSIZE, 4)
+            .ok_or_else(|| truncated("bzImage header"))) as usize;
+        let expected = (setup_sects + 1) * 512 + syssize * 16;
+        if data.len() < expected {
+            return Err(InspectError::new(format!(
+                "bzImage is truncated: {} of {} bytes", data.len(),
+                expected)));
+        }
+    }
+
+    let xloadflags = match protocol >= 0x020c {
+        true => get_le(data, BZ_XLOADFLAGS, 2).unwrap_or(0) as u16,
+        false => 0,
+    };
+    let cmdline_size = match protocol >= 0x0206 {
+        true => get_le(data, BZ_CMDLINE_SIZE, 4)
+            .unwrap_or(BZ_DEFAULT_CMDLINE_SIZE as u64) as u32,
+        false => BZ_DEFAULT_CMDLINE_SIZE,
+    };
+    let arch = match (protocol >= 0x020c, xloadflags & XLF_KERNEL_64 != 0) {
+        (true, true) => Some("x86_64"),
+        (true, false) => Some("x86"),
+        (false, _) => None,
+    };
+    let version = match get_le(data, BZ_KERNEL_VERSION, 2) {
+        Some(0) | None => None,
+        Some(offset) => data.get(0x200 + offset as usize..).map(c_string),
+    };
+
+    Ok(KernelInfo{
+        format: KernelFormat::BzImage{
+            protocol: protocol,
+            xloadflags: xloadflags,
+            cmdline_size: cmdline_size,
+        },
+        arch: arch,
+        version: version,
+    })
+}
+
+fn elf_arch(machine: u64, class64: bool) -> Option<&'static str> {
+    match (machine, class64) {
+        (3, _) => Some("x86"),
+        (62, _) => Some("x86_64"),
+        (40, _) => Some("arm"),
+        (183, _) => Some("aarch64"),
+        (243, true) => Some("riscv64"),
+        (21, _) => Some("powerpc64"),
+        (22, true) => Some("s390x"),
+        _ => None,
+    }
+}
+
+fn inspect_elf(data: &[u8]) -> Result<KernelInfo, InspectError> {
+    let class64 = data.get(4) == Some(&ELFCLASS64);
+    let big_endian = data.get(5) == Some(&ELFDATA2MSB);
+    let field = |offset: usize, size: usize| get(data, offset, size,
+                                                 big_endian);
+    // Offsets of e_machine, e_phoff, e_phentsize, e_phnum and of p_type,
+    // p_offset, p_filesz in program header.
+    let (phoff, phentsize, phnum, p_offset, p_filesz) = match class64 {
+        true => ((0x20, 8), 0x36, 0x38, (0x08, 8), (0x20, 8)),
+        false => ((0x1c, 4), 0x2a, 0x2c, (0x04, 4), (0x10, 4)),
+    };
+
+    let header = || truncated("ELF header");
+    let machine = try!(field(0x12, 2).ok_or_else(&header));
+    let phoff = try!(field(phoff.0, phoff.1).ok_or_else(&header)) as usize;
+    let phentsize = try!(field(phentsize, 2).ok_or_else(&header)) as usize;
+    let phnum = try!(field(phnum, 2).ok_or_else(&header)) as usize;
+
+    for i in 0..phnum {
+        let header = try!(i.checked_mul(phentsize)
+            .and_then(|header| header.checked_add(phoff))
+            .ok_or_else(|| truncated("ELF program header")));
+        let get_header = |offset: usize, size: usize| {
+            header.checked_add(offset)
+                .and_then(|offset| field(offset, size))
+                .ok_or_else(|| truncated("ELF program header"))
+        };
+        if try!(get_header(0, 4)) != PT_LOAD {
+            continue;
+        }
+        let offset = try!(get_header(p_offset.0, p_offset.1));
+        let filesz = try!(get_header(p_filesz.0, p_filesz.1));
+        if offset.saturating_add(filesz) > data.len() as u64 {
+            return Err(truncated("ELF segment"));
+        }
+    }
+
+    Ok(KernelInfo{
+        format: KernelFormat::Elf,
+        arch: elf_arch(machine, class64),
+        version: find_banner(data),
+    })
+}
+
+fn pe_arch(machine: u64) -> Option<&'static str> {
+    match machine {
+        0x014c => Some("x86"),
+        0x8664 => Some("x86_64"),
+        0x01c2 | 0x01c4 => Some("arm"),
+        0xaa64 => Some("aarch64"),
+        0x5064 => Some("riscv64"),
+        _ => None,
+    }
+}
+
+// Sections as (name, data).
+fn pe_sections(data: &[u8], pe: usize)
+        -> Result<Vec<(String, &[u8])>, InspectError> {
+    let header = || truncated("PE header");
+    let count = try!(get_le(data, pe + 6, 2).ok_or_else(&header)) as usize;
+    let optional_size = try!(get_le(data, pe + 20, 2).ok_or_else(&header));
+    let table = pe.saturating_add(24 + optional_size as usize);
+
+    let mut sections = Vec::new();
+    for i in 0..count {
+        let section = table.saturating_add(i * PE_SECTION_SIZE);
+        let name = match data.get(section..section.saturating_add(8)) {
+            Some(name) => c_string(name),
+            None => return Err(truncated("PE section table")),
+        };
+        let size = try!(get_le(data, section + 16, 4)
+            .ok_or_else(|| truncated("PE section table"))) as usize;
+        let offset = try!(get_le(data, section + 20, 4)
+            .ok_or_else(|| truncated("PE section table"))) as usize;
+        match offset.checked_add(size).and_then(|end| data.get(offset..end)) {
+            Some(section_data) => sections.push((name, section_data)),
+            None => return Err(InspectError::new(format!(
+                "PE section \"{}\" is truncated", name))),
+        }
+    }
+    Ok(sections)
+}
+
+fn inspect_pe(data: &[u8]) -> Result<Option<KernelInfo>, InspectError> {
+    let pe = match get_le(data, PE_OFFSET, 4) {
+        Some(pe) => pe as usize,
+        None => return Ok(None),
+    };
+    if data.get(pe..pe.saturating_add(4)) != Some(PE_MAGIC) {
+        return Ok(None);
+    }
+    let machine = try!(get_le(data, pe + 4, 2)
+        .ok_or_else(|| truncated("PE header")));
+    let sections = try!(pe_sections(data, pe));
+
+    let section = |name: &str| sections.iter()
+        .find(|&&(ref section, _)| section == name)
+        .map(|&(_, data)| data);
+    let (format, version) = match section(UKI_LINUX_SECTION) {
+        Some(linux) => {
+            let linux = try!(inspect(linux));
+            let version = section(UKI_UNAME_SECTION).map(c_string)
+                .or(linux.version);
+            (KernelFormat::Uki, version)
+        },
+        None => (KernelFormat::Pe, find_banner(data)),
+    };
+    Ok(Some(KernelInfo{
+        format: format,
+        arch: pe_arch(machine),
+        version: version,
+    }))
+}
+
+/// Identify kernel image.
+pub fn inspect(data: &[u8]) -> Result<KernelInfo, InspectError> {
+    if data.len() < IMAGE_HEADER_SIZE {
+        return Err(InspectError::new(format!(
+            "image of {} bytes is too short", data.len())));
+    }
+
+    let magic = &data[IMAGE_MAGIC_OFFSET..IMAGE_MAGIC_OFFSET + 4];
+    if data.get(BZ_HEADER..BZ_HEADER + 4) == Some(BZ_MAGIC) {
+        inspect_bzimage(data)
+    } else if magic == ARM64_MAGIC || magic == RISCV_MAGIC {
+        Ok(KernelInfo{
+            format: match magic == ARM64_MAGIC {
+                true => KernelFormat::Arm64Image,
+                false => KernelFormat::RiscvImage,
+            },
+            arch: match magic == ARM64_MAGIC {
+                true => Some("aarch64"),
+                false => Some("riscv64"),
+            },
+            version: find_banner(data),
+        })
+    } else if data.starts_with(ELF_MAGIC) {
+        inspect_elf(data)
+    } else if data.starts_with(PE_MZ_MAGIC) {
+        match try!(inspect_pe(data)) {
+            Some(info) => Ok(info),
+            None => Err(truncated("PE image")),
+        }
+    } else {
+        Ok(KernelInfo{
+            format: KernelFormat::Unknown,
+            arch: None,
+            version: find_banner(data),
+        })
+    }
+}
+
+/// Identify kernel image in file, reading it from the start.
+pub fn inspect_file(file: &std::fs::File) -> Result<KernelInfo, InspectError> {
+    use std::io::{Read, Seek};
+
+    let mut file = file;
+    let mut data = Vec::new();
+    try!(file.seek(std::io::SeekFrom::Start(0)));
+    try!(file.read_to_end(&mut data));
+    try!(file.seek(std::io::SeekFrom::Start(0)));
+    inspect(&data)
+}
+
+/// Identify kernel image as it is loaded.
+pub fn inspect_kernel(kernel: &decompress::Kernel)
+        -> Result<KernelInfo, InspectError> {
+    inspect_file(kernel.file())
+}
+
+/// Identify kernel image in file, decompressing it if needed.
+pub fn inspect_path(path: &std::path::Path)
+        -> Result<KernelInfo, InspectError> {
+    use std::io::Read;
+
+    let mut data = Vec::new();
+    try!(try!(std::fs::File::open(path)).read_to_end(&mut data));
+    match decompress::Kernel::load(path, &data) {
+        Ok(kernel) => inspect_kernel(&kernel),
+        Err(_) => Err(InspectError::new(String::from("unable to decompress"))),
+    }
+}
+
+#[test]
+fn identifies_kernels() {
+    let mut bzimage = vec![0u8; 5 * 512 + 0x100];
+    bzimage[BZ_SETUP_SECTS] = 4;
+    bzimage[BZ_SYSSIZE] = 0x10;
+    bzimage[BZ_HEADER..BZ_HEADER + 4].copy_from_slice(BZ_MAGIC);
+    bzimage[BZ_VERSION..BZ_VERSION + 2].copy_from_slice(&[0x0f, 0x02]);
+    bzimage[BZ_KERNEL_VERSION] = 0x80;
+    bzimage[0x280..0x290].copy_from_slice(b"6.1.0-13 (b@h) #");
+    bzimage[BZ_XLOADFLAGS] = 0x7f;
+    bzimage[BZ_CMDLINE_SIZE..BZ_CMDLINE_SIZE + 2]
+        .copy_from_slice(&[0xff, 0x07]);
+    let info = inspect(&bzimage).unwrap();
+    assert_eq!(info.format, KernelFormat::BzImage{
+        protocol: 0x020f,
+        xloadflags: 0x7f,
+        cmdline_size: 2047,
+    });
+    assert_eq!(info.arch, Some("x86_64"));
+    assert_eq!(info.version, Some(String::from("6.1.0-13 (b@h) #")));
+    assert!(inspect(&bzimage[..5 * 512 + 0x80]).is_err());
+
+    let mut image = vec![0u8; 0x100];
+    image[IMAGE_MAGIC_OFFSET..IMAGE_MAGIC_OFFSET + 4]
+        .copy_from_slice(ARM64_MAGIC);
+    image[0x80..0x98].copy_from_slice(b"Linux version 6.6.0 (x)\n");
+    let info = inspect(&image).unwrap();
+    assert_eq!(info.format, KernelFormat::Arm64Image);
+    assert_eq!(info.version, Some(String::from("6.6.0 (x)")));
+    let wrong = match normalize_arch("aarch64") == machine().unwrap() {
+        true => KernelInfo{arch: Some("x86_64"), ..info},
+        false => info,
+    };
+    assert!(wrong.check(None, kexec::Backend::Native).is_err());
+
+    let uki = KernelInfo{
+        format: KernelFormat::Uki,
+        arch: None,
+        version: None,
+    };
+    assert!(uki.check(None, kexec::Backend::Tool).is_err());
+
+    assert!(compatible("x86", "x86_64", kexec::Backend::Tool));
+    assert!(!compatible("x86", "x86_64", kexec::Backend::Native));
+    assert!(!compatible("x86_64", "x86", kexec::Backend::Tool));
+    assert!(compatible("aarch64", "aarch64", kexec::Backend::Legacy));
+
+    // Program header offset overflowing address space.
+    let mut elf = vec![0u8; 0x40];
+    elf[..4].copy_from_slice(ELF_MAGIC);
+    elf[4] = ELFCLASS64;
+    elf[0x20..0x28].copy_from_slice(&[0xff; 8]);
+    elf[0x36] = 0x38;
+    elf[0x38] = 2;
+    assert!(inspect(&elf).is_err());
+}
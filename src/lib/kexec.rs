@@ -0,0 +1,230 @@
+// Loading and executing kernels with kexec system calls, without kexec
+// tool. Images for kexec_load(2) are prepared by kexec_legacy.
+
+use std;
+
+extern crate libc;
+
+// Flags of kexec_file_load(2).
+const KEXEC_FILE_UNLOAD: libc::c_ulong = 0x00000001;
+const KEXEC_FILE_NO_INITRAMFS: libc::c_ulong = 0x00000004;
+// Flags of kexec_load(2).
+const KEXEC_ARCH_DEFAULT: libc::c_ulong = 0;
+
+// struct kexec_segment of kexec_load(2).
+#[repr(C)]
+struct RawSegment {
+    buf: *const libc::c_void,
+    bufsz: libc::size_t,
+    mem: libc::c_ulong,
+    memsz: libc::size_t,
+}
+
+/// Memory to be copied to physical address `mem` on kexec, remaining
+/// `memsz` is zeroed. `mem` and `memsz` must be page aligned.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub data: Vec<u8>,
+    pub mem: u64,
+    pub memsz: u64,
+}
+
+/// How kernels are loaded and executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// kexec_file_load(2) and reboot(2), falls back to `Legacy` if the
+    /// running kernel does not support kexec_file_load(2).
+    Native,
+    /// kexec_load(2) and reboot(2), kernel image is parsed in process.
+    Legacy,
+    /// External kexec tool.
+    Tool,
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        Backend::Native
+    }
+}
+
+#[derive(Debug)]
+pub enum KexecError {
+    /// Missing CAP_SYS_BOOT or kexec disabled by kexec_load_disabled sysctl.
+    PermissionDenied,
+    /// Kernel image format not supported by the running kernel.
+    InvalidImage,
+    /// Kernel signature verification failed, e.g. in lockdown mode.
+    KeyRejected,
+    /// Crash kernel is loaded or another load is in progress.
+    Busy,
+    /// System call is not supported by the running kernel.
+    NotSupported,
+    Io(std::io::Error),
+}
+
+impl KexecError {
+    fn from_os_error(err: std::io::Error) -> KexecError {
+        match err.raw_os_error() {
+            Some(libc::EPERM) => KexecError::PermissionDenied,
+            Some(libc::ENOEXEC) => KexecError::InvalidImage,
+            Some(libc::EKEYREJECTED) => KexecError::KeyRejected,
+            Some(libc::EBUSY) => KexecError::Busy,
+            Some(libc::ENOSYS) => KexecError::NotSupported,
+            _ => KexecError::Io(err),
+        }
+    }
+}
+
+impl std::fmt::Display for KexecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            KexecError::PermissionDenied => write!(
+                f, "permission denied (CAP_SYS_BOOT is required, kexec may \
+                    be disabled by kernel.kexec_load_disabled)"),
+            KexecError::InvalidImage => write!(
+                f, "kernel image is not supported by the running kernel"),
+            KexecError::KeyRejected => write!(
+                f, "kernel signature was rejected"),
+            KexecError::Busy => write!(
+                f, "kexec is busy (crash kernel loaded or load in progress)"),
+            KexecError::NotSupported => write!(
+                f, "system call is not supported by the running kernel"),
+            KexecError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::convert::From<std::io::Error> for KexecError {
+    fn from(err: std::io::Error) -> KexecError { KexecError::Io(err) }
+}
+
+/// Load kernel with kexec_file_load(2).
+pub fn file_load(kernel: &std::fs::File, initrd: Option<&std::fs::File>,
+                 cmdline: Option<&str>)
+        -> Result<(), KexecError> {
+    use std::os::unix::io::AsRawFd;
+
+    let cmdline = match cmdline {
+        Some(cmdline) => Some(try!(std::ffi::CString::new(cmdline)
+            .map_err(|err| KexecError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, err))))),
+        None => None,
+    };
+    let (cmdline_len, cmdline_ptr) = match cmdline {
+        Some(ref cmdline) => (cmdline.as_bytes_with_nul().len(),
+                              cmdline.as_ptr()),
+        None => (0, std::ptr::null()),
+    };
+    let (initrd_fd, flags) = match initrd {
+        Some(initrd) => (initrd.as_raw_fd(), 0),
+        None => (-1, KEXEC_FILE_NO_INITRAMFS),
+    };
+
+    let result = unsafe {
+        libc::syscall(libc::SYS_kexec_file_load, kernel.as_raw_fd(),
+                      initrd_fd, cmdline_len as libc::c_ulong, cmdline_ptr,
+                      flags)
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(KexecError::from_os_error(std::io::Error::last_os_error())),
+    }
+}
+
+/// Unload kernel loaded by `file_load()`.
+pub fn file_unload() -> Result<(), KexecError> {
+    let result = unsafe {
+        libc::syscall(libc::SYS_kexec_file_load, -1, -1, 0 as libc::c_ulong,
+                      std::ptr::null::<libc::c_char>(), KEXEC_FILE_UNLOAD)
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(KexecError::from_os_error(std::io::Error::last_os_error())),
+    }
+}
+
+/// Load segments with kexec_load(2).
+pub fn load(entry: u64, segments: &[Segment]) -> Result<(), KexecError> {
+    let raw: Vec<RawSegment> = segments.iter()
+        .map(|segment| RawSegment{
+            buf: segment.data.as_ptr() as *const libc::c_void,
+            bufsz: segment.data.len(),
+            mem: segment.mem as libc::c_ulong,
+            memsz: segment.memsz as libc::size_t,
+        })
+        .collect();
+
+    let result = unsafe {
+        libc::syscall(libc::SYS_kexec_load, entry as libc::c_ulong,
+                      raw.len() as libc::c_ulong, raw.as_ptr(),
+                      KEXEC_ARCH_DEFAULT)
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(KexecError::from_os_error(std::io::Error::last_os_error())),
+    }
+}
+
+/// Unload kernel loaded by `load()`.
+pub fn unload() -> Result<(), KexecError> {
+    load(0, &[])
+}
+
+/// `file_load()` call for the given files, as shown by dry run.
+pub fn describe_file_load(kernel: &str, initrd: Option<&str>,
+                          cmdline: Option<&str>) -> String {
+    let cmdline_len = cmdline.map(|cmdline| cmdline.len() + 1).unwrap_or(0);
+    format!("kexec_file_load(kernel_fd={:?}, initrd_fd={}, cmdline_len={}, \
+             cmdline={}, flags={})",
+            kernel,
+            initrd.map(|initrd| format!("{:?}", initrd))
+                .unwrap_or_else(|| String::from("-1")),
+            cmdline_len,
+            cmdline.map(|cmdline| format!("{:?}", cmdline))
+                .unwrap_or_else(|| String::from("NULL")),
+            match initrd {
+                Some(_) => "0",
+                None => "KEXEC_FILE_NO_INITRAMFS",
+            })
+}
+
+/// `load()` call, as shown by dry run.
+pub fn describe_load(entry: u64, segments: &[Segment]) -> String {
+    let segments: Vec<String> = segments.iter()
+        .map(|segment| format!("{{bufsz={:#x}, mem={:#x}, memsz={:#x}}}",
+                               segment.data.len(), segment.mem,
+                               segment.memsz))
+        .collect();
+    format!("kexec_load(entry={:#x}, nr_segments={}, segments=[{}], \
+             flags=KEXEC_ARCH_DEFAULT)",
+            entry, segments.len(), segments.join(", "))
+}
+
+/// `exec()` call, as shown by dry run.
+pub fn describe_exec() -> String {
+    String::from("reboot(LINUX_REBOOT_CMD_KEXEC)")
+}
+
+/// Execute loaded kernel. Returns only on failure.
+pub fn exec() -> Result<(), KexecError> {
+    // Like reboot(8), reboot(2) itself does not flush filesystem buffers.
+    unsafe {
+        libc::sync();
+        libc::reboot(libc::LINUX_REBOOT_CMD_KEXEC);
+    }
+    Err(KexecError::from_os_error(std::io::Error::last_os_error()))
+}
+
+#[test]
+fn maps_errno() {
+    let error = |errno| KexecError::from_os_error(
+        std::io::Error::from_raw_os_error(errno));
+    match error(libc::EKEYREJECTED) {
+        KexecError::KeyRejected => (),
+        err => panic!("Unexpected {:?}", err),
+    }
+    match error(libc::ENOENT) {
+        KexecError::Io(_) => (),
+        err => panic!("Unexpected {:?}", err),
+    }
+}
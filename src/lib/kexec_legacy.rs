@@ -0,0 +1,612 @@
+// Loading with kexec_load(2), for kernels built without kexec_file_load(2)
+// support. Unlike the file based system call it leaves parsing of the image
+// to userspace: x86_64 bzImage (boot protocol 2.06 or newer) and ELF vmlinux
+// are placed in physical memory here, together with boot_params, command
+// line and a small trampoline which enters the kernel like the 64-bit boot
+// protocol requires. EFI runtime services are not passed to the new kernel.
+
+use std;
+
+use kexec;
+
+const PATH_MEMMAP: &'static str = "/sys/firmware/memmap";
+const PATH_EFI_SYSTAB: &'static str = "/sys/firmware/efi/systab";
+
+const PAGE_SIZE: u64 = 4096;
+// Real mode memory is left alone.
+const LOW_MEMORY_END: u64 = 0x100000;
+// Limit for boot_params, command line and trampoline.
+const LOW_4G: u64 = 0x100000000;
+
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+// Offsets in boot_params, see Documentation/x86/zero-page.rst.
+const BP_ACPI_RSDP_ADDR: usize = 0x070;
+const BP_EXT_RAMDISK_IMAGE: usize = 0x0c0;
+const BP_EXT_RAMDISK_SIZE: usize = 0x0c4;
+const BP_EXT_CMD_LINE_PTR: usize = 0x0c8;
+const BP_E820_ENTRIES: usize = 0x1e8;
+const BP_E820_TABLE: usize = 0x2d0;
+const BP_E820_MAX_ENTRIES: usize = 128;
+
+// Offsets of setup header fields, same in bzImage and boot_params, see
+// Documentation/x86/boot.rst.
+const HDR_SETUP_SECTS: usize = 0x1f1;
+const HDR_BOOT_FLAG: usize = 0x1fe;
+// Byte after the jump opcode is the length of the rest of the header.
+const HDR_JUMP: usize = 0x200;
+const HDR_HEADER: usize = 0x202;
+const HDR_VERSION: usize = 0x206;
+const HDR_TYPE_OF_LOADER: usize = 0x210;
+const HDR_LOADFLAGS: usize = 0x211;
+const HDR_CODE32_START: usize = 0x214;
+const HDR_RAMDISK_IMAGE: usize = 0x218;
+const HDR_RAMDISK_SIZE: usize = 0x21c;
+const HDR_CMD_LINE_PTR: usize = 0x228;
+const HDR_INITRD_ADDR_MAX: usize = 0x22c;
+const HDR_KERNEL_ALIGNMENT: usize = 0x230;
+const HDR_RELOCATABLE_KERNEL: usize = 0x234;
+const HDR_XLOADFLAGS: usize = 0x236;
+const HDR_CMDLINE_SIZE: usize = 0x238;
+const HDR_PREF_ADDRESS: usize = 0x258;
+const HDR_INIT_SIZE: usize = 0x260;
+
+const HDR_MAGIC: &'static [u8] = b"HdrS";
+const BOOT_FLAG: u64 = 0xaa55;
+const MIN_VERSION: u64 = 0x0206;
+const LOADED_HIGH: u8 = 0x01;
+const XLF_KERNEL_64: u64 = 0x0001;
+const LOADER_UNDEFINED: u8 = 0xff;
+const DEFAULT_LOAD_ADDRESS: u64 = 0x100000;
+// 64-bit entry point from the start of protected mode kernel.
+const ENTRY_64_OFFSET: u64 = 0x200;
+
+// Header values for ELF kernels, which have no setup header.
+const ELF_VERSION: u64 = 0x020c;
+const ELF_CMDLINE_SIZE: u64 = 2047;
+const ELF_INITRD_ADDR_MAX: u64 = 0x7fffffff;
+
+const ELF_MAGIC: &'static [u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u64 = 62;
+const PT_LOAD: u64 = 1;
+const ELF_HEADER_SIZE: usize = 0x40;
+
+// Loads flat GDT and stack, sets %rsi to boot_params and jumps to the kernel:
+//     cli
+//     lgdt gdt_desc(%rip)
+//     mov $0x18, %eax; mov %eax, %ds, %es, %ss, %fs, %gs
+//     movabs $STACK, %rsp
+//     lea 1f(%rip), %rax; pushq $0x10; push %rax; lretq
+// 1:  movabs $BOOT_PARAMS, %rsi
+//     movabs $ENTRY, %rax
+//     jmp *%rax
+// gdt: null, null, 0x10 64-bit code, 0x18 data
+// gdt_desc: .word 31, .quad $GDT
+const TRAMPOLINE: [u8; 114] = [
+    0xfa, 0x0f, 0x01, 0x15, 0x60, 0x00, 0x00, 0x00, 0xb8, 0x18, 0x00, 0x00,
+    0x00, 0x8e, 0xd8, 0x8e, 0xc0, 0x8e, 0xd0, 0x8e, 0xe0, 0x8e, 0xe8, 0x48,
+    0xbc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x05,
+    0x05, 0x00, 0x00, 0x00, 0x6a, 0x10, 0x50, 0x48, 0xcb, 0x48, 0xbe, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x48, 0xb8, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xe0, 0x0f, 0x1f, 0x44, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x9a, 0xaf, 0x00,
+    0xff, 0xff, 0x00, 0x00, 0x00, 0x92, 0xcf, 0x00, 0x1f, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const TRAMPOLINE_STACK: usize = 0x19;
+const TRAMPOLINE_BOOT_PARAMS: usize = 0x2f;
+const TRAMPOLINE_ENTRY: usize = 0x39;
+const TRAMPOLINE_GDT: u64 = 0x48;
+const TRAMPOLINE_GDT_BASE: usize = 0x6a;
+
+/// Range of /sys/firmware/memmap, end is exclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub start: u64,
+    pub end: u64,
+    pub kind: u32,
+}
+
+// Kernel placed in memory, before boot_params are completed.
+struct Image {
+    segments: Vec<kexec::Segment>,
+    entry: u64,
+    params: Vec<u8>,
+    cmdline_size: u64,
+    initrd_addr_max: u64,
+}
+
+// Physical memory assignment, top-down in System RAM.
+struct Layout {
+    ram: Vec<(u64, u64)>,
+    used: Vec<(u64, u64)>,
+}
+
+impl Layout {
+    fn new(memmap: &[MemoryRange]) -> Layout {
+        Layout{
+            ram: memmap.iter()
+                .filter(|range| range.kind == E820_RAM)
+                .map(|range| (std::cmp::max(range.start, LOW_MEMORY_END),
+                              range.end))
+                .filter(|&(start, end)| start < end)
+                .collect(),
+            used: Vec::new(),
+        }
+    }
+
+    fn is_free(&self, start: u64, end: u64) -> bool {
+        self.ram.iter().any(|&(s, e)| s <= start && end <= e) &&
+            !self.used.iter().any(|&(s, e)| start < e && s < end)
+    }
+
+    fn reserve_at(&mut self, start: u64, size: u64) -> bool {
+        match start.checked_add(size) {
+            Some(end) if self.is_free(start, end) => {
+                self.used.push((start, end));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Highest free address, the range ends below `limit`.
+    fn reserve(&mut self, size: u64, align: u64, limit: u64) -> Option<u64> {
+        let mut best = None;
+        for &(start, end) in &self.ram {
+            // Highest placement ends at the end of RAM or at a used range.
+            let ceilings = std::iter::once(end)
+                .chain(self.used.iter().map(|&(s, _)| s));
+            for ceiling in ceilings {
+                let ceiling = std::cmp::min(ceiling, limit);
+                if ceiling < size {
+                    continue;
+                }
+                let candidate = (ceiling - size) / align * align;
+                if candidate >= start &&
+                        self.is_free(candidate, candidate + size) &&
+                        Some(candidate) > best {
+                    best = Some(candidate);
+                }
+            }
+        }
+        if let Some(address) = best {
+            self.used.push((address, address + size));
+        }
+        best
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+// Little endian integer of `size` bytes.
+fn get_le(data: &[u8], offset: usize, size: usize) -> u64 {
+    data[offset..offset + size].iter().rev()
+        .fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+fn put_le(data: &mut [u8], offset: usize, size: usize, value: u64) {
+    for (i, byte) in data[offset..offset + size].iter_mut().enumerate() {
+        *byte = (value >> (i * 8)) as u8;
+    }
+}
+
+fn invalid_image(reason: &str) -> kexec::KexecError {
+    error!("Unable to load kernel image: {}", reason);
+    kexec::KexecError::InvalidImage
+}
+
+fn no_memory(what: &str) -> kexec::KexecError {
+    error!("No free memory for {}", what);
+    kexec::KexecError::Io(std::io::Error::new(std::io::ErrorKind::Other,
+                                              "no free memory"))
+}
+
+fn read_file(file: &std::fs::File) -> std::io::Result<Vec<u8>> {
+    use std::io::{Read, Seek};
+
+    let mut file = file;
+    try!(file.seek(std::io::SeekFrom::Start(0)));
+    let mut data = Vec::new();
+    try!(file.read_to_end(&mut data));
+    Ok(data)
+}
+
+fn e820_type(name: &str) -> u32 {
+    match name {
+        "System RAM" => E820_RAM,
+        "ACPI Tables" => 3,
+        "ACPI Non-volatile Storage" => 4,
+        "Unusable memory" => 5,
+        "Persistent Memory" => 7,
+        "Persistent Memory (legacy)" => 12,
+        "Soft Reserved" => 0xefffffff,
+        _ => E820_RESERVED,
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.trim().trim_left_matches("0x"), 16).ok()
+}
+
+/// Firmware memory map, as passed to the running kernel.
+pub fn read_memmap() -> Result<Vec<MemoryRange>, kexec::KexecError> {
+    use std::io::Read;
+
+    let mut ranges = Vec::new();
+    for dir in try!(std::fs::read_dir(PATH_MEMMAP)) {
+        let path = try!(dir).path();
+        let mut values = Vec::new();
+        for name in &["start", "end", "type"] {
+            let mut value = String::new();
+            try!(try!(std::fs::File::open(path.join(name)))
+                 .read_to_string(&mut value));
+            values.push(String::from(value.trim()));
+        }
+        match (parse_hex(&values[0]), parse_hex(&values[1])) {
+            (Some(start), Some(end)) => ranges.push(MemoryRange{
+                start: start,
+                end: end + 1,
+                kind: e820_type(&values[2]),
+            }),
+            _ => warn!("Invalid memory map entry {:?}", path),
+        }
+    }
+    ranges.sort_by_key(|range| range.start);
+    Ok(ranges)
+}
+
+// ACPI RSDP of EFI systems, where it can not be found by scanning memory.
+fn efi_rsdp() -> Option<u64> {
+    use std::io::Read;
+
+    let mut systab = String::new();
+    if let Err(_) = std::fs::File::open(PATH_EFI_SYSTAB)
+            .and_then(|mut file| file.read_to_string(&mut systab)) {
+        return None;
+    }
+    let find = |key: &str| systab.lines()
+        .filter(|line| line.starts_with(key))
+        .filter_map(|line| parse_hex(&line[key.len()..]))
+        .next();
+    find("ACPI20=").or_else(|| find("ACPI="))
+}
+
+fn is_bzimage(data: &[u8]) -> bool {
+    data.len() > HDR_INIT_SIZE + 4 &&
+        get_le(data, HDR_BOOT_FLAG, 2) == BOOT_FLAG &&
+        &data[HDR_HEADER..HDR_HEADER + 4] == HDR_MAGIC
+}
+
+fn bzimage(data: &[u8], layout: &mut Layout)
+        -> Result<Image, kexec::KexecError> {
+    let version = get_le(data, HDR_VERSION, 2);
+    if version < MIN_VERSION {
+        return Err(invalid_image(&format!(
+            "boot protocol {}.{:02} is too old, 2.06 is required",
+            version >> 8, version & 0xff)));
+    }
+    if data[HDR_LOADFLAGS] & LOADED_HIGH == 0 {
+        return Err(invalid_image("zImage is not supported"));
+    }
+    if version >= 0x020c &&
+            get_le(data, HDR_XLOADFLAGS, 2) & XLF_KERNEL_64 == 0 {
+        return Err(invalid_image("kernel has no 64-bit entry point"));
+    }
+
+    let setup_sects = match data[HDR_SETUP_SECTS] {
+        0 => 4,
+        sects => sects as usize,
+    };
+    let setup_size = (setup_sects + 1) * 512;
+    if data.len() <= setup_size {
+        return Err(invalid_image("bzImage is truncated"));
+    }
+    let kernel = &data[setup_size..];
+
+    let (pref_address, init_size) = match version >= 0x020a {
+        true => (get_le(data, HDR_PREF_ADDRESS, 8),
+                 get_le(data, HDR_INIT_SIZE, 4)),
+        false => (DEFAULT_LOAD_ADDRESS, 0),
+    };
+    // Kernel decompresses itself in place, up to init_size.
+    let memsz = align_up(std::cmp::max(kernel.len() as u64, init_size),
+                         PAGE_SIZE);
+    let address = match layout.reserve_at(pref_address, memsz) {
+        true => pref_address,
+        false if data[HDR_RELOCATABLE_KERNEL] != 0 => {
+            let align = std::cmp::max(get_le(data, HDR_KERNEL_ALIGNMENT, 4),
+                                      PAGE_SIZE);
+            try!(layout.reserve(memsz, align, LOW_4G)
+                 .ok_or_else(|| no_memory("kernel")))
+        },
+        false => return Err(invalid_image(&format!(
+            "kernel can not be loaded at {:#x}", pref_address))),
+    };
+    debug!("bzImage (boot protocol {}.{:02}) at {:#x}",
+           version >> 8, version & 0xff, address);
+
+    let mut params = vec![0u8; PAGE_SIZE as usize];
+    let header_end = std::cmp::min(HDR_HEADER + data[HDR_JUMP + 1] as usize,
+                                   setup_size);
+    params[HDR_SETUP_SECTS..header_end]
+        .copy_from_slice(&data[HDR_SETUP_SECTS..header_end]);
+    put_le(&mut params, HDR_CODE32_START, 4, address);
+
+    Ok(Image{
+        segments: vec![kexec::Segment{
+            data: kernel.to_vec(),
+            mem: address,
+            memsz: memsz,
+        }],
+        entry: address + ENTRY_64_OFFSET,
+        params: params,
+        cmdline_size: get_le(data, HDR_CMDLINE_SIZE, 4),
+        initrd_addr_max: get_le(data, HDR_INITRD_ADDR_MAX, 4),
+    })
+}
+
+fn elf(data: &[u8], layout: &mut Layout) -> Result<Image, kexec::KexecError> {
+    if data.len() < ELF_HEADER_SIZE || data[4] != ELFCLASS64 ||
+            data[5] != ELFDATA2LSB {
+        return Err(invalid_image("only 64-bit little endian ELF is supported"));
+    }
+    let machine = get_le(data, 0x12, 2);
+    if machine != EM_X86_64 {
+        return Err(invalid_image(&format!("ELF machine {} is not x86_64",
+                                          machine)));
+    }
+    let phoff = get_le(data, 0x20, 8) as usize;
+    let phentsize = get_le(data, 0x36, 2) as usize;
+    let phnum = get_le(data, 0x38, 2) as usize;
+    if phentsize < 0x38 || phoff.checked_add(phentsize * phnum)
+            .map(|end| end > data.len()).unwrap_or(true) {
+        return Err(invalid_image("ELF program headers are truncated"));
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let header = &data[phoff + i * phentsize..];
+        if get_le(header, 0, 4) != PT_LOAD {
+            continue;
+        }
+        let offset = get_le(header, 0x08, 8) as usize;
+        let paddr = get_le(header, 0x18, 8);
+        let filesz = get_le(header, 0x20, 8) as usize;
+        let memsz = get_le(header, 0x28, 8);
+
+        let bytes = match offset.checked_add(filesz)
+                .and_then(|end| data.get(offset..end)) {
+            Some(bytes) => bytes,
+            None => return Err(invalid_image("ELF segment is truncated")),
+        };
+        if memsz < filesz as u64 {
+            return Err(invalid_image("ELF segment is larger than its memory"));
+        }
+        let mem = paddr / PAGE_SIZE * PAGE_SIZE;
+        let padding = (paddr - mem) as usize;
+        let memsz = match (padding as u64).checked_add(memsz) {
+            Some(size) if size <= std::u64::MAX - PAGE_SIZE => {
+                align_up(size, PAGE_SIZE)
+            },
+            _ => return Err(invalid_image("ELF segment is too large")),
+        };
+        if !layout.reserve_at(mem, memsz) {
+            return Err(invalid_image(&format!(
+                "ELF segment at {:#x} is not in free RAM", paddr)));
+        }
+
+        let mut segment = vec![0u8; padding];
+        segment.extend_from_slice(bytes);
+        segments.push(kexec::Segment{
+            data: segment,
+            mem: mem,
+            memsz: memsz,
+        });
+    }
+    if segments.is_empty() {
+        return Err(invalid_image("ELF has no loadable segments"));
+    }
+
+    let mut params = vec![0u8; PAGE_SIZE as usize];
+    put_le(&mut params, HDR_BOOT_FLAG, 2, BOOT_FLAG);
+    params[HDR_HEADER..HDR_HEADER + 4].copy_from_slice(HDR_MAGIC);
+    put_le(&mut params, HDR_VERSION, 2, ELF_VERSION);
+    params[HDR_LOADFLAGS] = LOADED_HIGH;
+
+    Ok(Image{
+        segments: segments,
+        entry: get_le(data, 0x18, 8),
+        params: params,
+        cmdline_size: ELF_CMDLINE_SIZE,
+        initrd_addr_max: ELF_INITRD_ADDR_MAX,
+    })
+}
+
+// Complete boot_params and add initrd, boot_params with command line and
+// trampoline. Returns entry point and segments.
+fn finish(image: Image, layout: &mut Layout, memmap: &[MemoryRange],
+          initrd: Option<Vec<u8>>, cmdline: Option<&str>, rsdp: Option<u64>)
+        -> Result<(u64, Vec<kexec::Segment>), kexec::KexecError> {
+    let Image{mut segments, entry, mut params, cmdline_size,
+              initrd_addr_max} = image;
+
+    let cmdline = cmdline.unwrap_or("");
+    if cmdline.len() as u64 > cmdline_size {
+        error!("Command line is longer than {} bytes supported by kernel",
+               cmdline_size);
+        return Err(kexec::KexecError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput, "command line too long")));
+    }
+
+    if let Some(initrd) = initrd {
+        let size = initrd.len() as u64;
+        let memsz = align_up(size, PAGE_SIZE);
+        let address = try!(layout.reserve(memsz, PAGE_SIZE, initrd_addr_max + 1)
+            .ok_or_else(|| no_memory("initrd")));
+        put_le(&mut params, HDR_RAMDISK_IMAGE, 4, address);
+        put_le(&mut params, BP_EXT_RAMDISK_IMAGE, 4, address >> 32);
+        put_le(&mut params, HDR_RAMDISK_SIZE, 4, size);
+        put_le(&mut params, BP_EXT_RAMDISK_SIZE, 4, size >> 32);
+        segments.push(kexec::Segment{
+            data: initrd,
+            mem: address,
+            memsz: memsz,
+        });
+    }
+
+    // boot_params page, command line pages and trampoline page with stack.
+    let cmdline_pages = align_up(cmdline.len() as u64 + 1, PAGE_SIZE);
+    let size = PAGE_SIZE + cmdline_pages + PAGE_SIZE;
+    let base = try!(layout.reserve(size, PAGE_SIZE, LOW_4G)
+        .ok_or_else(|| no_memory("boot parameters")));
+    let cmdline_address = base + PAGE_SIZE;
+    let trampoline_address = cmdline_address + cmdline_pages;
+
+    params[HDR_TYPE_OF_LOADER] = LOADER_UNDEFINED;
+    put_le(&mut params, HDR_CMD_LINE_PTR, 4, cmdline_address);
+    put_le(&mut params, BP_EXT_CMD_LINE_PTR, 4, cmdline_address >> 32);
+    if memmap.len() > BP_E820_MAX_ENTRIES {
+        warn!("Only {} of {} memory map entries are passed",
+              BP_E820_MAX_ENTRIES, memmap.len());
+    }
+    let ranges = &memmap[..std::cmp::min(memmap.len(), BP_E820_MAX_ENTRIES)];
+    params[BP_E820_ENTRIES] = ranges.len() as u8;
+    for (i, range) in ranges.iter().enumerate() {
+        let offset = BP_E820_TABLE + i * 20;
+        put_le(&mut params, offset, 8, range.start);
+        put_le(&mut params, offset + 8, 8, range.end - range.start);
+        put_le(&mut params, offset + 16, 4, range.kind as u64);
+    }
+    if let Some(rsdp) = rsdp {
+        put_le(&mut params, BP_ACPI_RSDP_ADDR, 8, rsdp);
+    }
+
+    let mut trampoline = TRAMPOLINE.to_vec();
+    put_le(&mut trampoline, TRAMPOLINE_STACK, 8,
+           trampoline_address + PAGE_SIZE);
+    put_le(&mut trampoline, TRAMPOLINE_BOOT_PARAMS, 8, base);
+    put_le(&mut trampoline, TRAMPOLINE_ENTRY, 8, entry);
+    put_le(&mut trampoline, TRAMPOLINE_GDT_BASE, 8,
+           trampoline_address + TRAMPOLINE_GDT);
+
+    let mut data = params;
+    data.extend_from_slice(cmdline.as_bytes());
+    data.resize((PAGE_SIZE + cmdline_pages) as usize, 0);
+    data.extend_from_slice(&trampoline);
+    segments.push(kexec::Segment{
+        data: data,
+        mem: base,
+        memsz: size,
+    });
+
+    Ok((trampoline_address, segments))
+}
+
+/// Entry point and segments for x86_64 bzImage or ELF vmlinux.
+pub fn plan(kernel: &std::fs::File, initrd: Option<&std::fs::File>,
+            cmdline: Option<&str>)
+        -> Result<(u64, Vec<kexec::Segment>), kexec::KexecError> {
+    if !cfg!(target_arch = "x86_64") {
+        error!("kexec_load is only implemented for x86_64");
+        return Err(kexec::KexecError::NotSupported);
+    }
+
+    let data = try!(read_file(kernel));
+    let initrd = match initrd {
+        Some(initrd) => Some(try!(read_file(initrd))),
+        None => None,
+    };
+    let memmap = try!(read_memmap());
+    let mut layout = Layout::new(&memmap);
+
+    let image = if is_bzimage(&data) {
+        try!(bzimage(&data, &mut layout))
+    } else if data.starts_with(ELF_MAGIC) {
+        try!(elf(&data, &mut layout))
+    } else {
+        return Err(invalid_image("neither bzImage nor ELF"));
+    };
+    finish(image, &mut layout, &memmap, initrd, cmdline, efi_rsdp())
+}
+
+/// Load x86_64 bzImage or ELF vmlinux with kexec_load(2).
+pub fn load(kernel: &std::fs::File, initrd: Option<&std::fs::File>,
+            cmdline: Option<&str>)
+        -> Result<(), kexec::KexecError> {
+    let (entry, segments) = try!(plan(kernel, initrd, cmdline));
+    kexec::load(entry, &segments)
+}
+
+#[test]
+fn lays_out_bzimage() {
+    let memmap = vec![
+        MemoryRange{start: 0, end: 0x9fc00, kind: E820_RAM},
+        MemoryRange{start: 0xf0000, end: 0x100000, kind: E820_RESERVED},
+        MemoryRange{start: 0x100000, end: 0x8000000, kind: E820_RAM},
+    ];
+    let mut data = vec![0u8; 5 * 512 + 0x1000];
+    data[HDR_SETUP_SECTS] = 4;
+    put_le(&mut data, HDR_BOOT_FLAG, 2, BOOT_FLAG);
+    data[HDR_JUMP + 1] = 0x66;
+    data[HDR_HEADER..HDR_HEADER + 4].copy_from_slice(HDR_MAGIC);
+    put_le(&mut data, HDR_VERSION, 2, 0x020f);
+    data[HDR_LOADFLAGS] = LOADED_HIGH;
+    put_le(&mut data, HDR_INITRD_ADDR_MAX, 4, 0x7fffffff);
+    put_le(&mut data, HDR_XLOADFLAGS, 2, XLF_KERNEL_64);
+    put_le(&mut data, HDR_CMDLINE_SIZE, 4, 2047);
+    put_le(&mut data, HDR_PREF_ADDRESS, 8, 0x1000000);
+    put_le(&mut data, HDR_INIT_SIZE, 4, 0x3000);
+    assert!(is_bzimage(&data));
+
+    let mut layout = Layout::new(&memmap);
+    let image = bzimage(&data, &mut layout).unwrap();
+    let (entry, segments) = finish(image, &mut layout, &memmap,
+                                   Some(vec![1u8; 0x1800]), Some("quiet"),
+                                   None).unwrap();
+    assert_eq!(segments.len(), 3);
+    assert_eq!((segments[0].mem, segments[0].memsz), (0x1000000, 0x3000));
+    assert_eq!((segments[1].mem, segments[1].memsz), (0x7ffe000, 0x2000));
+    assert_eq!((segments[2].mem, segments[2].memsz), (0x7ffb000, 0x3000));
+    assert_eq!(entry, 0x7ffd000);
+
+    let params = &segments[2].data;
+    assert_eq!(get_le(params, HDR_VERSION, 2), 0x020f);
+    assert_eq!(get_le(params, HDR_CODE32_START, 4), 0x1000000);
+    assert_eq!(get_le(params, HDR_RAMDISK_IMAGE, 4), 0x7ffe000);
+    assert_eq!(get_le(params, HDR_RAMDISK_SIZE, 4), 0x1800);
+    assert_eq!(get_le(params, HDR_CMD_LINE_PTR, 4), 0x7ffc000);
+    assert_eq!(&params[0x1000..0x1006], b"quiet\0");
+    assert_eq!(params[BP_E820_ENTRIES], 3);
+    assert_eq!(get_le(params, 0x2000 + TRAMPOLINE_ENTRY, 8), 0x1000200);
+
+    put_le(&mut data, HDR_VERSION, 2, 0x0205);
+    assert!(bzimage(&data, &mut Layout::new(&memmap)).is_err());
+
+    // ELF with a single segment at 16 MiB.
+    let mut data = vec![0u8; 0x1000];
+    data[4] = ELFCLASS64;
+    data[5] = ELFDATA2LSB;
+    put_le(&mut data, 0x12, 2, EM_X86_64);
+    put_le(&mut data, 0x20, 8, 0x40);
+    put_le(&mut data, 0x36, 2, 0x38);
+    put_le(&mut data, 0x38, 2, 1);
+    put_le(&mut data, 0x40, 4, PT_LOAD);
+    put_le(&mut data, 0x48, 8, 0x100);
+    put_le(&mut data, 0x58, 8, 0x1000100);
+    put_le(&mut data, 0x60, 8, 0x100);
+    put_le(&mut data, 0x68, 8, 0x1000);
+    let image = elf(&data, &mut Layout::new(&memmap)).unwrap();
+    assert_eq!((image.segments[0].mem, image.segments[0].memsz),
+               (0x1000000, 0x2000));
+    for &memsz in &[0xff, 0xffff_ffff_ffff_ff00] {
+        put_le(&mut data, 0x68, 8, memsz);
+        assert!(elf(&data, &mut Layout::new(&memmap)).is_err());
+    }
+}
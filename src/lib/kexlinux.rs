@@ -1,28 +1,131 @@
 use std;
 
+extern crate libc;
 extern crate natord;
+extern crate serde_json;
 extern crate syslinux_conf;
 
 use blockdev;
+use bootcount;
+use bootconf;
+use check;
+use cmdline;
+use decompress;
+use devicetree;
+use initrd;
+use inspect;
+use kexec;
+use kexec_legacy;
+use menu;
+use shutdown;
+use signature;
+use state;
+use sysappend;
+use syslinux;
 
 const CMD_KEXEC: &'static str = "kexec";
+const CMD_SYSTEMCTL: &'static str = "systemctl";
+// Added to SIGRTMIN, makes systemd start kexec.target.
+const SIGNAL_KEXEC_TARGET: libc::c_int = 6;
 
-#[derive(Debug)]
-pub struct SyslinuxConf {
-    pub timeout: Option<f64>,
-    pub total_timeout: Option<f64>,
+// Version of the JSON output of `KexLinux::to_json()`. Should be increased
+// on incompatible changes.
+const JSON_VERSION: u32 = 1;
+
+/// Where the configuration was found.
+#[derive(Debug, Clone)]
+pub struct BootSource {
+    pub root: std::path::PathBuf,
+    /// Block device, if it was found by kexlinux.
+    pub device: Option<std::path::PathBuf>,
+    pub fs_type: Option<String>,
+    /// Filesystem UUID, used to keep state per boot source.
+    pub uuid: Option<String>,
+}
 
-    pub ontimeout: syslinux_conf::Label,
-    pub onerror: Option<syslinux_conf::Label>,
+impl BootSource {
+    /// Identifies the source in boot state: filesystem UUID, or the root
+    /// directory if it is not known.
+    pub fn key(&self) -> String {
+        match self.uuid {
+            Some(ref uuid) => format!("UUID={}", uuid),
+            None => self.root.to_string_lossy().into_owned(),
+        }
+    }
+}
 
-    pub default_name: Option<String>,
-    pub labels: syslinux_conf::Labels,
+/// What to do once the kernel is loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecMode {
+    /// Execute the kernel right away, without stopping anything. Meant for
+    /// initramfs.
+    Immediate,
+    /// Leave the kernel loaded and return, e.g. to be executed by the
+    /// system on the next reboot.
+    LoadOnly,
+    /// Run `systemctl kexec`, so services are stopped and filesystems
+    /// unmounted before the kernel is executed.
+    Systemctl,
+    /// Signal PID 1 (SIGRTMIN+6) to start kexec.target, like `systemctl
+    /// kexec` without D-Bus.
+    SignalInit,
+}
+
+impl Default for ExecMode {
+    fn default() -> ExecMode {
+        ExecMode::Immediate
+    }
+}
+
+/// How to boot the selected entry.
+#[derive(Debug, Clone, Default)]
+pub struct BootOptions {
+    /// Entries to try after ONERROR if booting fails.
+    pub fallback: Vec<String>,
+    /// Changes of the kernel command line, applied to every tried entry.
+    pub cmdline: cmdline::CmdlineEdit,
+    /// Record the booted entry in the store as default for the next run,
+    /// see `KexLinux::apply_saved_entry()`.
+    pub save_default: Option<state::StateStore>,
+    /// Store for boot counting, counters are not decremented if not set.
+    pub boot_count: Option<state::StateStore>,
+    pub backend: kexec::Backend,
+    pub exec: ExecMode,
+    /// Stop processes and unmount filesystems before `ExecMode::Immediate`.
+    pub shutdown: Option<shutdown::Shutdown>,
+}
+
+/// Result of `KexLinux::dry_run()`.
+#[derive(Debug, Clone)]
+pub struct DryRun {
+    pub source: BootSource,
+    /// Name of the entry.
+    pub name: String,
+    pub kernel: std::path::PathBuf,
+    /// Format of kernel decompressed before loading, e.g. "gzip compressed".
+    pub kernel_format: Option<String>,
+    pub kernel_info: inspect::KernelInfo,
+    pub kernel_signature: signature::Status,
+    pub initrds: Vec<std::path::PathBuf>,
+    /// Final command line, with edits and SYSAPPEND options.
+    pub cmdline: Option<String>,
+    /// Base devicetree, overlays are applied on top.
+    pub devicetree: Option<std::path::PathBuf>,
+    pub devicetree_overlays: Vec<std::path::PathBuf>,
+    pub backend: kexec::Backend,
+    /// System calls or commands which would be run, in order.
+    pub invocation: Vec<String>,
+    /// Entries tried if booting the entry fails.
+    pub fallback: Vec<String>,
 }
 
 #[derive(Debug)]
 pub struct KexLinux {
-    reader: syslinux_conf::Reader,
-    conf: SyslinuxConf,
+    conf: bootconf::BootConf,
+    source: BootSource,
+    // Released by pre-exec shutdown.
+    mount: std::cell::RefCell<Option<blockdev::Mount>>,
+    verifier: signature::Verifier,
 }
 
 // TODO: Detailed errors.
@@ -41,127 +144,94 @@ impl std::convert::From<blockdev::BlockDevError> for KexLinuxError {
     fn from(_: blockdev::BlockDevError) -> KexLinuxError { KexLinuxError{} }
 }
 
-impl SyslinuxConf {
-    fn fix_append(mut label: syslinux_conf::Label) -> syslinux_conf::Label {
-        match label.kernel_or_config {
-            syslinux_conf::KernelOrConfig::Kernel(ref mut kernel) => {
-                kernel.append = kernel.append.clone().and_then(
-                    |v| if v == "-" { None } else { Some(v) })
-            },
-        };
-        label
-    }
-
-    fn filter_map_labels(label_defaults: syslinux_conf::Label,
-                     labels: syslinux_conf::Labels) -> syslinux_conf::Labels {
-        use std::iter::FromIterator;
-        syslinux_conf::Labels::from_iter(labels.into_iter()
-            .map(
-                |(label_name, label)| {
-                    use kexlinux::syslinux_conf::ApplyDefaults;
-                    (label_name, SyslinuxConf::fix_append(
-                        label.apply_defaults(&label_defaults)))
-                })
-            .filter(
-                |&(ref label_name, ref label)| {
-                    match label.kernel_or_config {
-                        syslinux_conf::KernelOrConfig::Kernel(ref kernel) => {
-                            match kernel.kernel_file {
-                                Some(syslinux_conf::KernelFile::Linux(_)) => {
-                                    true
-                                },
-
-                                // Other kernel types are not supported.
-                                Some(ref kernel_file) => {
-                                    warn!(
-                                        "Unsupported kernel type {:?} in \
-                                        \"{}\", skipping",
-                                        kernel_file, label_name);
-                                    false
-                                },
-
-                                None => {
-                                    warn!(
-                                        "No kernel in \"{}\", skipping",
-                                        label_name);
-                                    false
-                                },
-                            }
-                        },
-                    }
-            }))
-    }
+impl std::convert::From<decompress::DecompressError> for KexLinuxError {
+    fn from(_: decompress::DecompressError) -> KexLinuxError { KexLinuxError{} }
+}
 
-    fn from_conf(conf: syslinux_conf::SyslinuxConf)
-            -> Result<SyslinuxConf, KexLinuxError> {
-        let labels = SyslinuxConf::filter_map_labels(
-            conf.global.label_defaults, conf.labels);
+impl std::convert::From<initrd::InitrdError> for KexLinuxError {
+    fn from(_: initrd::InitrdError) -> KexLinuxError { KexLinuxError{} }
+}
 
-        let default = conf.global.default.as_ref().and_then(
-            |default_name| labels.get(default_name).cloned());
-        let ontimeout = conf.global.ontimeout.and_then(
-            |ontimeout_name| labels.get(&ontimeout_name).cloned());
+impl std::convert::From<devicetree::DevicetreeError> for KexLinuxError {
+    fn from(_: devicetree::DevicetreeError) -> KexLinuxError {
+        KexLinuxError{}
+    }
+}
 
-        let default_name = match default {
-            Some(_) => conf.global.default,
-            None => {
-                warn!("Default label not found: \"{:?}\"", conf.global.default);
-                None
-            },
-        };
+impl std::convert::From<inspect::InspectError> for KexLinuxError {
+    fn from(_: inspect::InspectError) -> KexLinuxError { KexLinuxError{} }
+}
 
-        Ok(SyslinuxConf{
-            timeout: conf.global.timeout,
-            total_timeout: conf.global.total_timeout,
+impl std::convert::From<kexec::KexecError> for KexLinuxError {
+    fn from(_: kexec::KexecError) -> KexLinuxError { KexLinuxError{} }
+}
 
-            ontimeout: try!(ontimeout.or(
-                default.or_else(|| match labels.front() {
-                    Some((_, first_label)) => Some(first_label.clone()),
-                    None => {
-                        error!("Nothing to boot");
-                        None
-                    },
-                })).ok_or(KexLinuxError{})),
-            onerror: conf.global.onerror.and_then(
-                |onerror_name| labels.get(&onerror_name).cloned()),
+impl std::convert::From<menu::MenuError> for KexLinuxError {
+    fn from(_: menu::MenuError) -> KexLinuxError { KexLinuxError{} }
+}
 
-            default_name: default_name,
-            labels: labels,
-        })
-    }
+impl std::convert::From<signature::SignatureError> for KexLinuxError {
+    fn from(_: signature::SignatureError) -> KexLinuxError { KexLinuxError{} }
+}
+
+impl std::convert::From<state::StateError> for KexLinuxError {
+    fn from(_: state::StateError) -> KexLinuxError { KexLinuxError{} }
 }
 
 impl KexLinux {
-    fn from_reader(reader: syslinux_conf::Reader)
+    fn read_root(root: &std::path::Path, registry: &bootconf::Registry)
             -> Result<KexLinux, KexLinuxError> {
         Ok(KexLinux{
-            conf: try!(SyslinuxConf::from_conf(try!(reader.read()))),
-            reader: reader,
+            conf: try!(registry.read(root)),
+            source: BootSource{
+                root: root.to_path_buf(),
+                device: None,
+                fs_type: None,
+                uuid: None,
+            },
+            mount: std::cell::RefCell::new(None),
+            verifier: signature::Verifier::default(),
         })
     }
 
+    /// Read configuration from already mounted root using sources from
+    /// the given registry.
+    pub fn from_root(root: &std::path::Path, registry: &bootconf::Registry)
+            -> Result<KexLinux, KexLinuxError> {
+        let mut kexlinux = try!(KexLinux::read_root(root, registry));
+        kexlinux.source.uuid = blockdev::fs_uuid(root);
+        Ok(kexlinux)
+    }
+
+    fn from_syslinux_source(root: std::path::PathBuf,
+                            source: syslinux::SyslinuxSource)
+            -> Result<KexLinux, KexLinuxError> {
+        let mut registry = bootconf::Registry::empty();
+        registry.register(Box::new(source));
+        KexLinux::from_root(&root, &registry)
+    }
+
     pub fn from_local_conf_file_path(root: std::path::PathBuf,
                                      conf_file_path: std::path::PathBuf)
             -> Result<KexLinux, KexLinuxError> {
-        KexLinux::from_reader(try!(
-            syslinux_conf::Reader::from_local_conf_file_path(root,
-                                                             conf_file_path)))
+        KexLinux::from_syslinux_source(
+            root, syslinux::SyslinuxSource::with_conf_file_path(conf_file_path))
     }
 
     pub fn from_local_type(root: std::path::PathBuf,
                            local_type: syslinux_conf::LocalConfType)
             -> Result<KexLinux, KexLinuxError> {
-        KexLinux::from_reader(try!(
-            syslinux_conf::Reader::from_local_type(root, local_type)))
+        KexLinux::from_syslinux_source(
+            root, syslinux::SyslinuxSource::with_type(local_type))
     }
 
     pub fn from_local(root: std::path::PathBuf)
             -> Result<KexLinux, KexLinuxError> {
-        KexLinux::from_reader(try!(
-            syslinux_conf::Reader::from_local(root)))
+        KexLinux::from_root(&root, &bootconf::Registry::new())
     }
 
-    fn from_device_list<BlockDevIter>(devs: BlockDevIter)
+    fn from_device_list<BlockDevIter>(devs: BlockDevIter,
+                                      registry: &bootconf::Registry)
             -> Result<KexLinux, KexLinuxError>
             where BlockDevIter: Iterator<Item=blockdev::BlockDev> {
         let mut filesystems = blockdev::get_filesystems(devs);
@@ -169,8 +239,15 @@ impl KexLinux {
 
         for fs in filesystems {
             match blockdev::Mount::mount(&fs) {
-                Ok(fs) => match KexLinux::from_local(fs.path().clone()) {
-                    Ok(kexlinux) => return Ok(kexlinux),
+                Ok(mount) => match KexLinux::read_root(mount.path(), registry) {
+                    Ok(mut kexlinux) => {
+                        kexlinux.source.device = Some(fs.dev.path.clone());
+                        kexlinux.source.fs_type = Some(fs.fs_type.clone());
+                        kexlinux.source.uuid = fs.uuid.clone();
+                        // Keep boot device mounted until kexec.
+                        *kexlinux.mount.borrow_mut() = Some(mount);
+                        return Ok(kexlinux)
+                    },
                     Err(_) => (),  // continue
                 },
 
@@ -182,23 +259,291 @@ impl KexLinux {
         Err(KexLinuxError{})
     }
 
-    pub fn from_device_path(dev: std::path::PathBuf)
+    pub fn from_device_path_with_registry(dev: std::path::PathBuf,
+                                          registry: &bootconf::Registry)
             -> Result<KexLinux, KexLinuxError> {
         let dev = try!(blockdev::BlockDev::from_dev_path(dev));
         match dev.partitions.is_empty() {
-            true => KexLinux::from_device_list(vec![dev].into_iter()),
-            false => KexLinux::from_device_list(dev.partitions.into_iter()),
+            true => KexLinux::from_device_list(vec![dev].into_iter(),
+                                               registry),
+            false => KexLinux::from_device_list(dev.partitions.into_iter(),
+                                                registry),
         }
     }
 
+    pub fn from_device_path(dev: std::path::PathBuf)
+            -> Result<KexLinux, KexLinuxError> {
+        KexLinux::from_device_path_with_registry(dev,
+                                                 &bootconf::Registry::new())
+    }
+
+    pub fn auto_with_registry(registry: &bootconf::Registry)
+            -> Result<KexLinux, KexLinuxError> {
+        KexLinux::from_device_list(try!(blockdev::BlockDevs::new()), registry)
+    }
+
     pub fn auto() -> Result<KexLinux, KexLinuxError> {
-        KexLinux::from_device_list(try!(blockdev::BlockDevs::new()))
+        KexLinux::auto_with_registry(&bootconf::Registry::new())
     }
 
-    pub fn get_conf(&self) -> &SyslinuxConf {
+    pub fn get_conf(&self) -> &bootconf::BootConf {
         &self.conf
     }
 
+    /// Make the entry saved by `BootOptions::save_default` on the previous
+    /// run from this boot source the default, if it still exists.
+    pub fn apply_saved_entry(&mut self, store: &state::StateStore)
+            -> Result<Option<String>, KexLinuxError> {
+        let uuid = match self.source.uuid {
+            Some(ref uuid) => uuid.clone(),
+            None => {
+                warn!("Boot source has no UUID, saved entry is not used");
+                return Ok(None);
+            },
+        };
+
+        match try!(store.saved_entry(&uuid)) {
+            Some(name) => match self.conf.set_default(&name) {
+                true => {
+                    info!("Using saved entry \"{}\"", name);
+                    Ok(Some(name))
+                },
+                false => {
+                    warn!("Saved entry \"{}\" not found, ignored", name);
+                    Ok(None)
+                },
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn save_entry(&self, entry: &bootconf::BootEntry, options: &BootOptions) {
+        let store = match options.save_default {
+            Some(ref store) => store,
+            None => return,
+        };
+        match self.source.uuid {
+            Some(ref uuid) => {
+                if let Err(_) = store.set_saved_entry(uuid, &entry.name) {
+                    warn!("Unable to save entry \"{}\"", entry.name);
+                }
+            },
+            None => warn!("Boot source has no UUID, entry is not saved"),
+        }
+    }
+
+    /// Load sidecar boot counters of the boot source.
+    pub fn apply_boot_counting(&mut self, store: &state::StateStore)
+            -> Result<(), KexLinuxError> {
+        match self.source.uuid {
+            Some(ref uuid) => {
+                let sidecar = try!(bootcount::read_sidecar(store, uuid));
+                for entry in &mut self.conf.entries {
+                    let counters = match entry.counter {
+                        Some(_) => bootcount::lookup(
+                            &sidecar, &entry.name,
+                            &bootcount::kernel_id(&entry.kernel)),
+                        None => None,
+                    };
+                    match counters {
+                        Some(&Some((left, done))) => {
+                            let counter = entry.counter.as_mut().unwrap();
+                            counter.left = left;
+                            counter.done = done;
+                        },
+                        Some(&None) => entry.counter = None,  // Marked good.
+                        None => (),
+                    }
+                }
+            },
+            None => {
+                let counted = self.conf.entries.iter()
+                    .any(|entry| entry.counter.is_some());
+                if counted {
+                    warn!("Boot source has no UUID, sidecar boot counters \
+                           are not used");
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Make sure the entry booted by `Selection::Auto` has tries left.
+    pub fn replace_bad_default(&mut self) -> Option<String> {
+        self.conf.replace_bad_default()
+    }
+
+    fn count_boot(&self, entry: &bootconf::BootEntry, options: &BootOptions) {
+        let (store, counter) = match (&options.boot_count, &entry.counter) {
+            (&Some(ref store), &Some(ref counter)) => (store, counter),
+            _ => return,
+        };
+        match self.source.uuid {
+            Some(ref uuid) => {
+                if let Err(_) = bootcount::count_boot(
+                        store, uuid, &entry.name,
+                        &bootcount::kernel_id(&entry.kernel), counter) {
+                    warn!("Unable to update boot counter of \"{}\"",
+                          entry.name);
+                }
+            },
+            None => warn!("Boot source has no UUID, boot is not counted"),
+        }
+    }
+
+    /// Reset boot counter of the entry booted last, from the booted system.
+    /// Returns name of the entry, if there was one to mark.
+    pub fn mark_good(store: &state::StateStore)
+            -> Result<Option<String>, KexLinuxError> {
+        let (uuid, name, id) = match try!(bootcount::pending(store)) {
+            Some(pending) => pending,
+            None => return Ok(None),
+        };
+
+        try!(bootcount::mark_good(store, &uuid, &name, &id));
+        try!(bootcount::clear_pending(store));
+        info!("Entry \"{}\" marked good", name);
+        Ok(Some(name))
+    }
+
+    /// Consume one-shot entry from the store and make it the entry booted
+    /// by `Selection::Auto`. The entry is cleared even if it does not exist
+    /// in configuration, so a bad value is never retried. Entry set for
+    /// another boot source is ignored and kept.
+    pub fn apply_next_entry(&mut self, store: &state::StateStore)
+            -> Result<Option<String>, KexLinuxError> {
+        let name = try!(store.take_next_entry(&self.source.key()));
+        Ok(self.use_next_entry(name))
+    }
+
+    /// Like `apply_next_entry()`, but the one-shot entry is not consumed.
+    pub fn preview_next_entry(&mut self, store: &state::StateStore)
+            -> Result<Option<String>, KexLinuxError> {
+        let name = try!(store.next_entry(&self.source.key()));
+        Ok(self.use_next_entry(name))
+    }
+
+    fn use_next_entry(&mut self, name: Option<String>) -> Option<String> {
+        match name {
+            Some(name) => match self.conf.set_default(&name) {
+                true => {
+                    info!("Booting one-shot entry \"{}\"", name);
+                    Some(name)
+                },
+                false => {
+                    warn!("One-shot entry \"{}\" not found, ignored", name);
+                    None
+                },
+            },
+            None => None,
+        }
+    }
+
+    /// Check kernel signatures with the verifier before loading.
+    pub fn set_verifier(&mut self, verifier: signature::Verifier) {
+        self.verifier = verifier;
+    }
+
+    /// Result of kernel signature check, `Status::Unchecked` if checks are
+    /// off.
+    pub fn kernel_signature(&self, entry: &bootconf::BootEntry)
+            -> signature::Status {
+        self.verifier.verify_kernel(&entry.kernel)
+    }
+
+    /// Root directory configuration was read from.
+    pub fn get_root(&self) -> &std::path::Path {
+        &self.source.root
+    }
+
+    pub fn get_source(&self) -> &BootSource {
+        &self.source
+    }
+
+    /// Discovered configuration in JSON format, for inventory tools.
+    pub fn to_json(&self) -> serde_json::Value {
+        let conf = &self.conf;
+        let entries: Vec<serde_json::Value> = conf.entries.iter()
+            .map(|entry| json!({
+                "name": entry.name,
+                "title": entry.title,
+                "kernel": entry.kernel.to_string_lossy(),
+                "initrds": entry.initrds.iter()
+                    .map(|initrd| initrd.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "cmdline": entry.cmdline,
+                "sysappend": entry.sysappend,
+                "devicetree": entry.devicetree.as_ref()
+                    .map(|path| path.to_string_lossy()),
+                "devicetree_dir": entry.devicetree_dir.as_ref()
+                    .map(|path| path.to_string_lossy()),
+                "devicetree_overlays": entry.devicetree_overlays.iter()
+                    .map(|path| path.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "hidden": entry.hidden,
+                "disabled": entry.disabled,
+                "protected": entry.passwd.is_some(),
+                "signature": match self.kernel_signature(entry) {
+                    signature::Status::Unchecked => None,
+                    status => Some(status.to_string()),
+                },
+                "tries_left": entry.counter.as_ref()
+                    .map(|counter| counter.left),
+                "tries_done": entry.counter.as_ref()
+                    .map(|counter| counter.done),
+                "default": Some(&entry.name) == conf.default_name.as_ref(),
+                "ontimeout": entry.name == conf.ontimeout_name,
+                "onerror": Some(&entry.name) == conf.onerror_name.as_ref(),
+            }))
+            .collect();
+
+        json!({
+            "version": JSON_VERSION,
+            "source": {
+                "root": self.source.root.to_string_lossy(),
+                "device": self.source.device.as_ref()
+                    .map(|path| path.to_string_lossy()),
+                "fs_type": self.source.fs_type,
+                "uuid": self.source.uuid,
+            },
+            "timeout": conf.timeout,
+            "total_timeout": conf.total_timeout,
+            "default": conf.default_name,
+            "ontimeout": conf.ontimeout_name,
+            "onerror": conf.onerror_name,
+            "entries": entries,
+        })
+    }
+
+    /// Check configuration on the boot device for problems.
+    pub fn check(&self, registry: &bootconf::Registry)
+            -> Result<check::Report, KexLinuxError> {
+        registry.check(&self.source.root)
+    }
+
+    /// Show boot menu on the controlling terminal and configured serial
+    /// console with `highlight` entry initially selected.
+    pub fn menu(&self, highlight: Option<&str>)
+            -> Result<bootconf::BootEntry, KexLinuxError> {
+        let mut terminal = try!(menu::Terminal::open_consoles(&self.conf));
+        let note = |entry: &bootconf::BootEntry| {
+            String::from(match self.kernel_signature(entry) {
+                signature::Status::Valid(_) => "signed",
+                signature::Status::Invalid(_) => "bad signature",
+                _ => "unsigned",
+            })
+        };
+        let mut menu = menu::Menu::new(&self.conf);
+        if self.verifier.policy() != signature::Policy::Off {
+            menu.set_annotator(&note);
+        }
+        if let Some(name) = highlight {
+            menu.highlight(name);
+        }
+        Ok(try!(menu.run(&mut terminal)))
+    }
+
     fn check_kexec_output(mut cmd: std::process::Command, stage: &str)
             -> Result<(), KexLinuxError> {
         let output = try!(cmd.output());
@@ -218,60 +563,493 @@ impl KexLinux {
         }
     }
 
-    fn load_kernel(label: &syslinux_conf::Label) -> Result<(), KexLinuxError> {
-        let kernel = match label.kernel_or_config {
-            syslinux_conf::KernelOrConfig::Kernel(ref kernel) => kernel,
-        };
+    /// Final kernel command line for the entry, with SYSAPPEND options.
+    pub fn cmdline(entry: &bootconf::BootEntry) -> Option<String> {
+        let cmdline = entry.cmdline.as_ref().map(|cmdline| cmdline.as_str());
+        match entry.sysappend {
+            0 => cmdline.map(String::from),
+            flags => sysappend::SysInfo::new().augment(cmdline, flags),
+        }
+    }
 
-        let kernel_file = match kernel.kernel_file {
-            Some(syslinux_conf::KernelFile::Linux(ref kernel_file)) => {
-                kernel_file
-            },
+    fn tool_load_command(kernel: &std::path::Path,
+                         initrd: Option<&std::path::Path>,
+                         cmdline: Option<&str>,
+                         devicetree: Option<&std::path::Path>)
+            -> Result<std::process::Command, KexLinuxError> {
+        let mut cmd = std::process::Command::new(CMD_KEXEC);
+        cmd.args(&["--load", try!(kernel.to_str().ok_or(KexLinuxError{}))]);
+        if let Some(initrd) = initrd {
+            cmd.args(
+                &["--initrd", try!(initrd.to_str().ok_or(KexLinuxError{}))]);
+        }
+        if let Some(cmdline) = cmdline {
+            cmd.args(&["--append", cmdline]);
+        }
+        if let Some(devicetree) = devicetree {
+            cmd.args(
+                &["--dtb", try!(devicetree.to_str().ok_or(KexLinuxError{}))]);
+        }
+        Ok(cmd)
+    }
 
-            Some(ref kernel_file) => {
-                error!("Unsupported kernel type {:?}, unable to kexec",
-                       kernel_file);
-                return Err(KexLinuxError{});
+    fn load_kernel_tool(kernel: &std::path::Path,
+                        initrd: Option<&std::path::Path>,
+                        cmdline: Option<&str>,
+                        devicetree: Option<&std::path::Path>)
+            -> Result<(), KexLinuxError> {
+        let mut cmd = try!(KexLinux::tool_load_command(kernel, initrd, cmdline,
+                                                       devicetree));
+        cmd.stdin(std::process::Stdio::null());
+
+        KexLinux::check_kexec_output(cmd, "load")
+    }
+
+    fn load_kernel_native(entry: &bootconf::BootEntry,
+                          kernel: &std::fs::File,
+                          initrd: Option<&std::fs::File>,
+                          cmdline: Option<&str>, backend: kexec::Backend)
+            -> Result<(), KexLinuxError> {
+        let result = match backend {
+            kexec::Backend::Native => {
+                match kexec::file_load(kernel, initrd, cmdline) {
+                    Err(kexec::KexecError::NotSupported) => {
+                        info!("kexec_file_load is not supported, using \
+                               kexec_load");
+                        kexec_legacy::load(kernel, initrd, cmdline)
+                    },
+                    result => result,
+                }
+            },
+            _ => kexec_legacy::load(kernel, initrd, cmdline),
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                error!("Unable to load kernel {:?}: {}", entry.kernel, err);
+                Err(KexLinuxError::from(err))
             },
+        }
+    }
 
-            None => {
-                error!("No kernel, unable to kexec");
-                return Err(KexLinuxError{});
+    // Reject kernels which can not run on this machine before loading.
+    fn inspect_kernel(entry: &bootconf::BootEntry,
+                      kernel: &decompress::Kernel,
+                      cmdline: Option<&str>, backend: kexec::Backend)
+            -> Result<inspect::KernelInfo, KexLinuxError> {
+        let info = match inspect::inspect_kernel(kernel) {
+            Ok(info) => info,
+            Err(err) => {
+                error!("Kernel {:?} is invalid: {}", entry.kernel, err);
+                return Err(KexLinuxError::from(err));
             },
         };
+        info!("Kernel is {}", info);
+        if info.format == inspect::KernelFormat::Unknown {
+            warn!("Unknown format of kernel {:?}", entry.kernel);
+        }
+        if let Err(err) = info.check(cmdline, backend) {
+            error!("Kernel {:?} can not be booted: {}", entry.kernel, err);
+            return Err(KexLinuxError::from(err));
+        }
+        Ok(info)
+    }
 
-        let mut cmd = std::process::Command::new(CMD_KEXEC);
+    // System calls always pass the current devicetree, only kexec tool can
+    // load another one. It is used instead of the default backend for
+    // entries with devicetree.
+    fn select_backend(entry: &bootconf::BootEntry, backend: kexec::Backend,
+                      devicetree: bool)
+            -> Result<kexec::Backend, KexLinuxError> {
+        match (backend, devicetree) {
+            (kexec::Backend::Native, true) => {
+                info!("Entry \"{}\" has a devicetree, loading it with the \
+                       \"{}\" command from kexec-tools", entry.name,
+                      CMD_KEXEC);
+                Ok(kexec::Backend::Tool)
+            },
+            (kexec::Backend::Legacy, true) => {
+                error!("Entry \"{}\" has a devicetree, which can only be \
+                        loaded with the \"{}\" command from kexec-tools",
+                       entry.name, CMD_KEXEC);
+                Err(KexLinuxError{})
+            },
+            _ => Ok(backend),
+        }
+    }
 
-        info!("Loading kernel \"{}\"...", kernel_file.to_string_lossy());
-        cmd.args(
-            &["--load", try!(kernel_file.to_str().ok_or(KexLinuxError{}))]);
+    // Read kernel once and check its signature, so the data which is loaded
+    // is the data which was verified.
+    fn read_kernel(entry: &bootconf::BootEntry,
+                   verifier: &signature::Verifier)
+            -> Result<(decompress::Kernel, signature::Status),
+                      KexLinuxError> {
+        use std::io::Read;
+
+        let mut data = Vec::new();
+        let result = std::fs::File::open(&entry.kernel)
+            .and_then(|mut file| file.read_to_end(&mut data));
+        if let Err(err) = result {
+            error!("Unable to read kernel {:?}: {}", entry.kernel, err);
+            return Err(KexLinuxError::from(err));
+        }
 
-        if let Some(ref initrd) = kernel.initrd {
+        let signature = verifier.verify_kernel_data(&entry.kernel, &data);
+        try!(verifier.enforce(&format!("Kernel {:?}", entry.kernel),
+                              &signature));
+        let kernel = try!(decompress::Kernel::load(&entry.kernel, &data));
+        Ok((kernel, signature))
+    }
+
+    // Returns backend the kernel was loaded with.
+    fn load_kernel(entry: &bootconf::BootEntry,
+                   verifier: &signature::Verifier, backend: kexec::Backend)
+            -> Result<kexec::Backend, KexLinuxError> {
+        info!("Loading kernel \"{}\"...", entry.kernel.to_string_lossy());
+        // Should be alive until kernel is loaded.
+        let (loaded, _) = try!(KexLinux::read_kernel(entry, verifier));
+        for initrd in &entry.initrds {
             info!("With initrd: \"{}\"", initrd.to_string_lossy());
-            cmd.args(
-                &["--initrd", try!(initrd.to_str().ok_or(KexLinuxError{}))]);
+        }
+        let cmdline = KexLinux::cmdline(entry);
+        if let Some(ref cmdline) = cmdline {
+            info!("With append: \"{}\"", cmdline);
         }
 
-        if let Some(ref append) = kernel.append {
-            info!("With append: \"{}\"", append);
-            cmd.args(&["--append", append]);
+        // Should be alive until kernel is loaded.
+        let devicetree = try!(devicetree::resolve(entry));
+        if let Some(ref devicetree) = devicetree {
+            info!("With devicetree: \"{}\"",
+                  devicetree.path().to_string_lossy());
         }
+        let backend = try!(KexLinux::select_backend(entry, backend,
+                                                    devicetree.is_some()));
 
-        cmd.stdin(std::process::Stdio::null());
+        let kernel = try!(loaded.file().try_clone());
+        try!(KexLinux::inspect_kernel(entry, &loaded,
+                                      cmdline.as_ref().map(|s| s.as_str()),
+                                      backend));
 
-        KexLinux::check_kexec_output(cmd, "load")
+        // Should be alive until kernel is loaded.
+        let concatenated = match entry.initrds.len() {
+            0 | 1 => None,
+            _ => Some(try!(initrd::Initrd::concat(&entry.initrds))),
+        };
+
+        match backend {
+            kexec::Backend::Native | kexec::Backend::Legacy => {
+                let opened = match (&concatenated, entry.initrds.first()) {
+                    (&None, Some(initrd)) => {
+                        Some(try!(std::fs::File::open(initrd)))
+                    },
+                    _ => None,
+                };
+                let initrd = concatenated.as_ref()
+                    .map(|concatenated| concatenated.file())
+                    .or(opened.as_ref());
+                try!(KexLinux::load_kernel_native(
+                    entry, &kernel, initrd,
+                    cmdline.as_ref().map(|s| s.as_str()), backend));
+            },
+
+            kexec::Backend::Tool => {
+                let initrd = match concatenated {
+                    Some(ref concatenated) => Some(concatenated.path()),
+                    None => entry.initrds.first().cloned(),
+                };
+                let kernel = loaded.path();
+                try!(KexLinux::load_kernel_tool(
+                    &kernel, initrd.as_ref().map(|path| path.as_path()),
+                    cmdline.as_ref().map(|s| s.as_str()),
+                    devicetree.as_ref().map(|devicetree| devicetree.path())));
+            },
+        }
+        Ok(backend)
     }
 
-    fn kexec() -> Result<(), KexLinuxError> {
-        let mut cmd = std::process::Command::new(CMD_KEXEC);
-        cmd.arg("--exec");
-        cmd.stdin(std::process::Stdio::null());
-        try!(KexLinux::check_kexec_output(cmd, "exec"));
+    fn unload(backend: kexec::Backend) -> Result<(), KexLinuxError> {
+        match backend {
+            kexec::Backend::Native | kexec::Backend::Legacy => {
+                let result = match backend {
+                    kexec::Backend::Native => match kexec::file_unload() {
+                        Err(kexec::KexecError::NotSupported) => {
+                            kexec::unload()
+                        },
+                        result => result,
+                    },
+                    _ => kexec::unload(),
+                };
+                result.map_err(|err| {
+                    error!("Unable to unload kernel: {}", err);
+                    KexLinuxError::from(err)
+                })
+            },
+            kexec::Backend::Tool => {
+                let mut cmd = std::process::Command::new(CMD_KEXEC);
+                cmd.arg("--unload");
+                cmd.stdin(std::process::Stdio::null());
+                KexLinux::check_kexec_output(cmd, "unload")
+            },
+        }
+    }
+
+    fn kexec(backend: kexec::Backend) -> Result<(), KexLinuxError> {
+        match backend {
+            // Kernel loaded by any backend can be executed this way.
+            kexec::Backend::Native | kexec::Backend::Legacy => {
+                if let Err(err) = kexec::exec() {
+                    error!("Unable to execute kernel: {}", err);
+                    return Err(KexLinuxError::from(err));
+                }
+            },
+            kexec::Backend::Tool => {
+                let mut cmd = std::process::Command::new(CMD_KEXEC);
+                cmd.arg("--exec");
+                cmd.stdin(std::process::Stdio::null());
+                try!(KexLinux::check_kexec_output(cmd, "exec"));
+            },
+        }
 
         panic!("This will never happen")
     }
 
-    pub fn boot(label: &syslinux_conf::Label) -> Result<(), KexLinuxError> {
-        KexLinux::load_kernel(label).and_then(|_| KexLinux::kexec())
+    fn exec_loaded(&self, options: &BootOptions, backend: kexec::Backend)
+            -> Result<(), KexLinuxError> {
+        match options.exec {
+            ExecMode::Immediate => {
+                if let Some(ref shutdown) = options.shutdown {
+                    // Unmounts boot device, kernel is already loaded.
+                    self.mount.borrow_mut().take();
+                    shutdown::run(shutdown);
+                }
+                KexLinux::kexec(backend)
+            },
+            ExecMode::LoadOnly => {
+                info!("Kernel loaded, not executing it");
+                Ok(())
+            },
+            ExecMode::Systemctl => {
+                let mut cmd = std::process::Command::new(CMD_SYSTEMCTL);
+                cmd.arg("kexec");
+                cmd.stdin(std::process::Stdio::null());
+                KexLinux::check_kexec_output(cmd, "systemctl")
+            },
+            ExecMode::SignalInit => {
+                let signal = libc::SIGRTMIN() + SIGNAL_KEXEC_TARGET;
+                match unsafe { libc::kill(1, signal) } {
+                    0 => Ok(()),
+                    _ => {
+                        error!("Unable to signal init: {}",
+                               std::io::Error::last_os_error());
+                        Err(KexLinuxError{})
+                    },
+                }
+            },
+        }
+    }
+
+    fn describe_exec(exec: ExecMode, backend: kexec::Backend)
+            -> Option<String> {
+        match (exec, backend) {
+            (ExecMode::Immediate, kexec::Backend::Tool) => {
+                Some(format!("{:?} \"--exec\"", CMD_KEXEC))
+            },
+            (ExecMode::Immediate, _) => Some(kexec::describe_exec()),
+            (ExecMode::LoadOnly, _) => None,
+            (ExecMode::Systemctl, _) => {
+                Some(format!("{:?} \"kexec\"", CMD_SYSTEMCTL))
+            },
+            (ExecMode::SignalInit, _) => {
+                Some(String::from("kill(1, SIGRTMIN+6)"))
+            },
+        }
+    }
+
+    pub fn boot(entry: &bootconf::BootEntry) -> Result<(), KexLinuxError> {
+        KexLinux::load_kernel(entry, &signature::Verifier::default(),
+                              kexec::Backend::default())
+            .and_then(KexLinux::kexec)
     }
+
+    /// Entries to try in order: the given one, ONERROR, then additional
+    /// fallback entries. Duplicates and unknown names are skipped.
+    pub fn boot_chain<'a>(&'a self, entry: &'a bootconf::BootEntry,
+                          fallback: &[String])
+            -> Vec<&'a bootconf::BootEntry> {
+        let mut chain = vec![entry];
+        let names = self.conf.onerror_name.iter().chain(fallback.iter());
+        for name in names {
+            match self.conf.get(name) {
+                Some(entry) => {
+                    if !chain.iter().any(|e| e.name == entry.name) {
+                        chain.push(entry);
+                    }
+                },
+                None => warn!("Fallback entry \"{}\" not found", name),
+            }
+        }
+        chain
+    }
+
+    /// Entry with command line changed according to options. SYSAPPEND
+    /// options are added first, so the edits can replace or remove them.
+    pub fn edit_entry(entry: &bootconf::BootEntry, options: &BootOptions)
+            -> bootconf::BootEntry {
+        let mut entry = entry.clone();
+        entry.cmdline = options.cmdline.apply(
+            KexLinux::cmdline(&entry).as_ref().map(|cmdline| cmdline.as_str()));
+        entry.sysappend = 0;
+        entry
+    }
+
+    /// What `boot_with_options()` would do for the entry. Kernel, initrds
+    /// and devicetree are resolved and checked, but nothing is loaded or
+    /// executed and boot state is not changed.
+    pub fn dry_run(&self, entry: &bootconf::BootEntry, options: &BootOptions)
+            -> Result<DryRun, KexLinuxError> {
+        let fallback = self.boot_chain(entry, &options.fallback).iter()
+            .skip(1)
+            .map(|entry| entry.name.clone())
+            .collect();
+        let entry = KexLinux::edit_entry(entry, options);
+
+        let (loaded, kernel_signature) = try!(KexLinux::read_kernel(
+            &entry, &self.verifier));
+        let kernel = try!(loaded.file().try_clone());
+        let kernel_name = match loaded.format() {
+            Some(_) => {
+                format!("<memory file of decompressed {:?}>", entry.kernel)
+            },
+            None => format!("<memory file of {:?}>", entry.kernel),
+        };
+        for initrd in &entry.initrds {
+            if let Err(err) = std::fs::File::open(initrd) {
+                error!("Unable to open initrd {:?}: {}", initrd, err);
+                return Err(KexLinuxError{});
+            }
+        }
+        let cmdline = KexLinux::cmdline(&entry);
+        let devicetree = try!(devicetree::find_base(&entry));
+        let backend = try!(KexLinux::select_backend(&entry, options.backend,
+                                                    devicetree.is_some()));
+        let kernel_info = try!(KexLinux::inspect_kernel(
+            &entry, &loaded, cmdline.as_ref().map(|s| s.as_str()), backend));
+
+        let initrd = match entry.initrds.len() {
+            0 => None,
+            1 => Some(entry.initrds[0].to_string_lossy().into_owned()),
+            count => Some(format!("<memory file of {} initrds>", count)),
+        };
+        let mut invocation = match backend {
+            kexec::Backend::Native => vec![
+                kexec::describe_file_load(
+                    &kernel_name,
+                    initrd.as_ref().map(|initrd| initrd.as_str()),
+                    cmdline.as_ref().map(|cmdline| cmdline.as_str())),
+            ],
+            kexec::Backend::Legacy => {
+                let initrd = match entry.initrds.is_empty() {
+                    true => None,
+                    false => Some(try!(initrd::Initrd::concat(&entry.initrds))),
+                };
+                let (start, segments) = try!(kexec_legacy::plan(
+                    &kernel, initrd.as_ref().map(|initrd| initrd.file()),
+                    cmdline.as_ref().map(|cmdline| cmdline.as_str())));
+                vec![kexec::describe_load(start, &segments)]
+            },
+            kexec::Backend::Tool => {
+                let cmd = try!(KexLinux::tool_load_command(
+                    std::path::Path::new(&kernel_name),
+                    initrd.as_ref().map(|initrd| std::path::Path::new(initrd)),
+                    cmdline.as_ref().map(|cmdline| cmdline.as_str()),
+                    devicetree.as_ref().map(|path| path.as_path())));
+                vec![format!("{:?}", cmd)]
+            },
+        };
+        if let (ExecMode::Immediate, Some(shutdown)) =
+                (options.exec, options.shutdown.as_ref()) {
+            invocation.push(shutdown::describe(shutdown));
+        }
+        invocation.extend(KexLinux::describe_exec(options.exec, backend));
+
+        Ok(DryRun{
+            source: self.source.clone(),
+            name: entry.name.clone(),
+            kernel: entry.kernel.clone(),
+            kernel_format: loaded.format().map(|format| format.to_string()),
+            kernel_info: kernel_info,
+            kernel_signature: kernel_signature,
+            initrds: entry.initrds.clone(),
+            cmdline: cmdline,
+            devicetree: devicetree,
+            devicetree_overlays: entry.devicetree_overlays.clone(),
+            backend: backend,
+            invocation: invocation,
+            fallback: fallback,
+        })
+    }
+
+    /// Boot the entry, falling back to ONERROR and then to the fallback
+    /// entries from options if loading or executing fails. Returns error
+    /// only if all of them failed, and success only if the kernel is not
+    /// executed immediately, see `ExecMode`. There is no fallback once the
+    /// system was shut down for executing the kernel.
+    pub fn boot_with_options(&self, entry: &bootconf::BootEntry,
+                             options: &BootOptions)
+            -> Result<(), KexLinuxError> {
+        let mut backend = options.backend;
+        for (index, entry) in self.boot_chain(entry, &options.fallback)
+                .into_iter().enumerate() {
+            let entry = KexLinux::edit_entry(entry, options);
+            if index > 0 {
+                info!("Falling back to \"{}\"...", entry.name);
+
+                // Do not leave previous kernel half-loaded.
+                if let Err(_) = KexLinux::unload(backend) {
+                    warn!("Unable to unload previous kernel");
+                }
+            }
+
+            backend = match KexLinux::load_kernel(&entry, &self.verifier,
+                                                  options.backend) {
+                Ok(backend) => backend,
+                Err(_) => {
+                    error!("Unable to boot \"{}\"", entry.name);
+                    continue;
+                },
+            };
+            self.save_entry(&entry, options);
+            self.count_boot(&entry, options);
+
+            match (self.exec_loaded(options, backend), &options.exec,
+                   &options.shutdown) {
+                (Ok(_), _, _) => return Ok(()),
+                (Err(err), &ExecMode::Immediate, &Some(_)) => {
+                    error!("Unable to execute \"{}\" after shutdown, \
+                            processes are stopped and filesystems unmounted, \
+                            not falling back", entry.name);
+                    return Err(err);
+                },
+                (Err(_), _, _) => error!("Unable to boot \"{}\"", entry.name),
+            }
+        }
+
+        Err(KexLinuxError{})
+    }
+}
+
+#[test]
+fn loads_devicetree_with_kexec_tool() {
+    let entry = &bootconf::test_conf().entries[0];
+    let select = |backend, devicetree| {
+        KexLinux::select_backend(entry, backend, devicetree).ok()
+    };
+    assert_eq!(select(kexec::Backend::default(), true),
+               Some(kexec::Backend::Tool));
+    assert_eq!(select(kexec::Backend::default(), false),
+               Some(kexec::Backend::default()));
+    assert_eq!(select(kexec::Backend::Tool, true), Some(kexec::Backend::Tool));
+    assert_eq!(select(kexec::Backend::Legacy, true), None);
+    assert_eq!(select(kexec::Backend::Legacy, false),
+               Some(kexec::Backend::Legacy));
 }
@@ -1,6 +1,40 @@
 #[macro_use] extern crate log;
+#[macro_use] extern crate serde_json;
 
 mod blockdev;
+mod bootcount;
+mod bootconf;
+mod check;
+mod cmdline;
+mod decompress;
+mod devicetree;
+mod initrd;
+mod inspect;
+mod kexec;
+mod kexec_legacy;
 mod kexlinux;
+mod menu;
+mod passwd;
+mod shutdown;
+mod signature;
+mod state;
+mod sysappend;
+mod syslinux;
+mod syslinux_extra;
 
-pub use kexlinux::{KexLinux, KexLinuxError};
+pub use bootcount::Counter;
+pub use bootconf::{BootConf, BootEntry, ConfSource, MenuItem, Registry,
+                   Selection, SerialConsole, Submenu};
+pub use cmdline::CmdlineEdit;
+pub use check::{Issue, Report, Severity};
+pub use inspect::{InspectError, KernelFormat, KernelInfo};
+pub use kexec::{Backend, KexecError};
+pub use kexlinux::{BootOptions, BootSource, DryRun, ExecMode, KexLinux,
+                   KexLinuxError};
+pub use menu::{Key, Menu, MenuError, Terminal};
+pub use shutdown::Shutdown;
+pub use signature::{Policy, SignatureError, Status, Verifier,
+                    DEFAULT_CERT_DIR};
+pub use state::{StateError, StateStore, DEFAULT_STATE_DIR};
+pub use sysappend::SysInfo;
+pub use syslinux::{SyslinuxConf, SyslinuxSource};
@@ -0,0 +1,1062 @@
+// Interactive text menu. Timeouts follow syslinux: any key press cancels
+// TIMEOUT, TOTALTIMEOUT can not be cancelled.
+
+use std;
+
+extern crate libc;
+
+use bootconf;
+use passwd;
+
+const PATH_TTY: &'static str = "/dev/tty";
+
+// How often countdown is redrawn.
+const TICK_MS: i32 = 100;
+// How long to wait for the rest of escape sequence.
+const ESCAPE_MS: i32 = 50;
+
+// TODO: Detailed errors.
+#[derive(Debug)]
+pub struct MenuError {}
+
+impl std::convert::From<std::io::Error> for MenuError {
+    fn from(_: std::io::Error) -> MenuError { MenuError{} }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Enter,
+    Tab,
+    Escape,
+    Backspace,
+    Char(char),
+    Other,
+}
+
+// Single terminal device in raw mode. Original mode is restored when
+// dropped.
+struct Device {
+    input: std::fs::File,
+    output: std::fs::File,
+    saved: Option<libc::termios>,
+}
+
+impl Device {
+    fn new(input: std::fs::File, output: std::fs::File,
+           configure: &Fn(&mut libc::termios))
+            -> Result<Device, MenuError> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = input.as_raw_fd();
+        let saved = match unsafe { libc::isatty(fd) } {
+            1 => {
+                let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+                if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+                    return Err(MenuError::from(std::io::Error::last_os_error()));
+                }
+
+                let mut raw = saved;
+                unsafe { libc::cfmakeraw(&mut raw) };
+                configure(&mut raw);
+                if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+                    return Err(MenuError::from(std::io::Error::last_os_error()));
+                }
+                Some(saved)
+            },
+            _ => None,
+        };
+
+        Ok(Device{
+            input: input,
+            output: output,
+            saved: saved,
+        })
+    }
+
+    fn read_byte(&mut self) -> Result<u8, MenuError> {
+        use std::io::Read;
+        let mut buf = [0u8; 1];
+        match self.input.read(&mut buf) {
+            Ok(0) => {
+                warn!("Terminal {:?} closed", self.input);
+                Err(MenuError{})
+            },
+            Ok(_) => Ok(buf[0]),
+            Err(err) => {
+                warn!("Unable to read from terminal {:?}: {}", self.input, err);
+                Err(MenuError::from(err))
+            },
+        }
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        if let Some(ref saved) = self.saved {
+            let fd = self.input.as_raw_fd();
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, saved) } != 0 {
+                error!("Unable to restore terminal mode");
+            }
+        }
+    }
+}
+
+/// One or more terminal devices in raw mode. Output is mirrored on all
+/// devices, input is accepted from whichever device sends it.
+pub struct Terminal {
+    devices: Vec<Device>,
+}
+
+impl std::fmt::Debug for Terminal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let devices: Vec<&std::fs::File> = self.devices.iter()
+            .map(|device| &device.output)
+            .collect();
+        write!(f, "Terminal {{ devices: {:?} }}", devices)
+    }
+}
+
+fn baud_to_speed(baud: u32) -> Option<libc::speed_t> {
+    Some(match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        921600 => libc::B921600,
+        _ => return None,
+    })
+}
+
+impl Terminal {
+    fn open(path: &std::path::Path) -> Result<std::fs::File, MenuError> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        match std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NOCTTY)
+                .open(path) {
+            Ok(file) => Ok(file),
+            Err(err) => {
+                warn!("Unable to open terminal {:?}: {}", path, err);
+                Err(MenuError::from(err))
+            },
+        }
+    }
+
+    /// Controlling terminal of the process.
+    pub fn open_tty() -> Result<Terminal, MenuError> {
+        let tty = try!(Terminal::open(std::path::Path::new(PATH_TTY)));
+        Terminal::from_files(try!(tty.try_clone()), tty)
+    }
+
+    /// Serial port with 8N1 framing at `baud`, optionally with RTS/CTS
+    /// flow control.
+    pub fn open_serial(path: &std::path::Path, baud: u32, flow_control: bool)
+            -> Result<Terminal, MenuError> {
+        let speed = match baud_to_speed(baud) {
+            Some(speed) => speed,
+            None => {
+                error!("Unsupported serial baud rate {}", baud);
+                return Err(MenuError{});
+            },
+        };
+
+        let serial = try!(Terminal::open(path));
+        let device = try!(Device::new(
+            try!(serial.try_clone()), serial, &|termios| {
+                unsafe {
+                    libc::cfsetispeed(termios, speed);
+                    libc::cfsetospeed(termios, speed);
+                }
+                termios.c_cflag |= libc::CREAD;
+                match flow_control {
+                    true => {
+                        termios.c_cflag |= libc::CRTSCTS;
+                        termios.c_cflag &= !libc::CLOCAL;
+                    },
+                    false => {
+                        termios.c_cflag &= !libc::CRTSCTS;
+                        termios.c_cflag |= libc::CLOCAL;
+                    },
+                }
+            }));
+        Ok(Terminal{
+            devices: vec![device],
+        })
+    }
+
+    /// Terminal from already opened files, e.g. pseudo-terminal.
+    pub fn from_files(input: std::fs::File, output: std::fs::File)
+            -> Result<Terminal, MenuError> {
+        Ok(Terminal{
+            devices: vec![try!(Device::new(input, output, &|_| ()))],
+        })
+    }
+
+    /// Mirror this terminal on devices of `other` too. Devices which are
+    /// already used, e.g. serial port which is also the controlling
+    /// terminal, are skipped.
+    pub fn join(&mut self, other: Terminal) {
+        use std::os::unix::fs::MetadataExt;
+
+        let rdev = |device: &Device| device.output.metadata().ok()
+            .map(|metadata| metadata.rdev())
+            .filter(|rdev| *rdev != 0);
+        for device in other.devices {
+            let rdev_other = rdev(&device);
+            if rdev_other.is_some() &&
+                    self.devices.iter().any(|own| rdev(own) == rdev_other) {
+                debug!("Terminal {:?} is already used", device.output);
+                continue;
+            }
+            self.devices.push(device);
+        }
+    }
+
+    /// Consoles configured for the menu: local console unless disabled,
+    /// and serial console if configured.
+    pub fn open_consoles(conf: &bootconf::BootConf)
+            -> Result<Terminal, MenuError> {
+        let mut terminals = Vec::new();
+        if conf.local_console {
+            if let Ok(terminal) = Terminal::open_tty() {
+                terminals.push(terminal);
+            }
+        }
+        if let Some(ref serial) = conf.serial {
+            if let Ok(terminal) = Terminal::open_serial(
+                    &serial.device, serial.baud, serial.flow_control) {
+                terminals.push(terminal);
+            }
+        }
+
+        let mut terminals = terminals.into_iter();
+        match terminals.next() {
+            Some(mut terminal) => {
+                for other in terminals {
+                    terminal.join(other);
+                }
+                Ok(terminal)
+            },
+            None => {
+                error!("No console to show menu on");
+                Err(MenuError{})
+            },
+        }
+    }
+
+    // Stop using device which failed or hung up. Fails when it was the
+    // last one.
+    fn remove(&mut self, index: usize) -> Result<(), MenuError> {
+        let device = self.devices.remove(index);
+        warn!("No longer using terminal {:?}", device.output);
+        match self.devices.is_empty() {
+            true => {
+                error!("No console left to show menu on");
+                Err(MenuError{})
+            },
+            false => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self, text: &str) -> Result<(), MenuError> {
+        use std::io::Write;
+
+        let mut index = 0;
+        while index < self.devices.len() {
+            let result = {
+                let output = &mut self.devices[index].output;
+                output.write_all(text.as_bytes()).and_then(|_| output.flush())
+            };
+            match result {
+                Ok(()) => index += 1,
+                Err(err) => {
+                    warn!("Unable to write to terminal {:?}: {}",
+                          self.devices[index].output, err);
+                    try!(self.remove(index));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Wait for input for up to `timeout_ms` (negative means forever) and
+    // return index of the device which has it.
+    fn poll(&self, timeout_ms: i32) -> Result<Option<usize>, MenuError> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut fds: Vec<libc::pollfd> = self.devices.iter()
+            .map(|device| libc::pollfd{
+                fd: device.input.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        loop {
+            match unsafe {
+                libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t,
+                           timeout_ms)
+            } {
+                -1 => {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(MenuError::from(err));
+                    }
+                },
+                0 => return Ok(None),
+                _ => return Ok(fds.iter().position(|fd| fd.revents != 0)),
+            }
+        }
+    }
+
+    fn read_escape(&mut self, index: usize) -> Result<Key, MenuError> {
+        match try!(self.poll(ESCAPE_MS)) {
+            Some(ready) if ready == index => (),
+            _ => return Ok(Key::Escape),
+        }
+        let device = &mut self.devices[index];
+        match try!(device.read_byte()) {
+            b'[' | b'O' => (),
+            _ => return Ok(Key::Other),
+        }
+
+        let mut seq = Vec::new();
+        loop {
+            let byte = try!(device.read_byte());
+            seq.push(byte);
+            if (byte as char).is_ascii_alphabetic() || byte == b'~' ||
+                    seq.len() > 8 {
+                break;
+            }
+        }
+
+        Ok(match &seq[..] {
+            b"A" => Key::Up,
+            b"B" => Key::Down,
+            b"C" => Key::Right,
+            b"D" => Key::Left,
+            b"H" | b"1~" => Key::Home,
+            b"F" | b"4~" => Key::End,
+            _ => Key::Other,
+        })
+    }
+
+    fn read_device_key(&mut self, index: usize) -> Result<Key, MenuError> {
+        Ok(match try!(self.devices[index].read_byte()) {
+            0x1b => try!(self.read_escape(index)),
+            b'\r' | b'\n' => Key::Enter,
+            b'\t' => Key::Tab,
+            0x7f | 0x08 => Key::Backspace,
+            byte if byte >= 0x20 && byte < 0x7f => Key::Char(byte as char),
+            _ => Key::Other,
+        })
+    }
+
+    /// Read key, waiting up to `timeout_ms` (negative means forever).
+    /// Devices which fail or hang up are dropped.
+    pub fn read_key(&mut self, timeout_ms: i32)
+            -> Result<Option<Key>, MenuError> {
+        loop {
+            let index = match try!(self.poll(timeout_ms)) {
+                Some(index) => index,
+                None => return Ok(None),
+            };
+
+            match self.read_device_key(index) {
+                Ok(key) => return Ok(Some(key)),
+                Err(_) => try!(self.remove(index)),
+            }
+        }
+    }
+}
+
+// Deadline which is `None` when disabled.
+fn deadline(start: std::time::Instant, seconds: Option<f64>)
+        -> Option<std::time::Instant> {
+    match seconds {
+        Some(seconds) if seconds > 0.0 => Some(
+            start + std::time::Duration::from_millis((seconds * 1000.0) as u64)),
+        _ => None,
+    }
+}
+
+fn remaining_ms(deadline: std::time::Instant) -> i32 {
+    let now = std::time::Instant::now();
+    match deadline > now {
+        true => {
+            let remaining = deadline - now;
+            (remaining.as_secs() * 1000 +
+             (remaining.subsec_nanos() / 1000000) as u64) as i32
+        },
+        false => 0,
+    }
+}
+
+enum Row {
+    Entry(String, String),
+    Separator,
+    Submenu(usize, String),
+}
+
+/// Boot menu for the configuration.
+pub struct Menu<'a> {
+    conf: &'a bootconf::BootConf,
+    items: Vec<bootconf::MenuItem>,
+
+    // Indexes of opened submenus.
+    path: Vec<usize>,
+    selected: usize,
+
+    timeout: Option<std::time::Instant>,
+    total_timeout: Option<std::time::Instant>,
+
+    // Shown instead of the status line once.
+    notice: Option<String>,
+
+    // Shown next to entry titles, by entry name.
+    notes: std::collections::HashMap<String, String>,
+    annotator: Option<&'a Fn(&bootconf::BootEntry) -> String>,
+}
+
+impl<'a> Menu<'a> {
+    pub fn new(conf: &'a bootconf::BootConf) -> Menu<'a> {
+        let items = match conf.menu.is_empty() {
+            true => conf.entries.iter()
+                .map(|entry| bootconf::MenuItem::Entry(entry.name.clone()))
+                .collect(),
+            false => conf.menu.clone(),
+        };
+
+        let mut menu = Menu{
+            conf: conf,
+            items: items,
+
+            path: Vec::new(),
+            selected: 0,
+
+            timeout: None,
+            total_timeout: None,
+
+            notice: None,
+
+            notes: std::collections::HashMap::new(),
+            annotator: None,
+        };
+        let initial = conf.default_name.clone()
+            .unwrap_or(conf.ontimeout_name.clone());
+        menu.highlight(&initial);
+        menu
+    }
+
+    /// Highlight entry on the top level, if it is there.
+    pub fn highlight(&mut self, name: &str) {
+        self.path.clear();
+        let rows = self.rows();
+        match rows.iter().position(|row| match *row {
+            Row::Entry(ref entry_name, _) => entry_name == name,
+            _ => false,
+        }) {
+            Some(index) => self.selected = index,
+            None => self.selected = self.next_selectable(&rows, 0, 1),
+        }
+    }
+
+    /// Show note from `annotator` next to entry titles, e.g. "signed".
+    /// Notes are made only for entries which get highlighted, as making
+    /// them may be slow.
+    pub fn set_annotator(&mut self,
+                         annotator: &'a Fn(&bootconf::BootEntry) -> String) {
+        self.annotator = Some(annotator);
+    }
+
+    fn annotate_selected(&mut self) {
+        let annotator = match self.annotator {
+            Some(annotator) => annotator,
+            None => return,
+        };
+        let name = match self.rows().get(self.selected) {
+            Some(&Row::Entry(ref name, _)) => name.clone(),
+            _ => return,
+        };
+        if !self.notes.contains_key(&name) {
+            if let Some(entry) = self.conf.get(&name) {
+                self.notes.insert(name, annotator(entry));
+            }
+        }
+    }
+
+    fn current_items(&self) -> &[bootconf::MenuItem] {
+        let mut items = &self.items[..];
+        for index in &self.path {
+            items = match items[*index] {
+                bootconf::MenuItem::Submenu(ref submenu) => &submenu.items,
+                _ => panic!("This will never happen"),
+            };
+        }
+        items
+    }
+
+    fn current_title(&self) -> Option<String> {
+        let mut title = self.conf.title.clone();
+        let mut items = &self.items[..];
+        for index in &self.path {
+            if let bootconf::MenuItem::Submenu(ref submenu) = items[*index] {
+                title = submenu.title.clone().or(submenu.label.clone());
+                items = &submenu.items;
+            }
+        }
+        title
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        self.current_items().iter().enumerate()
+            .filter_map(|(index, item)| match *item {
+                bootconf::MenuItem::Entry(ref name) => {
+                    self.conf.get(name)
+                        .filter(|entry| !entry.hidden)
+                        .map(|entry| Row::Entry(
+                            entry.name.clone(),
+                            entry.title.clone()
+                                .unwrap_or(entry.name.clone())))
+                },
+                bootconf::MenuItem::Separator => Some(Row::Separator),
+                bootconf::MenuItem::Submenu(ref submenu) => {
+                    Some(Row::Submenu(index, submenu.label.clone()
+                        .or(submenu.title.clone())
+                        .unwrap_or(String::from("..."))))
+                },
+            })
+            .collect()
+    }
+
+    fn is_selectable(&self, row: &Row) -> bool {
+        match *row {
+            Row::Entry(ref name, _) => self.conf.get(name)
+                .map(|entry| !entry.disabled)
+                .unwrap_or(false),
+            Row::Separator => false,
+            Row::Submenu(_, _) => true,
+        }
+    }
+
+    // Nearest selectable row starting from `from` in direction `step`.
+    fn next_selectable(&self, rows: &[Row], from: usize, step: isize)
+            -> usize {
+        let mut index = from as isize;
+        while index >= 0 && (index as usize) < rows.len() {
+            if self.is_selectable(&rows[index as usize]) {
+                return index as usize;
+            }
+            index += step;
+        }
+        self.selected
+    }
+
+    fn draw(&self, terminal: &mut Terminal, status: &str)
+            -> Result<(), MenuError> {
+        let mut screen = String::from("\x1b[2J\x1b[H");
+        if let Some(title) = self.current_title() {
+            screen.push_str(&format!("  {}\r\n\r\n", title));
+        }
+
+        for (index, row) in self.rows().iter().enumerate() {
+            let (text, selectable) = match *row {
+                Row::Entry(ref name, ref title) => {
+                    let text = match self.notes.get(name) {
+                        Some(note) => format!("{} [{}]", title, note),
+                        None => title.clone(),
+                    };
+                    (text, self.is_selectable(row))
+                },
+                Row::Separator => (String::new(), false),
+                Row::Submenu(_, ref label) => (format!("{} >", label), true),
+            };
+            match (index == self.selected, selectable) {
+                (true, _) => screen.push_str(
+                    &format!("\x1b[7m> {}\x1b[0m\r\n", text)),
+                (false, true) => screen.push_str(&format!("  {}\r\n", text)),
+                (false, false) => screen.push_str(
+                    &format!("\x1b[2m  {}\x1b[0m\r\n", text)),
+            }
+        }
+
+        if let Some(&Row::Entry(ref name, _)) = self.rows().get(self.selected) {
+            if let Some(help) = self.conf.get(name)
+                    .and_then(|entry| entry.help.as_ref()) {
+                screen.push_str(&format!("\r\n{}\r\n",
+                                         help.replace("\n", "\r\n")));
+            }
+        }
+
+        screen.push_str(&format!("\r\n{}", status));
+        terminal.write(&screen)
+    }
+
+    fn status(&mut self) -> String {
+        if let Some(notice) = self.notice.take() {
+            return notice;
+        }
+
+        let now = std::time::Instant::now();
+        let deadline = match (self.timeout, self.total_timeout) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+        match deadline {
+            Some(deadline) if deadline > now => {
+                let remaining = deadline - now;
+                format!("Automatic boot in {} seconds...",
+                        remaining.as_secs() + 1)
+            },
+            _ => String::from(
+                "Use arrow keys to select, Enter to boot, Tab to edit."),
+        }
+    }
+
+    // Milliseconds until the nearest deadline, `None` if there is none.
+    fn until_deadline(&self) -> Option<i32> {
+        let deadlines: Vec<std::time::Instant> = self.timeout.iter()
+            .chain(self.total_timeout.iter())
+            .cloned()
+            .collect();
+        deadlines.into_iter().min().map(remaining_ms)
+    }
+
+    // Wait for a key until the nearest deadline. While a deadline is
+    // pending, `prompt` is redrawn on every tick, or the status line when
+    // there is no prompt.
+    fn wait_key(&mut self, terminal: &mut Terminal, prompt: Option<&str>)
+            -> Result<Option<Key>, MenuError> {
+        loop {
+            let timeout_ms = match self.until_deadline() {
+                Some(0) => return Ok(None),
+                Some(ms) => std::cmp::min(ms, TICK_MS),
+                None => -1,
+            };
+
+            match try!(terminal.read_key(timeout_ms)) {
+                Some(key) => {
+                    // Any key cancels TIMEOUT, but not TOTALTIMEOUT.
+                    self.timeout = None;
+                    return Ok(Some(key));
+                },
+                None => match (self.timeout.or(self.total_timeout), prompt) {
+                    (Some(_), Some(prompt)) => try!(terminal.write(prompt)),
+                    (Some(_), None) => {
+                        let status = self.status();
+                        try!(terminal.write(&format!("\r\x1b[K{}", status)));
+                    },
+                    (None, _) => (),
+                },
+            }
+        }
+    }
+
+    // Hashes accepted to boot entry or open submenu protected by `passwd`.
+    fn select_hashes(&self, passwd: Option<String>) -> Vec<String> {
+        match passwd {
+            Some(passwd) => std::iter::once(passwd)
+                .chain(self.conf.master_passwd.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Hashes accepted to edit command line of the entry. Like in syslinux,
+    // master password, if set, is required for editing.
+    fn edit_hashes(&self, entry: &bootconf::BootEntry) -> Vec<String> {
+        match self.conf.master_passwd {
+            Some(ref master_passwd) => vec![master_passwd.clone()],
+            None => entry.passwd.iter().cloned().collect(),
+        }
+    }
+
+    // Ask for password if any of `hashes` is required. Returns `None` when
+    // TOTALTIMEOUT expired.
+    fn unlock(&mut self, terminal: &mut Terminal, hashes: &[String])
+            -> Result<Option<bool>, MenuError> {
+        if hashes.is_empty() {
+            return Ok(Some(true));
+        }
+
+        let mut password = String::new();
+        loop {
+            let prompt = format!("\r\x1b[KPassword: {}",
+                                 "*".repeat(password.chars().count()));
+            try!(terminal.write(&prompt));
+
+            match try!(self.wait_key(terminal, Some(&prompt))) {
+                None => return Ok(None),
+                Some(Key::Enter) => break,
+                Some(Key::Escape) => return Ok(Some(false)),
+                Some(Key::Backspace) => {
+                    password.pop();
+                },
+                Some(Key::Char(c)) => password.push(c),
+                Some(_) => (),
+            }
+        }
+
+        match hashes.iter().any(|hash| passwd::verify(hash, &password)) {
+            true => Ok(Some(true)),
+            false => {
+                warn!("Incorrect password entered in menu");
+                self.notice = Some(String::from("Incorrect password."));
+                Ok(Some(false))
+            },
+        }
+    }
+
+    fn submenu_passwd(&self, index: usize) -> Option<String> {
+        match self.current_items().get(index) {
+            Some(&bootconf::MenuItem::Submenu(ref submenu)) => {
+                submenu.passwd.clone()
+            },
+            _ => None,
+        }
+    }
+
+    fn edit(&mut self, terminal: &mut Terminal, entry: &bootconf::BootEntry)
+            -> Result<Option<Option<bootconf::BootEntry>>, MenuError> {
+        let mut line: Vec<char> = entry.cmdline.clone().unwrap_or_default()
+            .chars().collect();
+        let mut cursor = line.len();
+
+        loop {
+            let text: String = line.iter().collect();
+            let back = line.len() - cursor;
+            let prompt = format!(
+                "\r\x1b[K{}> {}{}", entry.name, text,
+                match back {
+                    0 => String::new(),
+                    back => format!("\x1b[{}D", back),
+                });
+            try!(terminal.write(&prompt));
+
+            match try!(self.wait_key(terminal, Some(&prompt))) {
+                // TOTALTIMEOUT expired.
+                None => return Ok(None),
+
+                Some(Key::Enter) => {
+                    let mut entry = entry.clone();
+                    let text: String = line.iter().collect();
+                    entry.cmdline = match text.trim().is_empty() {
+                        true => None,
+                        false => Some(text),
+                    };
+                    return Ok(Some(Some(entry)));
+                },
+                Some(Key::Escape) => return Ok(Some(None)),
+                Some(Key::Left) if cursor > 0 => cursor -= 1,
+                Some(Key::Right) if cursor < line.len() => cursor += 1,
+                Some(Key::Home) => cursor = 0,
+                Some(Key::End) => cursor = line.len(),
+                Some(Key::Backspace) if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                },
+                Some(Key::Char(c)) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                },
+                Some(_) => (),
+            }
+        }
+    }
+
+    /// Show menu and return entry chosen by user, with command line
+    /// possibly edited. Returns the ONTIMEOUT entry when time is out.
+    pub fn run(&mut self, terminal: &mut Terminal)
+            -> Result<bootconf::BootEntry, MenuError> {
+        let start = std::time::Instant::now();
+        self.timeout = deadline(start, self.conf.timeout);
+        self.total_timeout = deadline(start, self.conf.total_timeout);
+
+        let result = self.run_loop(terminal);
+        try!(terminal.write("\x1b[0m\r\n"));
+        result
+    }
+
+    fn run_loop(&mut self, terminal: &mut Terminal)
+            -> Result<bootconf::BootEntry, MenuError> {
+        loop {
+            self.annotate_selected();
+            let status = self.status();
+            try!(self.draw(terminal, &status));
+
+            let rows = self.rows();
+            let key = match try!(self.wait_key(terminal, None)) {
+                Some(key) => key,
+                None => {
+                    info!("Timeout, booting \"{}\"", self.conf.ontimeout_name);
+                    return Ok(self.conf.ontimeout().clone());
+                },
+            };
+
+            match key {
+                Key::Up if self.selected > 0 => {
+                    self.selected = self.next_selectable(
+                        &rows, self.selected - 1, -1)
+                },
+                Key::Down => {
+                    self.selected = self.next_selectable(
+                        &rows, self.selected + 1, 1)
+                },
+                Key::Home => {
+                    self.selected = self.next_selectable(&rows, 0, 1)
+                },
+                Key::End if !rows.is_empty() => {
+                    self.selected = self.next_selectable(
+                        &rows, rows.len() - 1, -1)
+                },
+
+                Key::Escape | Key::Left if !self.path.is_empty() => {
+                    let index = self.path.pop().unwrap();
+                    let rows = self.rows();
+                    self.selected = rows.iter()
+                        .position(|row| match *row {
+                            Row::Submenu(i, _) => i == index,
+                            _ => false,
+                        })
+                        .unwrap_or(0);
+                },
+
+                Key::Enter | Key::Right | Key::Tab => {
+                    match rows.get(self.selected) {
+                        Some(&Row::Submenu(index, _)) if key != Key::Tab => {
+                            let hashes = self.select_hashes(
+                                self.submenu_passwd(index));
+                            match try!(self.unlock(terminal, &hashes)) {
+                                Some(true) => {
+                                    self.path.push(index);
+                                    let rows = self.rows();
+                                    self.selected = 0;
+                                    self.selected = self.next_selectable(
+                                        &rows, 0, 1);
+                                },
+                                Some(false) => (),
+                                None => return Ok(
+                                    self.conf.ontimeout().clone()),
+                            }
+                        },
+
+                        Some(&Row::Entry(_, _)) if key == Key::Right => (),
+
+                        Some(&Row::Entry(ref name, _)) => {
+                            let entry = self.conf.get(name).unwrap();
+                            let hashes = match key {
+                                Key::Tab => self.edit_hashes(entry),
+                                _ => self.select_hashes(entry.passwd.clone()),
+                            };
+                            match try!(self.unlock(terminal, &hashes)) {
+                                Some(true) => (),
+                                Some(false) => continue,
+                                None => return Ok(
+                                    self.conf.ontimeout().clone()),
+                            }
+
+                            match key {
+                                Key::Tab => {
+                                    match try!(self.edit(terminal, entry)) {
+                                        Some(Some(entry)) => return Ok(entry),
+                                        Some(None) => (),  // Edit cancelled.
+                                        None => return Ok(
+                                            self.conf.ontimeout().clone()),
+                                    }
+                                },
+                                _ => return Ok(entry.clone()),
+                            }
+                        },
+
+                        _ => (),
+                    }
+                },
+
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn open_pty() -> (std::fs::File, std::fs::File) {
+    use std::os::unix::io::FromRawFd;
+
+    let mut master = 0;
+    let mut slave = 0;
+    assert_eq!(unsafe {
+        libc::openpty(&mut master, &mut slave, std::ptr::null_mut(),
+                      std::ptr::null(), std::ptr::null())
+    }, 0);
+    unsafe {
+        (std::fs::File::from_raw_fd(master), std::fs::File::from_raw_fd(slave))
+    }
+}
+
+#[test]
+fn selects_and_edits_through_pty() {
+    use std::io::Write;
+
+    let conf = bootconf::test_conf();
+    let (mut master, slave) = open_pty();
+    let mut terminal = Terminal::from_files(slave.try_clone().unwrap(),
+                                            slave).unwrap();
+
+    master.write_all(b"\x1b[B\r").unwrap();
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    assert_eq!(entry.name, "rescue");
+
+    master.write_all(b"\t single\r").unwrap();
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    assert_eq!(entry.name, "linux");
+    assert_eq!(entry.cmdline, Some(String::from("quiet single")));
+}
+
+#[test]
+fn keeps_editor_while_total_timeout_is_pending() {
+    use std::io::{Read, Write};
+
+    let mut conf = bootconf::test_conf();
+    conf.total_timeout = Some(10.0);
+
+    let (mut master, slave) = open_pty();
+    let mut terminal = Terminal::from_files(slave.try_clone().unwrap(),
+                                            slave).unwrap();
+
+    let mut input = master.try_clone().unwrap();
+    let writer = std::thread::spawn(move || {
+        input.write_all(b"\t").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(350));
+        input.write_all(b" single\r").unwrap();
+    });
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    writer.join().unwrap();
+    assert_eq!(entry.cmdline, Some(String::from("quiet single")));
+
+    let mut buf = [0u8; 65536];
+    let len = master.read(&mut buf).unwrap();
+    let output = String::from_utf8_lossy(&buf[..len]);
+    let editing = output.find("linux> quiet").unwrap();
+    assert!(!output[editing..].contains("Automatic boot"));
+}
+
+#[test]
+fn annotates_highlighted_entries_only() {
+    use std::io::{Read, Write};
+
+    let conf = bootconf::test_conf();
+    let (mut master, slave) = open_pty();
+    let mut terminal = Terminal::from_files(slave.try_clone().unwrap(),
+                                            slave).unwrap();
+
+    let annotated = std::cell::RefCell::new(Vec::new());
+    let annotator = |entry: &bootconf::BootEntry| {
+        annotated.borrow_mut().push(entry.name.clone());
+        String::from("signed")
+    };
+    master.write_all(b"\r").unwrap();
+    let mut menu = Menu::new(&conf);
+    menu.set_annotator(&annotator);
+    assert_eq!(menu.run(&mut terminal).unwrap().name, "linux");
+    assert_eq!(*annotated.borrow(), vec![String::from("linux")]);
+
+    let mut buf = [0u8; 4096];
+    let len = master.read(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..len]).contains("[signed]"));
+}
+
+#[test]
+fn boots_ontimeout_entry_on_timeout() {
+    let mut conf = bootconf::test_conf();
+    conf.timeout = Some(0.2);
+    conf.ontimeout_name = String::from("rescue");
+
+    let (_master, slave) = open_pty();
+    let mut terminal = Terminal::from_files(slave.try_clone().unwrap(),
+                                            slave).unwrap();
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    assert_eq!(entry.name, "rescue");
+}
+
+#[test]
+fn asks_for_password() {
+    use std::io::Write;
+
+    let mut conf = bootconf::test_conf();
+    conf.entries[1].passwd = Some(String::from("rescue"));
+    conf.master_passwd = Some(String::from("master"));
+
+    let (mut master, slave) = open_pty();
+    let mut terminal = Terminal::from_files(slave.try_clone().unwrap(),
+                                            slave).unwrap();
+
+    // Wrong password returns to the menu.
+    master.write_all(b"\x1b[B\rwrong\r\rrescue\r").unwrap();
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    assert_eq!(entry.name, "rescue");
+
+    // Editing needs the master password even for unprotected entries.
+    master.write_all(b"\trescue\r\tmaster\r single\r").unwrap();
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    assert_eq!(entry.cmdline, Some(String::from("quiet single")));
+}
+
+#[test]
+fn mirrors_output_and_reads_any_device() {
+    use std::io::{Read, Write};
+
+    let conf = bootconf::test_conf();
+    let (mut master1, slave1) = open_pty();
+    let (mut master2, slave2) = open_pty();
+    let mut terminal = Terminal::from_files(slave1.try_clone().unwrap(),
+                                            slave1).unwrap();
+    terminal.join(Terminal::from_files(slave2.try_clone().unwrap(),
+                                       slave2).unwrap());
+
+    master2.write_all(b"\x1b[B\r").unwrap();
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    assert_eq!(entry.name, "rescue");
+
+    for master in [&mut master1, &mut master2].iter_mut() {
+        let mut buf = [0u8; 4096];
+        let len = master.read(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..len]).contains("rescue"));
+    }
+}
+
+#[test]
+fn drops_hung_up_devices() {
+    use std::io::Write;
+
+    let conf = bootconf::test_conf();
+    let (master1, slave1) = open_pty();
+    let (mut master2, slave2) = open_pty();
+    let mut terminal = Terminal::from_files(slave1.try_clone().unwrap(),
+                                            slave1).unwrap();
+    terminal.join(Terminal::from_files(slave2.try_clone().unwrap(),
+                                       slave2).unwrap());
+
+    drop(master1);
+    master2.write_all(b"\x1b[B\r").unwrap();
+    let entry = Menu::new(&conf).run(&mut terminal).unwrap();
+    assert_eq!(entry.name, "rescue");
+    assert_eq!(terminal.devices.len(), 1);
+
+    drop(master2);
+    assert!(Menu::new(&conf).run(&mut terminal).is_err());
+}
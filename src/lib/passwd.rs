@@ -0,0 +1,251 @@
+// Verification of syslinux MENU PASSWD / MENU MASTER PASSWD values. Like
+// com32/menu/passwd.c: "$4$salt$base64(sha1(salt + password))" is checked
+// here, as are MD5 ("$1$") and SHA-2 ("$5$", "$6$") crypt(3) formats,
+// anything not starting with '$' is a plain text password.
+
+use std;
+
+extern crate openssl;
+
+const BASE64_CHARS: &'static [u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// crypt(3) uses its own base64 alphabet, least significant bits first.
+const CRYPT_CHARS: &'static [u8] =
+    b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// Order in which digest bytes are encoded, in groups of three.
+const MD5_ORDER: &'static [usize] = &[
+    0, 6, 12, 1, 7, 13, 2, 8, 14, 3, 9, 15, 4, 10, 5, 11];
+const SHA256_ORDER: &'static [usize] = &[
+    0, 10, 20, 21, 1, 11, 12, 22, 2, 3, 13, 23, 24, 4, 14, 15, 25, 5,
+    6, 16, 26, 27, 7, 17, 18, 28, 8, 9, 19, 29, 31, 30];
+const SHA512_ORDER: &'static [usize] = &[
+    0, 21, 42, 22, 43, 1, 44, 2, 23, 3, 24, 45, 25, 46, 4, 47, 5, 26,
+    6, 27, 48, 28, 49, 7, 50, 8, 29, 9, 30, 51, 31, 52, 10, 53, 11, 32,
+    12, 33, 54, 34, 55, 13, 56, 14, 35, 15, 36, 57, 37, 58, 16, 59, 17, 38,
+    18, 39, 60, 40, 61, 19, 62, 20, 41, 63];
+
+fn digest(kind: openssl::hash::MessageDigest, data: &[u8]) -> Vec<u8> {
+    match openssl::hash::hash(kind, data) {
+        Ok(digest) => digest.to_vec(),
+        Err(err) => {
+            error!("Failed to hash password: {}", err);
+            Vec::new()
+        },
+    }
+}
+
+fn crypt_base64(digest: &[u8], order: &[usize]) -> String {
+    let mut result = String::new();
+    for group in order.chunks(3) {
+        let mut value = group.iter()
+            .fold(0u32, |acc, &i| acc << 8 | digest[i] as u32);
+        for _ in 0..group.len() + 1 {
+            result.push(CRYPT_CHARS[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    }
+    result
+}
+
+// Bytes of `data` repeated or truncated to `len` bytes.
+fn repeat_to(data: &[u8], len: usize) -> Vec<u8> {
+    data.iter().cloned().cycle().take(len).collect()
+}
+
+// "$1$salt$hash", see FreeBSD crypt-md5.c.
+fn md5_crypt(password: &[u8], hash: &str) -> String {
+    let md5 = openssl::hash::MessageDigest::md5();
+    let salt = hash[3..].split('$').next().unwrap_or("");
+    let salt = &salt.as_bytes()[..std::cmp::min(salt.len(), 8)];
+
+    let alternate = digest(md5, &[password, salt, password].concat());
+    if alternate.is_empty() {
+        return String::new();
+    }
+
+    let mut data = [password, b"$1$", salt].concat();
+    data.extend(repeat_to(&alternate, password.len()));
+    let mut i = password.len();
+    while i > 0 {
+        data.push(match i & 1 {
+            1 => 0,
+            _ => password.first().cloned().unwrap_or(0),
+        });
+        i >>= 1;
+    }
+    let mut result = digest(md5, &data);
+
+    for i in 0..1000 {
+        let mut data = Vec::new();
+        data.extend_from_slice(match i & 1 {
+            1 => password,
+            _ => &result,
+        });
+        if i % 3 != 0 {
+            data.extend_from_slice(salt);
+        }
+        if i % 7 != 0 {
+            data.extend_from_slice(password);
+        }
+        data.extend_from_slice(match i & 1 {
+            1 => &result,
+            _ => password,
+        });
+        result = digest(md5, &data);
+    }
+
+    format!("$1${}${}", String::from_utf8_lossy(salt),
+            crypt_base64(&result, MD5_ORDER))
+}
+
+// "$5$[rounds=N$]salt$hash" and "$6$...", see Ulrich Drepper's
+// "Unix crypt using SHA-256 and SHA-512".
+fn sha_crypt(password: &[u8], hash: &str) -> String {
+    let (kind, order) = match &hash[..3] {
+        "$5$" => (openssl::hash::MessageDigest::sha256(), SHA256_ORDER),
+        _ => (openssl::hash::MessageDigest::sha512(), SHA512_ORDER),
+    };
+
+    let mut salt = &hash[3..];
+    let mut rounds = None;
+    if salt.starts_with("rounds=") {
+        let end = salt.find('$').unwrap_or(salt.len());
+        rounds = salt[7..end].parse::<u32>().ok()
+            .map(|rounds| std::cmp::max(rounds, 1000));
+        salt = &salt[std::cmp::min(end + 1, salt.len())..];
+    }
+    let salt = salt.split('$').next().unwrap_or("");
+    let salt = &salt.as_bytes()[..std::cmp::min(salt.len(), 16)];
+
+    let alternate = digest(kind, &[password, salt, password].concat());
+    if alternate.is_empty() {
+        return String::new();
+    }
+
+    let mut data = [password, salt].concat();
+    data.extend(repeat_to(&alternate, password.len()));
+    let mut i = password.len();
+    while i > 0 {
+        data.extend_from_slice(match i & 1 {
+            1 => &alternate,
+            _ => password,
+        });
+        i >>= 1;
+    }
+    let mut result = digest(kind, &data);
+
+    let p = repeat_to(&digest(kind, &password.repeat(password.len())),
+                      password.len());
+    let s = repeat_to(&digest(kind, &salt.repeat(16 + result[0] as usize)),
+                      salt.len());
+
+    for i in 0..rounds.unwrap_or(5000) {
+        let mut data = Vec::new();
+        data.extend_from_slice(match i & 1 {
+            1 => &p,
+            _ => &result,
+        });
+        if i % 3 != 0 {
+            data.extend_from_slice(&s);
+        }
+        if i % 7 != 0 {
+            data.extend_from_slice(&p);
+        }
+        data.extend_from_slice(match i & 1 {
+            1 => &result,
+            _ => &p,
+        });
+        result = digest(kind, &data);
+    }
+
+    format!("{}{}{}${}", &hash[..3],
+            match rounds {
+                Some(rounds) => format!("rounds={}$", rounds),
+                None => String::new(),
+            },
+            String::from_utf8_lossy(salt), crypt_base64(&result, order))
+}
+
+/// Decode base64 up to the first character outside of the alphabet.
+pub fn unbase64(text: &str) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match BASE64_CHARS.iter().position(|&x| x == c) {
+            Some(value) => value as u32,
+            None => break,
+        };
+        acc = acc << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    result
+}
+
+fn verify_sha1(hash: &str, password: &str) -> bool {
+    // "$4$salt$hash$"
+    let rest = &hash[3..];
+    let (salt, encoded) = match rest.find('$') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+        None => return false,
+    };
+
+    let mut data = salt.as_bytes().to_vec();
+    data.extend_from_slice(password.as_bytes());
+    let expected = unbase64(encoded);
+    expected.len() >= 20 &&
+        equal(&expected[..20], &openssl::sha::sha1(&data))
+}
+
+// Constant time comparison, so that timing does not reveal how much of a
+// guess matches.
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
+/// Check password against MENU PASSWD value.
+pub fn verify(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$4$") {
+        verify_sha1(hash, password)
+    } else if hash.starts_with("$1$") {
+        equal(md5_crypt(password.as_bytes(), hash).as_bytes(),
+              hash.as_bytes())
+    } else if hash.starts_with("$5$") || hash.starts_with("$6$") {
+        equal(sha_crypt(password.as_bytes(), hash).as_bytes(),
+              hash.as_bytes())
+    } else if hash.starts_with('$') {
+        warn!("Unsupported password hash format \"{}\"",
+              hash.splitn(3, '$').take(2).collect::<Vec<_>>().join("$"));
+        false
+    } else {
+        equal(hash.as_bytes(), password.as_bytes())
+    }
+}
+
+#[test]
+fn verifies_syslinux_hashes() {
+    let hashes = [
+        "$1$saltsalt$9xy1btjgzLYfb7hivXtC//",
+        "$4$Wv3sSPYp$2hhHZesIZ59vm/InjgzElQvm7/0$",
+        "$5$saltsalt$0IyaXrmV7.sGNS6tirgqHLqX/G.FBvgkYA.lpPdS5sA",
+        "$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5kn\
+         V8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1",
+        "$1$$ysVNzQc4CTMkp5daOdZ.3/",
+        "$5$rounds=1000$saltsalt$eKLZU9t9OoPWrqOQsoTIKG0aYkZ5rGOoOQhiIvoSWX2",
+        "$6$rounds=1000$saltsaltsaltsalt$cv7no.30n.S75jcCNBQv6o7WVXQ5AaB1EpIe\
+         SKwsNupcnQDQ0DsukcAPRNjPumwKIBg2JK5ii9uw5enNnnA4N/",
+        "secret",
+    ];
+    for hash in hashes.iter() {
+        assert!(verify(hash, "secret"), "{}", hash);
+        assert!(!verify(hash, "Secret"), "{}", hash);
+    }
+    assert!(!verify("$2y$10$abcdefghijklmnopqrstuv", "secret"));
+}
@@ -0,0 +1,307 @@
+// Orderly shutdown before the kernel is executed, for kexlinux running as
+// the last stage of initramfs where nothing else stops processes and
+// unmounts filesystems. Failures are logged but never prevent the kexec.
+
+use std;
+
+extern crate libc;
+
+const PATH_PROC: &'static str = "/proc";
+const PATH_MOUNTS: &'static str = "/proc/self/mounts";
+const PATH_NET: &'static str = "/sys/class/net";
+
+const DEFAULT_KILL_TIMEOUT_MS: u64 = 5000;
+const POLL_MS: u64 = 100;
+
+// Flag of kernel threads in /proc/PID/stat.
+const PF_KTHREAD: u64 = 0x00200000;
+
+// API filesystems without data to flush, left mounted.
+const KEEP_FS_TYPES: &'static [&'static str] = &[
+    "autofs", "binfmt_misc", "bpf", "cgroup", "cgroup2", "configfs",
+    "debugfs", "devpts", "devtmpfs", "efivarfs", "fusectl", "hugetlbfs",
+    "mqueue", "proc", "pstore", "ramfs", "rootfs", "securityfs", "sysfs",
+    "tmpfs", "tracefs",
+];
+
+/// Pre-exec shutdown settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shutdown {
+    /// Time other processes get to exit after SIGTERM, before SIGKILL.
+    pub kill_timeout_ms: u64,
+    /// Bring network interfaces down.
+    pub network_down: bool,
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown{
+            kill_timeout_ms: DEFAULT_KILL_TIMEOUT_MS,
+            network_down: false,
+        }
+    }
+}
+
+// struct ifreq with ifr_flags.
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+fn read_to_string(path: &std::path::Path) -> Option<String> {
+    use std::io::Read;
+
+    let mut text = String::new();
+    match std::fs::File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text)) {
+        Ok(_) => Some(text),
+        Err(_) => None,
+    }
+}
+
+// Whether /proc/PID/stat line is of a live user space process, not a zombie
+// or kernel thread.
+fn is_user_process(stat: &str) -> bool {
+    // Fields after "(comm)": state, ppid, pgrp, session, tty_nr, tpgid,
+    // flags. Command name itself may contain spaces and parentheses.
+    let fields: Vec<&str> = match stat.rfind(')') {
+        Some(pos) => stat[pos + 1..].split_whitespace().collect(),
+        None => return false,
+    };
+    let flags: u64 = match fields.get(6).map(|flags| flags.parse()) {
+        Some(Ok(flags)) => flags,
+        _ => return false,
+    };
+    fields.first() != Some(&"Z") && flags & PF_KTHREAD == 0
+}
+
+// PIDs of user space processes other than init and this one.
+fn other_processes() -> Vec<libc::pid_t> {
+    let own = unsafe { libc::getpid() };
+    let dirs = match std::fs::read_dir(PATH_PROC) {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            warn!("Unable to list processes: {}", err);
+            return Vec::new();
+        },
+    };
+
+    dirs.filter_map(|dir| dir.ok())
+        .filter_map(|dir| dir.file_name().to_str()
+                    .and_then(|name| name.parse::<libc::pid_t>().ok()))
+        .filter(|&pid| pid != 1 && pid != own)
+        .filter(|&pid| {
+            read_to_string(&std::path::Path::new(PATH_PROC)
+                           .join(pid.to_string()).join("stat"))
+                .map(|stat| is_user_process(&stat))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn kill_processes(timeout_ms: u64) {
+    info!("Sending SIGTERM to all processes...");
+    unsafe { libc::kill(-1, libc::SIGTERM) };
+
+    let mut waited = 0;
+    while !other_processes().is_empty() && waited < timeout_ms {
+        std::thread::sleep(std::time::Duration::from_millis(POLL_MS));
+        waited += POLL_MS;
+    }
+
+    let left = other_processes();
+    if !left.is_empty() {
+        info!("Sending SIGKILL to {} processes...", left.len());
+        unsafe { libc::kill(-1, libc::SIGKILL) };
+    }
+}
+
+// Undo octal escapes of /proc/self/mounts, e.g. "\040" for space.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 4) {
+            Some(digits) if bytes[i] == b'\\' &&
+                    digits.iter().all(|&d| d >= b'0' && d <= b'7') => {
+                std::str::from_utf8(digits).ok()
+                    .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+            },
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                result.push(byte);
+                i += 4;
+            },
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+// Mount points with filesystem types, in order of mounting.
+fn parse_mounts(text: &str) -> Vec<(std::path::PathBuf, String)> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.len() >= 3 {
+                true => Some((std::path::PathBuf::from(unescape(fields[1])),
+                              String::from(fields[2]))),
+                false => None,
+            }
+        })
+        .collect()
+}
+
+fn umount_all() {
+    let mounts = match read_to_string(std::path::Path::new(PATH_MOUNTS)) {
+        Some(text) => parse_mounts(&text),
+        None => {
+            warn!("Unable to read {}", PATH_MOUNTS);
+            return;
+        },
+    };
+
+    // Most recent first, so nested mounts go before their parents.
+    for &(ref path, ref fs_type) in mounts.iter().rev() {
+        if KEEP_FS_TYPES.contains(&fs_type.as_str()) {
+            continue;
+        }
+        let c_path = match path.to_str()
+                .and_then(|path| std::ffi::CString::new(path).ok()) {
+            Some(c_path) => c_path,
+            None => continue,
+        };
+
+        let root = path == std::path::Path::new("/");
+        if !root && unsafe { libc::umount2(c_path.as_ptr(), 0) } == 0 {
+            debug!("Unmounted {:?}", path);
+            continue;
+        }
+        let result = unsafe {
+            libc::mount(std::ptr::null(), c_path.as_ptr(), std::ptr::null(),
+                        libc::MS_REMOUNT | libc::MS_RDONLY, std::ptr::null())
+        };
+        match result {
+            0 => debug!("Remounted {:?} read-only", path),
+            _ => warn!("Unable to unmount or remount read-only {:?}: {}",
+                       path, std::io::Error::last_os_error()),
+        }
+    }
+}
+
+fn network_down() {
+    let dirs = match std::fs::read_dir(PATH_NET) {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            warn!("Unable to list network interfaces: {}", err);
+            return;
+        },
+    };
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if socket < 0 {
+        warn!("Unable to open socket: {}", std::io::Error::last_os_error());
+        return;
+    }
+
+    for name in dirs.filter_map(|dir| dir.ok())
+            .filter_map(|dir| dir.file_name().into_string().ok()) {
+        if name == "lo" || name.len() >= libc::IFNAMSIZ {
+            continue;
+        }
+        let mut request = IfReq{
+            name: [0; libc::IFNAMSIZ],
+            flags: 0,
+            _pad: [0; 22],
+        };
+        for (dst, src) in request.name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+
+        let result = unsafe {
+            if libc::ioctl(socket, libc::SIOCGIFFLAGS as _, &mut request) < 0 {
+                -1
+            } else {
+                request.flags &= !(libc::IFF_UP as libc::c_short);
+                libc::ioctl(socket, libc::SIOCSIFFLAGS as _, &request)
+            }
+        };
+        match result {
+            0 => debug!("Network interface {} is down", name),
+            _ => warn!("Unable to bring network interface {} down: {}",
+                       name, std::io::Error::last_os_error()),
+        }
+    }
+    unsafe { libc::close(socket) };
+}
+
+/// Steps of `run()`, as shown by dry run.
+pub fn describe(shutdown: &Shutdown) -> String {
+    let mut steps = vec!["sync", "SIGTERM and SIGKILL to other processes",
+                         "unmount or remount read-only filesystems"];
+    if shutdown.network_down {
+        steps.push("network interfaces down");
+    }
+    steps.push("sync");
+    format!("shutdown: {}", steps.join(", "))
+}
+
+/// Sync, stop other processes, unmount filesystems and optionally bring
+/// network down. Mounts held by this process should be released first.
+pub fn run(shutdown: &Shutdown) {
+    info!("Shutting down before kexec...");
+    unsafe {
+        libc::sync();
+        // Do not go down with the session of a killed parent.
+        libc::signal(libc::SIGHUP, libc::SIG_IGN);
+    }
+
+    kill_processes(shutdown.kill_timeout_ms);
+    umount_all();
+    if shutdown.network_down {
+        network_down();
+    }
+    unsafe { libc::sync() };
+}
+
+#[test]
+fn parses_mounts() {
+    let text = "rootfs / rootfs rw 0 0\n\
+                /dev/sda1 /mnt/my\\040boot vfat ro,relatime 0 0\n\
+                proc /proc proc rw 0 0\n";
+    assert_eq!(parse_mounts(text), vec![
+        (std::path::PathBuf::from("/"), String::from("rootfs")),
+        (std::path::PathBuf::from("/mnt/my boot"), String::from("vfat")),
+        (std::path::PathBuf::from("/proc"), String::from("proc")),
+    ]);
+
+    assert_eq!(unescape("a\\134b"), "a\\b");
+    assert_eq!(unescape("tab\\011"), "tab\t");
+    assert_eq!(unescape("short\\04"), "short\\04");
+    assert_eq!(unescape("digits\\08x"), "digits\\08x");
+    assert_eq!(unescape("big\\777"), "big\\777");
+    assert_eq!(unescape("sign\\+12"), "sign\\+12");
+    assert_eq!(unescape("end\\"), "end\\");
+    assert_eq!(unescape("\\040\\040"), "  ");
+}
+
+#[test]
+fn finds_user_processes() {
+    assert!(is_user_process(
+        "1234 (bash) S 1 1234 1234 34816 1234 4194304 532 0 0 0"));
+    assert!(is_user_process(
+        "1235 (a) b (c)) R 1 1235 1235 0 -1 4194560 80 0 0 0"));
+    assert!(!is_user_process(
+        "2 (kthreadd) S 0 0 0 0 -1 2129984 0 0 0 0"));
+    assert!(!is_user_process(
+        "1236 (defunct) Z 1 1236 1236 0 -1 4227084 0 0 0 0"));
+    assert!(!is_user_process("1237 (truncated) S 1"));
+    assert!(!is_user_process("garbage"));
+}
@@ -0,0 +1,880 @@
+// Verification of kernel and configuration file signatures against trusted
+// X.509 certificates.
+// EFI stub kernels carry Authenticode signature as made by sbsign or pesign.
+// Other files need detached PKCS#7 signature in DER or PEM next to them,
+// e.g. made with:
+//
+//     openssl smime -sign -binary -outform DER -in vmlinuz \
+//         -signer cert.pem -inkey key.pem -out vmlinuz.sig
+
+use std;
+
+extern crate openssl;
+extern crate tempdir;
+
+/// Where trusted certificates are read from if none are given.
+pub const DEFAULT_CERT_DIR: &'static str = "/etc/kexlinux/certs";
+
+const SIGNATURE_SUFFIX: &'static str = ".sig";
+const PEM_BEGIN: &'static [u8] = b"-----BEGIN";
+
+// PE image, see "PE Format" in Windows documentation.
+const PE_MZ_MAGIC: &'static [u8] = b"MZ";
+const PE_OFFSET: usize = 0x3c;
+const PE_MAGIC: &'static [u8] = b"PE\0\0";
+// Offsets in COFF header.
+const PE_NUMBER_OF_SECTIONS: usize = 6;
+const PE_OPTIONAL_HEADER_SIZE: usize = 20;
+const PE_OPTIONAL_HEADER: usize = 24;
+const PE32_MAGIC: u64 = 0x10b;
+const PE32_PLUS_MAGIC: u64 = 0x20b;
+// Offsets in optional header.
+const PE_SIZE_OF_HEADERS: usize = 60;
+const PE_CHECKSUM: usize = 64;
+const PE32_DATA_DIRS: usize = 96;
+const PE32_PLUS_DATA_DIRS: usize = 112;
+const PE_CERT_TABLE_DIR: usize = 4;
+// Section table entries follow the optional header.
+const PE_SECTION_HEADER_SIZE: usize = 40;
+const PE_SECTION_RAW_SIZE: usize = 16;
+const PE_SECTION_RAW_POINTER: usize = 20;
+const WIN_CERT_HEADER_SIZE: usize = 8;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u64 = 2;
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_CONTEXT_0: u8 = 0xa0;
+// Contents of OBJECT IDENTIFIERs of digest algorithms.
+const OID_SHA1: &'static [u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OID_SHA256: &'static [u8] = &[
+    0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_SHA384: &'static [u8] = &[
+    0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+const OID_SHA512: &'static [u8] = &[
+    0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+// TODO: Detailed errors.
+#[derive(Debug)]
+pub struct SignatureError {}
+
+impl std::convert::From<std::io::Error> for SignatureError {
+    fn from(_: std::io::Error) -> SignatureError { SignatureError{} }
+}
+
+impl std::convert::From<openssl::error::ErrorStack> for SignatureError {
+    fn from(_: openssl::error::ErrorStack) -> SignatureError {
+        SignatureError{}
+    }
+}
+
+/// What to do with unsigned files and invalid signatures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Do not check signatures.
+    Off,
+    /// Check and log, but boot anyway.
+    Warn,
+    /// Refuse to boot.
+    Enforce,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::Off
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// Policy is off.
+    Unchecked,
+    /// Valid signature by the certificate with the name.
+    Valid(String),
+    Unsigned,
+    /// Invalid signature, with the reason.
+    Invalid(String),
+}
+
+impl Status {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Status::Valid(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Status::Unchecked => write!(f, "not checked"),
+            Status::Valid(ref signer) => write!(f, "signed by \"{}\"", signer),
+            Status::Unsigned => write!(f, "unsigned"),
+            Status::Invalid(ref reason) => {
+                write!(f, "invalid signature: {}", reason)
+            },
+        }
+    }
+}
+
+// DER element.
+#[derive(Debug, Clone, Copy)]
+struct Der {
+    tag: u8,
+    offset: usize,
+    // Contents.
+    start: usize,
+    end: usize,
+}
+
+impl Der {
+    fn parse(data: &[u8], offset: usize) -> Result<Der, String> {
+        let malformed = || String::from("malformed PKCS#7 signature");
+        let tag = try!(data.get(offset).cloned().ok_or_else(&malformed));
+        let first = try!(data.get(offset + 1).cloned()
+            .ok_or_else(&malformed));
+        let (length, header) = match first < 0x80 {
+            true => (first as usize, 2),
+            false => {
+                let count = (first & 0x7f) as usize;
+                let bytes = match (count, data.get(offset + 2..
+                                                   offset + 2 + count)) {
+                    (1..=4, Some(bytes)) => bytes,
+                    _ => return Err(malformed()),
+                };
+                (bytes.iter().fold(0, |value, &byte| value << 8 |
+                                   byte as usize), 2 + count)
+            },
+        };
+        let start = offset + header;
+        match start.checked_add(length) {
+            Some(end) if end <= data.len() => Ok(Der{
+                tag: tag,
+                offset: offset,
+                start: start,
+                end: end,
+            }),
+            _ => Err(malformed()),
+        }
+    }
+
+    fn children(&self, data: &[u8]) -> Result<Vec<Der>, String> {
+        let mut children = Vec::new();
+        let mut offset = self.start;
+        while offset < self.end {
+            let child = try!(Der::parse(&data[..self.end], offset));
+            offset = child.end;
+            children.push(child);
+        }
+        Ok(children)
+    }
+
+    // Child at `index` which should have the tag.
+    fn child(&self, data: &[u8], index: usize, tag: Option<u8>)
+            -> Result<Der, String> {
+        match try!(self.children(data)).get(index) {
+            Some(child) if tag.map(|tag| tag == child.tag).unwrap_or(true) => {
+                Ok(*child)
+            },
+            _ => Err(String::from("unexpected PKCS#7 signature structure")),
+        }
+    }
+
+    fn whole<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.offset..self.end]
+    }
+
+    fn contents<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.start..self.end]
+    }
+}
+
+fn der_encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    match contents.len() < 0x80 {
+        true => result.push(contents.len() as u8),
+        false => {
+            let length = (contents.len() as u64).to_be_bytes();
+            let skip = length.iter().position(|&byte| byte != 0).unwrap_or(7);
+            result.push(0x80 | (length.len() - skip) as u8);
+            result.extend_from_slice(&length[skip..]);
+        },
+    }
+    result.extend_from_slice(contents);
+    result
+}
+
+// Authenticode signature as detached PKCS#7 signature of its content, a
+// SpcIndirectDataContent with digest of the image.
+struct Authenticode {
+    detached: Vec<u8>,
+    // Signed data, contents of SpcIndirectDataContent without tag and length.
+    content: Vec<u8>,
+    digest: openssl::hash::MessageDigest,
+    image_digest: Vec<u8>,
+}
+
+fn parse_authenticode(data: &[u8]) -> Result<Authenticode, String> {
+    // ContentInfo { contentType, [0] SignedData { version,
+    // digestAlgorithms, contentInfo { contentType,
+    // [0] SpcIndirectDataContent }, ... } }
+    let info = try!(Der::parse(data, 0));
+    let content_type = try!(info.child(data, 0, None));
+    let explicit = try!(info.child(data, 1, Some(DER_CONTEXT_0)));
+    let signed = try!(explicit.child(data, 0, Some(DER_SEQUENCE)));
+    let signed_children = try!(signed.children(data));
+    let content_info = try!(signed.child(data, 2, Some(DER_SEQUENCE)));
+    let indirect = try!(try!(content_info.child(data, 1, Some(DER_CONTEXT_0)))
+                        .child(data, 0, Some(DER_SEQUENCE)));
+
+    // SpcIndirectDataContent { data, DigestInfo { AlgorithmIdentifier {
+    // algorithm, ... }, digest } }
+    let digest_info = try!(indirect.child(data, 1, Some(DER_SEQUENCE)));
+    let algorithm = try!(try!(digest_info.child(data, 0, Some(DER_SEQUENCE)))
+                         .child(data, 0, None));
+    let digest = match algorithm.contents(data) {
+        oid if oid == OID_SHA1 => openssl::hash::MessageDigest::sha1(),
+        oid if oid == OID_SHA256 => openssl::hash::MessageDigest::sha256(),
+        oid if oid == OID_SHA384 => openssl::hash::MessageDigest::sha384(),
+        oid if oid == OID_SHA512 => openssl::hash::MessageDigest::sha512(),
+        _ => return Err(String::from("unsupported digest algorithm")),
+    };
+    let image_digest = try!(digest_info.child(data, 1, None));
+
+    // Same SignedData without content.
+    let mut detached = Vec::new();
+    for (index, child) in signed_children.iter().enumerate() {
+        match index {
+            2 => detached.extend(der_encode(
+                DER_SEQUENCE,
+                try!(content_info.child(data, 0, None)).whole(data))),
+            _ => detached.extend_from_slice(child.whole(data)),
+        }
+    }
+    let mut detached_info = content_type.whole(data).to_vec();
+    detached_info.extend(der_encode(DER_CONTEXT_0,
+                                    &der_encode(DER_SEQUENCE, &detached)));
+
+    Ok(Authenticode{
+        detached: der_encode(DER_SEQUENCE, &detached_info),
+        content: indirect.contents(data).to_vec(),
+        digest: digest,
+        image_digest: image_digest.contents(data).to_vec(),
+    })
+}
+
+// Signed PE image.
+struct PeImage {
+    // Ranges covered by Authenticode digest, in order: headers except
+    // checksum and certificate table directory entry, section data by file
+    // offset, then data beyond the total size of both up to certificate
+    // table.
+    hashed: Vec<(usize, usize)>,
+    // PKCS#7 signatures from certificate table.
+    signatures: Vec<(usize, usize)>,
+}
+
+fn get_le(data: &[u8], offset: usize, size: usize) -> Result<u64, String> {
+    match offset.checked_add(size).and_then(|end| data.get(offset..end)) {
+        Some(bytes) => Ok(bytes.iter().rev()
+                          .fold(0, |value, &byte| value << 8 | byte as u64)),
+        None => Err(String::from("truncated PE image")),
+    }
+}
+
+// `None` for images which are not PE or not signed.
+fn parse_pe(data: &[u8]) -> Result<Option<PeImage>, String> {
+    if !data.starts_with(PE_MZ_MAGIC) {
+        return Ok(None);
+    }
+    let pe = try!(get_le(data, PE_OFFSET, 4)) as usize;
+    if data.get(pe..pe.saturating_add(4)) != Some(PE_MAGIC) {
+        return Ok(None);
+    }
+
+    let optional = pe + PE_OPTIONAL_HEADER;
+    let data_dirs = match try!(get_le(data, optional, 2)) {
+        PE32_MAGIC => optional + PE32_DATA_DIRS,
+        PE32_PLUS_MAGIC => optional + PE32_PLUS_DATA_DIRS,
+        _ => return Err(String::from("unknown PE optional header")),
+    };
+    // NumberOfRvaAndSizes precedes the directories.
+    if try!(get_le(data, data_dirs - 4, 4)) as usize <= PE_CERT_TABLE_DIR {
+        return Ok(None);
+    }
+    let cert_dir = data_dirs + PE_CERT_TABLE_DIR * 8;
+    let table = try!(get_le(data, cert_dir, 4)) as usize;
+    let table_size = try!(get_le(data, cert_dir + 4, 4)) as usize;
+    if table_size == 0 {
+        return Ok(None);
+    }
+    // Header ranges should be in order, and nothing may follow the table,
+    // as it would not be covered by the signature.
+    let checksum = optional + PE_CHECKSUM;
+    let headers_end = try!(get_le(data, optional + PE_SIZE_OF_HEADERS, 4))
+        as usize;
+    if checksum + 4 > cert_dir || cert_dir + 8 > headers_end ||
+            headers_end > table {
+        return Err(String::from("certificate table overlaps PE header"));
+    }
+    match table.checked_add(table_size) {
+        Some(end) if end == data.len() => (),
+        Some(end) if end < data.len() => {
+            return Err(String::from("data after certificate table"));
+        },
+        _ => return Err(String::from("truncated certificate table")),
+    }
+    let table_end = data.len();
+
+    let sections = try!(get_le(data, pe + PE_NUMBER_OF_SECTIONS, 2)) as usize;
+    let section_table = optional + try!(
+        get_le(data, pe + PE_OPTIONAL_HEADER_SIZE, 2)) as usize;
+    let mut section_data = Vec::new();
+    for index in 0..sections {
+        let header = section_table + index * PE_SECTION_HEADER_SIZE;
+        let size = try!(get_le(data, header + PE_SECTION_RAW_SIZE, 4))
+            as usize;
+        let start = try!(get_le(data, header + PE_SECTION_RAW_POINTER, 4))
+            as usize;
+        if size == 0 {
+            continue;
+        }
+        match start.checked_add(size) {
+            Some(end) if end <= table => section_data.push((start, end)),
+            _ => return Err(String::from("section out of PE image")),
+        }
+    }
+    section_data.sort();
+    let hashed_size = section_data.iter().fold(
+        headers_end, |sum, &(start, end)| sum.saturating_add(end - start));
+
+    let mut hashed = vec![(0, checksum), (checksum + 4, cert_dir),
+                          (cert_dir + 8, headers_end)];
+    hashed.extend(section_data);
+    if hashed_size < table {
+        hashed.push((hashed_size, table));
+    }
+
+    let mut signatures = Vec::new();
+    let mut offset = table;
+    while offset + WIN_CERT_HEADER_SIZE <= table_end {
+        let length = try!(get_le(data, offset, 4)) as usize;
+        let cert_type = try!(get_le(data, offset + 6, 2));
+        if length < WIN_CERT_HEADER_SIZE || offset + length > table_end {
+            return Err(String::from("malformed certificate table"));
+        }
+        if cert_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            signatures.push((offset + WIN_CERT_HEADER_SIZE, offset + length));
+        }
+        // Entries are aligned to 8 bytes.
+        offset += (length + 7) & !7;
+    }
+
+    Ok(Some(PeImage{
+        hashed: hashed,
+        signatures: signatures,
+    }))
+}
+
+fn signature_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(SIGNATURE_SUFFIX);
+    std::path::PathBuf::from(signature)
+}
+
+fn read_file(path: &std::path::Path) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut data = Vec::new();
+    try!(try!(std::fs::File::open(path)).read_to_end(&mut data));
+    Ok(data)
+}
+
+fn read_certs(path: &std::path::Path)
+        -> Result<Vec<openssl::x509::X509>, String> {
+    let data = try!(read_file(path).map_err(|err| err.to_string()));
+    let certs = match data.starts_with(PEM_BEGIN) {
+        true => openssl::x509::X509::stack_from_pem(&data),
+        false => openssl::x509::X509::from_der(&data).map(|cert| vec![cert]),
+    };
+    certs.map_err(|err| err.to_string())
+}
+
+/// Checks signatures against trusted certificates according to policy.
+#[derive(Debug, Clone, Default)]
+pub struct Verifier {
+    policy: Policy,
+    certs: Vec<openssl::x509::X509>,
+}
+
+impl Verifier {
+    pub fn new(policy: Policy) -> Verifier {
+        Verifier{
+            policy: policy,
+            certs: Vec::new(),
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Trust certificates from PEM or DER file, or from all files in
+    /// directory, skipping those without certificates. Returns number of
+    /// certificates added.
+    pub fn add_certs(&mut self, path: &std::path::Path)
+            -> Result<usize, SignatureError> {
+        if !path.is_dir() {
+            return match read_certs(path) {
+                Ok(certs) => Ok(self.trust(certs)),
+                Err(err) => {
+                    error!("Unable to read certificates from {:?}: {}", path,
+                           err);
+                    Err(SignatureError{})
+                },
+            };
+        }
+
+        let mut paths: Vec<std::path::PathBuf> = try!(std::fs::read_dir(path))
+            .filter_map(|dir| dir.ok())
+            .map(|dir| dir.path())
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        let mut count = 0;
+        for path in &paths {
+            match read_certs(path) {
+                Ok(certs) => count += self.trust(certs),
+                Err(err) => warn!("Skipping {:?}: {}", path, err),
+            }
+        }
+        Ok(count)
+    }
+
+    fn trust(&mut self, certs: Vec<openssl::x509::X509>) -> usize {
+        let count = certs.len();
+        self.certs.extend(certs);
+        count
+    }
+
+    fn store(&self)
+            -> Result<openssl::x509::store::X509Store, SignatureError> {
+        let mut builder = try!(openssl::x509::store::X509StoreBuilder::new());
+        for cert in &self.certs {
+            try!(builder.add_cert(cert.clone()));
+        }
+        // Trusted certificates need not be self-signed roots, code signing
+        // certificates are not for S/MIME and clock is often wrong at boot.
+        let flags = openssl::x509::verify::X509VerifyFlags::PARTIAL_CHAIN |
+            openssl::x509::verify::X509VerifyFlags::NO_CHECK_TIME;
+        try!(builder.set_flags(flags));
+        try!(builder.set_purpose(openssl::x509::X509PurposeId::ANY));
+        Ok(builder.build())
+    }
+
+    // Name of the signer if signature of content is valid.
+    fn check_pkcs7(&self, pkcs7: &openssl::pkcs7::Pkcs7, content: &[u8])
+            -> Result<String, String> {
+        let store = try!(self.store().map_err(
+            |_| String::from("unable to set up trusted certificates")));
+        let certs = try!(openssl::stack::Stack::new()
+                         .map_err(|err| err.to_string()));
+        try!(pkcs7.verify(&certs, &store, Some(content), None,
+                          openssl::pkcs7::Pkcs7Flags::BINARY)
+             .map_err(|err| err.to_string()));
+
+        let signers = try!(pkcs7.signers(
+            &certs, openssl::pkcs7::Pkcs7Flags::empty())
+            .map_err(|err| err.to_string()));
+        let name = signers.iter().next()
+            .and_then(|signer| signer.subject_name()
+                      .entries_by_nid(openssl::nid::Nid::COMMONNAME).next())
+            .map(|entry| String::from_utf8_lossy(entry.data().as_slice())
+                 .into_owned())
+            .unwrap_or(String::from("unknown signer"));
+        Ok(name)
+    }
+
+    fn check_authenticode(&self, data: &[u8], image: &PeImage,
+                          signature: &[u8])
+            -> Result<String, String> {
+        let authenticode = try!(parse_authenticode(signature));
+        let mut hasher = try!(openssl::hash::Hasher::new(authenticode.digest)
+                              .map_err(|err| err.to_string()));
+        for &(start, end) in &image.hashed {
+            try!(hasher.update(&data[start..end])
+                 .map_err(|err| err.to_string()));
+        }
+        let digest = try!(hasher.finish().map_err(|err| err.to_string()));
+        if digest[..] != authenticode.image_digest[..] {
+            return Err(String::from("image digest does not match"));
+        }
+
+        let pkcs7 = try!(openssl::pkcs7::Pkcs7::from_der(
+            &authenticode.detached).map_err(|err| err.to_string()));
+        self.check_pkcs7(&pkcs7, &authenticode.content)
+    }
+
+    fn verify_authenticode(&self, data: &[u8], image: &PeImage) -> Status {
+        let mut last = String::from("no PKCS#7 signatures");
+        // Any valid signature is enough.
+        for &(start, end) in &image.signatures {
+            match self.check_authenticode(data, image, &data[start..end]) {
+                Ok(signer) => return Status::Valid(signer),
+                Err(reason) => last = reason,
+            }
+        }
+        Status::Invalid(last)
+    }
+
+    fn verify_detached(&self, path: &std::path::Path, data: &[u8]) -> Status {
+        let path = signature_path(path);
+        let signature = match read_file(&path) {
+            Ok(signature) => signature,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Status::Unsigned;
+            },
+            Err(err) => {
+                return Status::Invalid(format!("unable to read {:?}: {}",
+                                               path, err));
+            },
+        };
+        let pkcs7 = match signature.starts_with(PEM_BEGIN) {
+            true => openssl::pkcs7::Pkcs7::from_pem(&signature),
+            false => openssl::pkcs7::Pkcs7::from_der(&signature),
+        };
+        match pkcs7 {
+            Ok(pkcs7) => match self.check_pkcs7(&pkcs7, data) {
+                Ok(signer) => Status::Valid(signer),
+                Err(reason) => Status::Invalid(reason),
+            },
+            Err(err) => Status::Invalid(format!("unable to parse {:?}: {}",
+                                                path, err)),
+        }
+    }
+
+    /// Check file against detached signature in the file with ".sig"
+    /// appended to the name.
+    pub fn verify_file(&self, path: &std::path::Path) -> Status {
+        if self.policy == Policy::Off {
+            return Status::Unchecked;
+        }
+        match read_file(path) {
+            Ok(data) => self.verify_detached(path, &data),
+            Err(err) => Status::Invalid(format!("unable to read: {}", err)),
+        }
+    }
+
+    /// Like `verify_file()`, for file already read from `path`.
+    pub fn verify_file_data(&self, path: &std::path::Path, data: &[u8])
+            -> Status {
+        if self.policy == Policy::Off {
+            return Status::Unchecked;
+        }
+        self.verify_detached(path, data)
+    }
+
+    /// Check kernel: Authenticode signature of signed PE images, detached
+    /// signature otherwise.
+    pub fn verify_kernel(&self, path: &std::path::Path) -> Status {
+        if self.policy == Policy::Off {
+            return Status::Unchecked;
+        }
+        match read_file(path) {
+            Ok(data) => self.verify_kernel_data(path, &data),
+            Err(err) => Status::Invalid(format!("unable to read: {}", err)),
+        }
+    }
+
+    /// Like `verify_kernel()`, for kernel already read from `path`. Data
+    /// which is going to be loaded should be checked this way, the file
+    /// could change after being checked.
+    pub fn verify_kernel_data(&self, path: &std::path::Path, data: &[u8])
+            -> Status {
+        if self.policy == Policy::Off {
+            return Status::Unchecked;
+        }
+        match parse_pe(data) {
+            Ok(Some(image)) => self.verify_authenticode(data, &image),
+            Ok(None) => self.verify_detached(path, data),
+            Err(reason) => Status::Invalid(reason),
+        }
+    }
+
+    /// Apply policy to the result of verification of `what`.
+    pub fn enforce(&self, what: &str, status: &Status)
+            -> Result<(), SignatureError> {
+        match (self.policy, status) {
+            (Policy::Off, _) => Ok(()),
+            (_, &Status::Valid(_)) => {
+                info!("{}: {}", what, status);
+                Ok(())
+            },
+            (Policy::Warn, _) => {
+                warn!("{}: {}", what, status);
+                Ok(())
+            },
+            (_, _) => {
+                error!("{}: {}, refusing to boot", what, status);
+                Err(SignatureError{})
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_signer() -> (openssl::pkey::PKey<openssl::pkey::Private>,
+                     openssl::x509::X509) {
+    let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+    let key = openssl::pkey::PKey::from_rsa(rsa).unwrap();
+    let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "Test signer").unwrap();
+    let name = name.build();
+    let mut cert = openssl::x509::X509Builder::new().unwrap();
+    let serial = openssl::bn::BigNum::from_u32(1).unwrap();
+    cert.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&openssl::asn1::Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&openssl::asn1::Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+    (key, cert.build())
+}
+
+#[test]
+fn verifies_detached_signatures() {
+    use std::io::Write;
+
+    let (key, cert) = test_signer();
+    let dir = tempdir::TempDir::new("kexlinux-test").unwrap();
+    let kernel = dir.path().join("vmlinuz");
+    std::fs::File::create(&kernel).unwrap().write_all(b"kernel").unwrap();
+    let mut verifier = Verifier::new(Policy::Enforce);
+    verifier.certs.push(cert.clone());
+    assert_eq!(verifier.verify_kernel(&kernel), Status::Unsigned);
+    assert!(verifier.enforce("Kernel", &Status::Unsigned).is_err());
+
+    let signature = openssl::pkcs7::Pkcs7::sign(
+        &cert, &key, &openssl::stack::Stack::new().unwrap(), b"kernel",
+        openssl::pkcs7::Pkcs7Flags::DETACHED |
+        openssl::pkcs7::Pkcs7Flags::BINARY).unwrap();
+    std::fs::File::create(signature_path(&kernel)).unwrap()
+        .write_all(&signature.to_der().unwrap()).unwrap();
+    assert_eq!(verifier.verify_kernel(&kernel),
+               Status::Valid(String::from("Test signer")));
+
+    std::fs::File::create(&kernel).unwrap().write_all(b"kernal").unwrap();
+    assert!(!verifier.verify_kernel(&kernel).is_valid());
+    assert_eq!(Verifier::new(Policy::Off).verify_kernel(&kernel),
+               Status::Unchecked);
+}
+
+// Authenticode signature as made by sbsign: SignedData with
+// SpcIndirectDataContent and signed contentType and messageDigest.
+#[cfg(test)]
+fn authenticode_sign(key: &openssl::pkey::PKeyRef<openssl::pkey::Private>,
+                     cert: &openssl::x509::X509, image_digest: &[u8])
+        -> Vec<u8> {
+    const OID: u8 = 0x06;
+    const SET: u8 = 0x31;
+    let oid_spc_indirect = [0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02,
+                            0x01, 0x04];
+    let oid_spc_pe_image = [0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02,
+                            0x01, 0x0f];
+    let oid_pkcs = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01];
+    let pkcs = |suffix: &[u8]| {
+        der_encode(OID, &[&oid_pkcs[..], suffix].concat())
+    };
+    let sha256 = der_encode(DER_SEQUENCE, &[
+        der_encode(OID, OID_SHA256), vec![0x05, 0x00]].concat());
+
+    let indirect = der_encode(DER_SEQUENCE, &[
+        der_encode(DER_SEQUENCE, &[
+            der_encode(OID, &oid_spc_pe_image),
+            vec![0x30, 0x09, 0x03, 0x01, 0x00,
+                 0xa0, 0x04, 0xa2, 0x02, 0x80, 0x00]].concat()),
+        der_encode(DER_SEQUENCE, &[
+            sha256.clone(), der_encode(0x04, image_digest)].concat()),
+    ].concat());
+    let content = Der::parse(&indirect, 0).unwrap().contents(&indirect)
+        .to_vec();
+
+    let attributes = [
+        der_encode(DER_SEQUENCE, &[
+            pkcs(&[0x09, 0x03]),
+            der_encode(SET, &der_encode(OID, &oid_spc_indirect))].concat()),
+        der_encode(DER_SEQUENCE, &[
+            pkcs(&[0x09, 0x04]),
+            der_encode(SET, &der_encode(0x04, &openssl::sha::sha256(
+                &content)))].concat()),
+    ].concat();
+    let mut signer = openssl::sign::Signer::new(
+        openssl::hash::MessageDigest::sha256(), key).unwrap();
+    signer.update(&der_encode(SET, &attributes)).unwrap();
+    let signature = signer.sign_to_vec().unwrap();
+
+    let signer_info = der_encode(DER_SEQUENCE, &[
+        vec![0x02, 0x01, 0x01],
+        der_encode(DER_SEQUENCE, &[
+            cert.issuer_name().to_der().unwrap(),
+            der_encode(0x02, &cert.serial_number().to_bn().unwrap()
+                       .to_vec())].concat()),
+        sha256.clone(),
+        der_encode(DER_CONTEXT_0, &attributes),
+        der_encode(DER_SEQUENCE, &[
+            pkcs(&[0x01, 0x01, 0x01]), vec![0x05, 0x00]].concat()),
+        der_encode(0x04, &signature),
+    ].concat());
+    let signed = der_encode(DER_SEQUENCE, &[
+        vec![0x02, 0x01, 0x01],
+        der_encode(SET, &sha256),
+        der_encode(DER_SEQUENCE, &[
+            der_encode(OID, &oid_spc_indirect),
+            der_encode(DER_CONTEXT_0, &indirect)].concat()),
+        der_encode(DER_CONTEXT_0, &cert.to_der().unwrap()),
+        der_encode(SET, &signer_info),
+    ].concat());
+    der_encode(DER_SEQUENCE, &[
+        pkcs(&[0x07, 0x02]), der_encode(DER_CONTEXT_0, &signed)].concat())
+}
+
+#[cfg(test)]
+fn set_le(image: &mut [u8], offset: usize, value: usize) {
+    for index in 0..4 {
+        image[offset + index] = (value >> (index * 8)) as u8;
+    }
+}
+
+// Image with certificate table holding the signature appended.
+#[cfg(test)]
+fn append_signature(image: &[u8], cert_dir: usize, mut signature: Vec<u8>)
+        -> Vec<u8> {
+    let length = WIN_CERT_HEADER_SIZE + signature.len();
+    let padded = (signature.len() + 7) & !7;
+    signature.resize(padded, 0);
+    let mut signed = image.to_vec();
+    set_le(&mut signed, cert_dir, image.len());
+    set_le(&mut signed, cert_dir + 4, WIN_CERT_HEADER_SIZE + padded);
+    signed.extend_from_slice(&[0, 0, 0, 0, 0x00, 0x02, 0x02, 0x00]);
+    set_le(&mut signed, image.len(), length);
+    signed.extend_from_slice(&signature);
+    signed
+}
+
+#[test]
+fn verifies_signed_pe_images() {
+    // PE32+ headers without sections followed by some code.
+    let mut image = vec![0; 0x200];
+    image[..2].copy_from_slice(PE_MZ_MAGIC);
+    image[PE_OFFSET] = 0x40;
+    image[0x40..0x44].copy_from_slice(PE_MAGIC);
+    let optional = 0x40 + PE_OPTIONAL_HEADER;
+    image[optional - 4] = 240;
+    image[optional..optional + 2].copy_from_slice(&[0x0b, 0x02]);
+    image[optional + PE_SIZE_OF_HEADERS] = 0x48;
+    image[optional + PE_SIZE_OF_HEADERS + 1] = 0x01;
+    image[optional + PE32_PLUS_DATA_DIRS - 4] = 16;
+    for (index, byte) in image[0x148..].iter_mut().enumerate() {
+        *byte = index as u8;
+    }
+    let cert_dir = optional + PE32_PLUS_DATA_DIRS + PE_CERT_TABLE_DIR * 8;
+    let hashed = [&image[..optional + PE_CHECKSUM],
+                  &image[optional + PE_CHECKSUM + 4..cert_dir],
+                  &image[cert_dir + 8..]].concat();
+
+    let (key, cert) = test_signer();
+    let signature = authenticode_sign(&key, &cert,
+                                      &openssl::sha::sha256(&hashed));
+    let mut signed = append_signature(&image, cert_dir, signature);
+    let table_size = signed.len() - image.len();
+    // Checksum is not covered.
+    signed[optional + PE_CHECKSUM] = 0xff;
+
+    let mut verifier = Verifier::new(Policy::Enforce);
+    verifier.certs.push(cert.clone());
+    let path = std::path::Path::new("vmlinuz");
+    assert_eq!(verifier.verify_kernel_data(path, &signed),
+               Status::Valid(String::from("Test signer")));
+
+    let mut tampered = signed.clone();
+    tampered[0x180] ^= 1;
+    assert_eq!(verifier.verify_kernel_data(path, &tampered),
+               Status::Invalid(String::from("image digest does not match")));
+    let mut appended = signed.clone();
+    appended.extend_from_slice(b"payload!");
+    assert!(!verifier.verify_kernel_data(path, &appended).is_valid());
+    // Padding entry in data directories before the certificate table
+    // directory, followed by the valid signature.
+    let mut overlapping = signed.clone();
+    let table = cert_dir - 24;
+    set_le(&mut overlapping, table, image.len() - table);
+    set_le(&mut overlapping, cert_dir, table);
+    set_le(&mut overlapping, cert_dir + 4, signed.len() - table);
+    assert_eq!(verifier.verify_kernel_data(path, &overlapping),
+               Status::Invalid(String::from(
+                   "certificate table overlaps PE header")));
+    // Tables overlapping the headers, out of or at the end of the image.
+    for &(table, size) in &[(0x40, table_size), (cert_dir + 4, table_size),
+                            (image.len(), table_size + 8),
+                            (image.len() + 8, table_size - 8),
+                            (0xffff_fff0, table_size)] {
+        let mut malformed = signed.clone();
+        set_le(&mut malformed, cert_dir, table);
+        set_le(&mut malformed, cert_dir + 4, size);
+        assert!(!verifier.verify_kernel_data(path, &malformed).is_valid());
+    }
+}
+
+#[test]
+fn hashes_pe_sections_in_file_order() {
+    // PE32+ with two sections listed in reverse order of their data, and
+    // padding between them.
+    let mut image = vec![0; 0x400];
+    image[..2].copy_from_slice(PE_MZ_MAGIC);
+    image[PE_OFFSET] = 0x40;
+    image[0x40..0x44].copy_from_slice(PE_MAGIC);
+    image[0x40 + PE_NUMBER_OF_SECTIONS] = 2;
+    let optional = 0x40 + PE_OPTIONAL_HEADER;
+    image[optional - 4] = 240;
+    image[optional..optional + 2].copy_from_slice(&[0x0b, 0x02]);
+    set_le(&mut image, optional + PE_SIZE_OF_HEADERS, 0x200);
+    image[optional + PE32_PLUS_DATA_DIRS - 4] = 16;
+    let sections = optional + 240;
+    for &(index, start, size) in &[(0, 0x300, 0x100), (1, 0x200, 0x80)] {
+        let header = sections + index * PE_SECTION_HEADER_SIZE;
+        set_le(&mut image, header + PE_SECTION_RAW_SIZE, size);
+        set_le(&mut image, header + PE_SECTION_RAW_POINTER, start);
+    }
+    for (index, byte) in image[0x200..].iter_mut().enumerate() {
+        *byte = index as u8 ^ 0x5a;
+    }
+    let cert_dir = optional + PE32_PLUS_DATA_DIRS + PE_CERT_TABLE_DIR * 8;
+    // Data beyond headers and sections starts at their total size.
+    let hashed = [&image[..optional + PE_CHECKSUM],
+                  &image[optional + PE_CHECKSUM + 4..cert_dir],
+                  &image[cert_dir + 8..0x200],
+                  &image[0x200..0x280], &image[0x300..0x400],
+                  &image[0x380..0x400]].concat();
+
+    let (key, cert) = test_signer();
+    let signature = authenticode_sign(&key, &cert,
+                                      &openssl::sha::sha256(&hashed));
+    let signed = append_signature(&image, cert_dir, signature);
+    let mut verifier = Verifier::new(Policy::Enforce);
+    verifier.certs.push(cert.clone());
+    let path = std::path::Path::new("vmlinuz");
+    assert_eq!(verifier.verify_kernel_data(path, &signed),
+               Status::Valid(String::from("Test signer")));
+
+    let mut tampered = signed.clone();
+    tampered[0x220] ^= 1;
+    assert_eq!(verifier.verify_kernel_data(path, &tampered),
+               Status::Invalid(String::from("image digest does not match")));
+    // Section data reaching into the certificate table.
+    let mut malformed = signed.clone();
+    set_le(&mut malformed, sections + PE_SECTION_RAW_SIZE, 0x108);
+    assert_eq!(verifier.verify_kernel_data(path, &malformed),
+               Status::Invalid(String::from("section out of PE image")));
+}
@@ -0,0 +1,213 @@
+// Persistent boot state (one-shot entry etc.). Every key is a small file in
+// the state directory, updates are done by rename so a crash never leaves
+// partially written values.
+
+use std;
+
+pub const DEFAULT_STATE_DIR: &'static str = "/var/lib/kexlinux";
+
+const KEY_NEXT_ENTRY: &'static str = "next-entry";
+// Followed by filesystem UUID of the boot source.
+const KEY_SAVED_ENTRY_PREFIX: &'static str = "saved-entry.";
+
+// TODO: Detailed errors.
+#[derive(Debug)]
+pub struct StateError {}
+
+impl std::convert::From<std::io::Error> for StateError {
+    fn from(_: std::io::Error) -> StateError { StateError{} }
+}
+
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    dir: std::path::PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: std::path::PathBuf) -> StateStore {
+        StateStore{
+            dir: dir,
+        }
+    }
+
+    pub fn dir(&self) -> &std::path::Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        assert!(!key.is_empty() && !key.contains('/') && !key.starts_with('.'),
+                "Invalid state key \"{}\"", key);
+        self.dir.join(key)
+    }
+
+    // Temporary file next to the key, hidden from `get()`.
+    fn temp_path(&self, key: &str, what: &str) -> std::path::PathBuf {
+        self.dir.join(format!(".{}.{}-{}", key, what, std::process::id()))
+    }
+
+    // Make renames and removals in the directory durable.
+    fn sync_dir(&self) -> Result<(), StateError> {
+        try!(try!(std::fs::File::open(&self.dir)).sync_all());
+        Ok(())
+    }
+
+    fn read(path: &std::path::Path) -> Result<Option<String>, StateError> {
+        use std::io::Read;
+
+        let mut value = String::new();
+        match std::fs::File::open(path) {
+            Ok(mut file) => {
+                try!(file.read_to_string(&mut value));
+                Ok(Some(String::from(value.trim_right_matches('\n'))))
+            },
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Ok(None)
+            },
+            Err(err) => {
+                error!("Unable to read state {:?}: {}", path, err);
+                Err(StateError{})
+            },
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, StateError> {
+        StateStore::read(&self.path(key))
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), StateError> {
+        use std::io::Write;
+
+        let path = self.path(key);
+        let temp_path = self.temp_path(key, "new");
+        let result = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::File::create(&temp_path))
+            .and_then(|mut file| {
+                try!(writeln!(file, "{}", value));
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&temp_path, &path));
+        if let Err(err) = result {
+            error!("Unable to write state {:?}: {}", path, err);
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(StateError{});
+        }
+        self.sync_dir()
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), StateError> {
+        let path = self.path(key);
+        match std::fs::remove_file(&path) {
+            Ok(_) => self.sync_dir(),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Ok(())
+            },
+            Err(err) => {
+                error!("Unable to remove state {:?}: {}", path, err);
+                Err(StateError{})
+            },
+        }
+    }
+
+    /// Read and clear the value. Value is moved away by rename first, so
+    /// it is consumed at most once even if reading fails.
+    pub fn take(&self, key: &str) -> Result<Option<String>, StateError> {
+        let path = self.path(key);
+        let taken_path = self.temp_path(key, "taken");
+        match std::fs::rename(&path, &taken_path) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            },
+            Err(err) => {
+                error!("Unable to clear state {:?}: {}", path, err);
+                return Err(StateError{});
+            },
+        }
+        try!(self.sync_dir());
+
+        let value = StateStore::read(&taken_path);
+        if let Err(err) = std::fs::remove_file(&taken_path) {
+            warn!("Unable to remove {:?}: {}", taken_path, err);
+        }
+        value
+    }
+
+    // Name from one-shot entry value if it was set for the source.
+    fn next_entry_for(value: Option<String>, source: &str) -> Option<String> {
+        let value = match value {
+            Some(value) => value,
+            None => return None,
+        };
+        match value.find('\n') {
+            Some(end) if &value[..end] == source => {
+                Some(String::from(&value[end + 1..]))
+            },
+            _ => {
+                info!("One-shot entry is for another boot source, ignored");
+                None
+            },
+        }
+    }
+
+    /// Entry to boot on the next run only, if booting from the source with
+    /// the given key, see `BootSource::key()`.
+    pub fn set_next_entry(&self, source: &str, name: &str)
+            -> Result<(), StateError> {
+        self.set(KEY_NEXT_ENTRY, &format!("{}\n{}", source, name))
+    }
+
+    pub fn next_entry(&self, source: &str)
+            -> Result<Option<String>, StateError> {
+        Ok(StateStore::next_entry_for(try!(self.get(KEY_NEXT_ENTRY)), source))
+    }
+
+    /// Consume the one-shot entry. Entry set for another source is kept.
+    pub fn take_next_entry(&self, source: &str)
+            -> Result<Option<String>, StateError> {
+        match try!(self.next_entry(source)) {
+            Some(_) => Ok(StateStore::next_entry_for(
+                try!(self.take(KEY_NEXT_ENTRY)), source)),
+            None => Ok(None),
+        }
+    }
+
+    /// Entry last booted from the boot source with the given filesystem
+    /// UUID.
+    pub fn saved_entry(&self, uuid: &str) -> Result<Option<String>, StateError> {
+        self.get(&format!("{}{}", KEY_SAVED_ENTRY_PREFIX, uuid))
+    }
+
+    pub fn set_saved_entry(&self, uuid: &str, name: &str)
+            -> Result<(), StateError> {
+        self.set(&format!("{}{}", KEY_SAVED_ENTRY_PREFIX, uuid), name)
+    }
+}
+
+impl Default for StateStore {
+    fn default() -> StateStore {
+        StateStore::new(std::path::PathBuf::from(DEFAULT_STATE_DIR))
+    }
+}
+
+#[test]
+fn takes_value_once() {
+    extern crate tempdir;
+
+    let dir = tempdir::TempDir::new("kexlinux-test").unwrap();
+    let store = StateStore::new(dir.path().join("state"));
+    assert_eq!(store.take_next_entry("UUID=a").unwrap(), None);
+
+    store.set_next_entry("UUID=a", "rescue").unwrap();
+    store.set_next_entry("UUID=a", "linux").unwrap();
+    assert_eq!(store.get(KEY_NEXT_ENTRY).unwrap(),
+               Some(String::from("UUID=a\nlinux")));
+    // Another boot source neither uses nor consumes it.
+    assert_eq!(store.next_entry("UUID=b").unwrap(), None);
+    assert_eq!(store.take_next_entry("UUID=b").unwrap(), None);
+    assert_eq!(store.next_entry("UUID=a").unwrap(),
+               Some(String::from("linux")));
+    assert_eq!(store.take_next_entry("UUID=a").unwrap(),
+               Some(String::from("linux")));
+    assert_eq!(store.take_next_entry("UUID=a").unwrap(), None);
+    assert_eq!(std::fs::read_dir(store.dir()).unwrap().count(), 0);
+}
@@ -0,0 +1,374 @@
+// Implementation of syslinux IPAPPEND/SYSAPPEND: kernel command line
+// options describing the machine are appended according to the bitmask.
+
+use std;
+
+const PATH_SYS: &'static str = "/sys";
+const PATH_PROC: &'static str = "/proc";
+
+pub const IP: u32 = 0x1;
+pub const BOOTIF: u32 = 0x2;
+pub const SYSUUID: u32 = 0x4;
+pub const CPU: u32 = 0x8;
+
+// DMI fields: bit, option name, file in /sys/class/dmi/id.
+const DMI_FIELDS: &'static [(u32, &'static str, &'static str)] = &[
+    (0x10,    "SYSVENDOR",   "sys_vendor"),
+    (0x20,    "SYSPRODUCT",  "product_name"),
+    (0x40,    "SYSVERSION",  "product_version"),
+    (0x80,    "SYSSERIAL",   "product_serial"),
+    (0x100,   "SYSSKU",      "product_sku"),
+    (0x200,   "SYSFAMILY",   "product_family"),
+    (0x400,   "MBVENDOR",    "board_vendor"),
+    (0x800,   "MBPRODUCT",   "board_name"),
+    (0x1000,  "MBVERSION",   "board_version"),
+    (0x2000,  "MBSERIAL",    "board_serial"),
+    (0x4000,  "MBASSET",     "board_asset_tag"),
+    (0x8000,  "BIOSVENDOR",  "bios_vendor"),
+    (0x10000, "BIOSVERSION", "bios_version"),
+    (0x20000, "SYSFF",       "chassis_type"),
+];
+
+#[derive(Debug)]
+struct Route {
+    iface: String,
+    destination: std::net::Ipv4Addr,
+    gateway: std::net::Ipv4Addr,
+    mask: std::net::Ipv4Addr,
+}
+
+#[derive(Debug)]
+struct Interface {
+    name: String,
+    hw_type: u32,
+    mac: String,
+}
+
+/// Source of machine information for SYSAPPEND options.
+#[derive(Debug, Clone)]
+pub struct SysInfo {
+    sys_root: std::path::PathBuf,
+    proc_root: std::path::PathBuf,
+}
+
+fn read_trimmed(path: &std::path::Path) -> Option<String> {
+    use std::io::prelude::*;
+    let mut buf = String::new();
+    match std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut buf)) {
+        Ok(_) => Some(String::from(buf.trim())),
+        Err(_) => None,
+    }
+}
+
+// Same transformation as syslinux does: no spaces, only printable ASCII.
+fn sanitize(value: &str) -> String {
+    value.trim().chars()
+        .filter(|c| c.is_ascii() && !c.is_control())
+        .map(|c| if c == ' ' { '_' } else { c })
+        .collect()
+}
+
+fn parse_route_addr(hex: &str) -> Option<std::net::Ipv4Addr> {
+    // Addresses are in host byte order.
+    u32::from_str_radix(hex, 16).ok().map(|value| {
+        let bytes = value.to_ne_bytes();
+        std::net::Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+    })
+}
+
+fn mask_addr(addr: std::net::Ipv4Addr, mask: std::net::Ipv4Addr) -> u32 {
+    u32::from(addr) & u32::from(mask)
+}
+
+impl SysInfo {
+    pub fn new() -> SysInfo {
+        SysInfo::with_roots(std::path::PathBuf::from(PATH_SYS),
+                            std::path::PathBuf::from(PATH_PROC))
+    }
+
+    /// Read information from alternative locations of /sys and /proc.
+    pub fn with_roots(sys_root: std::path::PathBuf,
+                      proc_root: std::path::PathBuf) -> SysInfo {
+        SysInfo{
+            sys_root: sys_root,
+            proc_root: proc_root,
+        }
+    }
+
+    fn proc_cmdline_option(&self, key: &str) -> Option<String> {
+        let prefix = format!("{}=", key);
+        read_trimmed(&self.proc_root.join("cmdline")).and_then(
+            |cmdline| cmdline.split_whitespace()
+                .find(|option| option.starts_with(&prefix))
+                .map(|option| String::from(&option[prefix.len()..])))
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        let routes = match read_trimmed(&self.proc_root.join("net/route")) {
+            Some(routes) => routes,
+            None => return Vec::new(),
+        };
+
+        routes.lines().skip(1).filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                return None;
+            }
+
+            match (parse_route_addr(fields[1]), parse_route_addr(fields[2]),
+                   parse_route_addr(fields[7])) {
+                (Some(destination), Some(gateway), Some(mask)) => Some(Route{
+                    iface: String::from(fields[0]),
+                    destination: destination,
+                    gateway: gateway,
+                    mask: mask,
+                }),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    fn local_addrs(&self) -> Vec<std::net::Ipv4Addr> {
+        let trie = match read_trimmed(&self.proc_root.join("net/fib_trie")) {
+            Some(trie) => trie,
+            None => return Vec::new(),
+        };
+
+        // Local addresses are leaves followed by "/32 host LOCAL".
+        let mut addrs = Vec::new();
+        let mut last_leaf = None;
+        for line in trie.lines() {
+            let line = line.trim();
+            if line.starts_with("|--") {
+                last_leaf = line[3..].trim().parse::<std::net::Ipv4Addr>().ok();
+            } else if line.starts_with("/32 host LOCAL") {
+                if let Some(addr) = last_leaf {
+                    if !addr.is_loopback() && !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+        }
+        addrs
+    }
+
+    fn interfaces(&self) -> Vec<Interface> {
+        let net_dir = self.sys_root.join("class/net");
+        let mut ifaces: Vec<Interface> = match net_dir.read_dir() {
+            Ok(entries) => entries.filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    let hw_type = read_trimmed(&path.join("type"))
+                        .and_then(|hw_type| hw_type.parse::<u32>().ok());
+                    let mac = read_trimmed(&path.join("address"));
+                    match (hw_type, mac) {
+                        // Skip loopback.
+                        (Some(772), _) => None,
+                        (Some(hw_type), Some(mac)) => Some(Interface{
+                            name: name,
+                            hw_type: hw_type,
+                            mac: mac.to_lowercase(),
+                        }),
+                        _ => None,
+                    }
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        ifaces.sort_by(|a, b| a.name.cmp(&b.name));
+        ifaces
+    }
+
+    // Interface we were most probably booted from: the one matching
+    // BOOTIF of the current boot, the one with default route, or the first
+    // one.
+    fn boot_interface(&self) -> Option<Interface> {
+        let mut ifaces = self.interfaces();
+
+        let boot_mac = self.proc_cmdline_option("BOOTIF")
+            .and_then(|bootif| bootif.splitn(2, '-').nth(1)
+                .map(|mac| mac.replace("-", ":").to_lowercase()));
+        let default_iface = self.routes().into_iter()
+            .find(|route| u32::from(route.destination) == 0)
+            .map(|route| route.iface);
+
+        let position = ifaces.iter()
+            .position(|iface| Some(&iface.mac) == boot_mac.as_ref())
+            .or_else(|| ifaces.iter()
+                .position(|iface| Some(&iface.name) == default_iface.as_ref()));
+        match position {
+            Some(position) => Some(ifaces.remove(position)),
+            None => match ifaces.is_empty() {
+                true => None,
+                false => Some(ifaces.remove(0)),
+            },
+        }
+    }
+
+    fn bootif(&self) -> Option<String> {
+        self.boot_interface().map(|iface| format!(
+            "BOOTIF={:02x}-{}", iface.hw_type, iface.mac.replace(":", "-")))
+    }
+
+    fn ip(&self) -> Option<String> {
+        if let Some(ip) = self.proc_cmdline_option("ip") {
+            return Some(format!("ip={}", ip));
+        }
+
+        let iface = match self.boot_interface() {
+            Some(iface) => iface,
+            None => return None,
+        };
+        let routes: Vec<Route> = self.routes().into_iter()
+            .filter(|route| route.iface == iface.name)
+            .collect();
+        let gateway = routes.iter()
+            .find(|route| u32::from(route.destination) == 0)
+            .map(|route| route.gateway)
+            .unwrap_or(std::net::Ipv4Addr::new(0, 0, 0, 0));
+
+        for addr in self.local_addrs() {
+            let net = routes.iter().find(|route| {
+                u32::from(route.destination) != 0 &&
+                    mask_addr(addr, route.mask) == u32::from(route.destination)
+            });
+            if let Some(net) = net {
+                return Some(format!("ip={}:{}:{}:{}", addr,
+                                    std::net::Ipv4Addr::new(0, 0, 0, 0),
+                                    gateway, net.mask));
+            }
+        }
+        None
+    }
+
+    fn sysuuid(&self) -> Option<String> {
+        read_trimmed(&self.sys_root.join("class/dmi/id/product_uuid"))
+            .map(|uuid| format!("SYSUUID={}", uuid.to_lowercase()))
+    }
+
+    fn cpu(&self) -> Option<String> {
+        let cpuinfo = match read_trimmed(&self.proc_root.join("cpuinfo")) {
+            Some(cpuinfo) => cpuinfo,
+            None => return None,
+        };
+
+        let value = |key: &str| cpuinfo.lines()
+            .find(|line| line.splitn(2, ':').next()
+                .map(|k| k.trim() == key).unwrap_or(false))
+            .and_then(|line| line.splitn(2, ':').nth(1))
+            .map(|v| String::from(v.trim()));
+
+        let family = match value("cpu family")
+                .and_then(|family| family.parse::<u32>().ok()) {
+            Some(family) => std::cmp::min(family, 6),
+            None => return None,
+        };
+        let flags = value("flags").unwrap_or_default();
+        let flags: Vec<&str> = flags.split_whitespace().collect();
+
+        let mut cpu = format!("CPU={}", family);
+        for &(flag, letter) in &[("pae", 'P'), ("nx", 'X'), ("vmx", 'V'),
+                                 ("svm", 'S'), ("lm", 'L')] {
+            if flags.contains(&flag) {
+                cpu.push(letter);
+            }
+        }
+        Some(cpu)
+    }
+
+    /// Options to append for the given SYSAPPEND bitmask, in bit order.
+    pub fn options(&self, flags: u32) -> Vec<String> {
+        let mut options = Vec::new();
+        {
+            let mut push = |option: Option<String>, name: &str| match option {
+                Some(option) => options.push(option),
+                None => warn!("Unable to determine {} for SYSAPPEND", name),
+            };
+
+            if flags & IP != 0 {
+                push(self.ip(), "ip");
+            }
+            if flags & BOOTIF != 0 {
+                push(self.bootif(), "BOOTIF");
+            }
+            if flags & SYSUUID != 0 {
+                push(self.sysuuid(), "SYSUUID");
+            }
+            if flags & CPU != 0 {
+                push(self.cpu(), "CPU");
+            }
+
+            let dmi_dir = self.sys_root.join("class/dmi/id");
+            for &(flag, name, file) in DMI_FIELDS {
+                if flags & flag != 0 {
+                    push(read_trimmed(&dmi_dir.join(file)).map(
+                            |value| format!("{}={}", name, sanitize(&value))),
+                         name);
+                }
+            }
+        }
+        options
+    }
+
+    /// Append options for the given bitmask to the command line.
+    pub fn augment(&self, cmdline: Option<&str>, flags: u32)
+            -> Option<String> {
+        let options = self.options(flags);
+        if options.is_empty() {
+            return cmdline.map(String::from);
+        }
+
+        let options = options.join(" ");
+        Some(match cmdline {
+            Some(cmdline) if !cmdline.trim().is_empty() => {
+                format!("{} {}", cmdline.trim_right(), options)
+            },
+            _ => options,
+        })
+    }
+}
+
+#[test]
+fn builds_options_from_roots() {
+    extern crate tempdir;
+    use std::io::Write;
+
+    let dir = tempdir::TempDir::new("kexlinux-test").unwrap();
+    let sys_root = dir.path().join("sys");
+    let proc_root = dir.path().join("proc");
+
+    let write = |path: std::path::PathBuf, content: &str| {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::File::create(path).unwrap()
+            .write_all(content.as_bytes()).unwrap();
+    };
+    write(sys_root.join("class/net/eth0/type"), "1\n");
+    write(sys_root.join("class/net/eth0/address"), "AA:BB:CC:DD:EE:FF\n");
+    write(sys_root.join("class/net/lo/type"), "772\n");
+    write(sys_root.join("class/net/lo/address"), "00:00:00:00:00:00\n");
+    write(sys_root.join("class/dmi/id/product_uuid"),
+          "4C4C4544-0042-3510-8052-B4C04F303232\n");
+    write(sys_root.join("class/dmi/id/sys_vendor"), "Example Corp.\n");
+    write(proc_root.join("cmdline"), "root=/dev/sda1 quiet\n");
+    write(proc_root.join("net/route"),
+          "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\
+           \t\tMTU\tWindow\tIRTT\n\
+           eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n\
+           eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n");
+    write(proc_root.join("net/fib_trie"),
+          "Main:\n\
+           \x20 +-- 0.0.0.0/0 3 0 5\n\
+           \x20    |-- 192.168.1.42\n\
+           \x20       /32 host LOCAL\n");
+
+    let info = SysInfo::with_roots(sys_root, proc_root);
+    assert_eq!(
+        info.augment(Some("quiet"), IP | BOOTIF | SYSUUID | 0x10),
+        Some(String::from(
+            "quiet ip=192.168.1.42:0.0.0.0:192.168.1.1:255.255.255.0 \
+             BOOTIF=01-aa-bb-cc-dd-ee-ff \
+             SYSUUID=4c4c4544-0042-3510-8052-b4c04f303232 \
+             SYSVENDOR=Example_Corp.")));
+    assert_eq!(info.augment(Some("quiet"), 0), Some(String::from("quiet")));
+}
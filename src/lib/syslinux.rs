@@ -0,0 +1,682 @@
+use std;
+
+extern crate syslinux_conf;
+extern crate tempdir;
+
+use bootcount;
+use bootconf::{BootConf, BootEntry, ConfSource, MenuItem, SerialConsole,
+               Submenu};
+use check;
+use kexlinux::KexLinuxError;
+use signature;
+use syslinux_extra;
+
+const SERIAL_DEVICE_PREFIX: &'static str = "/dev/ttyS";
+// Wait for CTS assertion before transmitting.
+const SERIAL_FLOW_CTS: u32 = 0x010;
+
+#[derive(Debug)]
+pub struct SyslinuxConf {
+    pub timeout: Option<f64>,
+    pub total_timeout: Option<f64>,
+
+    pub ontimeout: syslinux_conf::Label,
+    pub ontimeout_name: String,
+    pub onerror: Option<syslinux_conf::Label>,
+    pub onerror_name: Option<String>,
+
+    pub default_name: Option<String>,
+    pub labels: syslinux_conf::Labels,
+
+    /// MENU, TEXT, SYSAPPEND, FDT and INITRD directives.
+    pub directives: syslinux_extra::Directives,
+}
+
+impl SyslinuxConf {
+    fn fix_append(mut label: syslinux_conf::Label) -> syslinux_conf::Label {
+        match label.kernel_or_config {
+            syslinux_conf::KernelOrConfig::Kernel(ref mut kernel) => {
+                kernel.append = kernel.append.clone().and_then(
+                    |v| if v == "-" { None } else { Some(v) })
+            },
+        };
+        label
+    }
+
+    fn filter_map_labels(label_defaults: syslinux_conf::Label,
+                     labels: syslinux_conf::Labels) -> syslinux_conf::Labels {
+        use std::iter::FromIterator;
+        syslinux_conf::Labels::from_iter(labels.into_iter()
+            .map(
+                |(label_name, label)| {
+                    use syslinux::syslinux_conf::ApplyDefaults;
+                    (label_name, SyslinuxConf::fix_append(
+                        label.apply_defaults(&label_defaults)))
+                })
+            .filter(
+                |&(ref label_name, ref label)| {
+                    match label.kernel_or_config {
+                        syslinux_conf::KernelOrConfig::Kernel(ref kernel) => {
+                            match kernel.kernel_file {
+                                Some(syslinux_conf::KernelFile::Linux(_)) => {
+                                    true
+                                },
+
+                                // Other kernel types are not supported.
+                                Some(ref kernel_file) => {
+                                    warn!(
+                                        "Unsupported kernel type {:?} in \
+                                        \"{}\", skipping",
+                                        kernel_file, label_name);
+                                    false
+                                },
+
+                                None => {
+                                    warn!(
+                                        "No kernel in \"{}\", skipping",
+                                        label_name);
+                                    false
+                                },
+                            }
+                        },
+                    }
+            }))
+    }
+
+    pub fn from_conf(conf: syslinux_conf::SyslinuxConf,
+                     directives: syslinux_extra::Directives)
+            -> Result<SyslinuxConf, KexLinuxError> {
+        let labels = SyslinuxConf::filter_map_labels(
+            conf.global.label_defaults, conf.labels);
+
+        // MENU DEFAULT takes precedence: with menus, DEFAULT usually points
+        // to the menu module.
+        let menu_default = labels.iter()
+            .map(|(label_name, _)| label_name)
+            .find(|label_name| directives.labels.get(*label_name)
+                .map(|label| label.menu_default)
+                .unwrap_or(false))
+            .cloned();
+        let default_name = menu_default.or(conf.global.default);
+
+        let default = default_name.as_ref().and_then(
+            |default_name| labels.get(default_name).cloned());
+        let ontimeout_name = conf.global.ontimeout.and_then(
+            |ontimeout_name| match labels.get(&ontimeout_name) {
+                Some(_) => Some(ontimeout_name),
+                None => None,
+            });
+
+        let default_name = match default {
+            Some(_) => default_name,
+            None => {
+                warn!("Default label not found: \"{:?}\"", default_name);
+                None
+            },
+        };
+
+        let ontimeout_name = try!(ontimeout_name.or(
+            default_name.clone().or_else(|| match labels.front() {
+                Some((first_name, _)) => Some(first_name.clone()),
+                None => {
+                    error!("Nothing to boot");
+                    None
+                },
+            })).ok_or(KexLinuxError{}));
+        let onerror_name = conf.global.onerror.and_then(
+            |onerror_name| match labels.get(&onerror_name) {
+                Some(_) => Some(onerror_name),
+                None => None,
+            });
+
+        Ok(SyslinuxConf{
+            timeout: conf.global.timeout,
+            total_timeout: conf.global.total_timeout,
+
+            ontimeout: try!(labels.get(&ontimeout_name).cloned()
+                .ok_or(KexLinuxError{})),
+            ontimeout_name: ontimeout_name,
+            onerror: onerror_name.as_ref().and_then(
+                |onerror_name| labels.get(onerror_name).cloned()),
+            onerror_name: onerror_name,
+
+            default_name: default_name,
+            labels: labels,
+
+            directives: directives,
+        })
+    }
+
+    // Paths in labels read from copy `from` of configuration on `to`.
+    fn rebase(self, from: &std::path::Path, to: &std::path::Path)
+            -> SyslinuxConf {
+        use std::iter::FromIterator;
+
+        let rebase_path = |path: &mut std::path::PathBuf| {
+            let rebased = path.strip_prefix(from).map(|p| to.join(p));
+            if let Ok(rebased) = rebased {
+                *path = rebased;
+            }
+        };
+        let rebase_label = |mut label: syslinux_conf::Label| {
+            match label.kernel_or_config {
+                syslinux_conf::KernelOrConfig::Kernel(ref mut kernel) => {
+                    if let Some(syslinux_conf::KernelFile::Linux(
+                            ref mut path)) = kernel.kernel_file {
+                        rebase_path(path);
+                    }
+                    if let Some(ref mut initrd) = kernel.initrd {
+                        rebase_path(initrd);
+                    }
+                },
+            }
+            label
+        };
+
+        SyslinuxConf{
+            ontimeout: rebase_label(self.ontimeout),
+            onerror: self.onerror.map(&rebase_label),
+            labels: syslinux_conf::Labels::from_iter(
+                self.labels.into_iter()
+                    .map(|(name, label)| (name, rebase_label(label)))),
+            ..self
+        }
+    }
+
+    fn initrds(&self, kernel: &syslinux_conf::Kernel,
+               label_directives: &syslinux_extra::LabelDirectives)
+            -> Vec<std::path::PathBuf> {
+        let initrds = match label_directives.initrds.is_empty() {
+            true => &self.directives.initrds,
+            false => &label_directives.initrds,
+        };
+
+        // syslinux_conf treats comma-separated list as a single path.
+        match (&kernel.initrd, initrds.len() > 1) {
+            (&Some(_), true) => initrds.clone(),
+            (&Some(ref initrd), false) => vec![initrd.clone()],
+            (&None, _) => Vec::new(),
+        }
+    }
+
+    fn label_to_entry(&self, name: &str, label: &syslinux_conf::Label)
+            -> Option<BootEntry> {
+        let kernel = match label.kernel_or_config {
+            syslinux_conf::KernelOrConfig::Kernel(ref kernel) => kernel,
+        };
+
+        let label_directives = self.directives.labels.get(name).cloned()
+            .unwrap_or_default();
+        let initrds = self.initrds(kernel, &label_directives);
+
+        match kernel.kernel_file {
+            Some(syslinux_conf::KernelFile::Linux(ref kernel_file)) => {
+                Some(BootEntry{
+                    name: String::from(name),
+                    title: label_directives.menu_label,
+
+                    kernel: kernel_file.clone(),
+                    initrds: initrds,
+                    cmdline: kernel.append.clone(),
+                    devicetree: label_directives.fdt,
+                    devicetree_dir: label_directives.fdt_dir,
+                    devicetree_overlays: label_directives.fdt_overlays,
+                    sysappend: label_directives.sysappend
+                        .or(self.directives.sysappend)
+                        .unwrap_or(0),
+
+                    help: label_directives.help,
+                    hidden: label_directives.hidden,
+                    disabled: label_directives.disabled,
+                    passwd: label_directives.passwd,
+                    // Label names can not be changed, sidecar is used.
+                    counter: bootcount::Counter::from_name(name),
+                })
+            },
+
+            _ => None,
+        }
+    }
+
+    /// Convert into format-neutral configuration.
+    pub fn to_boot_conf(&self) -> BootConf {
+        let entries: Vec<BootEntry> = self.labels.iter()
+            .filter_map(|(name, label)| self.label_to_entry(name, label))
+            .collect();
+        let menu = SyslinuxConf::menu_items(&self.directives.menu.items,
+                                            &entries);
+
+        BootConf{
+            timeout: self.timeout,
+            total_timeout: self.total_timeout,
+
+            ontimeout_name: self.ontimeout_name.clone(),
+            onerror_name: self.onerror_name.clone(),
+
+            default_name: self.default_name.clone(),
+            entries: entries,
+
+            title: self.directives.menu.title.clone(),
+            menu: menu,
+            master_passwd: self.directives.master_passwd.clone(),
+
+            serial: self.directives.serial.as_ref().map(|serial| SerialConsole{
+                device: std::path::PathBuf::from(
+                    format!("{}{}", SERIAL_DEVICE_PREFIX, serial.port)),
+                baud: serial.baud,
+                flow_control: serial.flow_control & SERIAL_FLOW_CTS != 0,
+            }),
+            local_console: self.directives.console.unwrap_or(true),
+        }
+    }
+
+    fn menu_items(nodes: &[syslinux_extra::MenuNode], entries: &[BootEntry])
+            -> Vec<MenuItem> {
+        nodes.iter()
+            .filter_map(|node| match *node {
+                syslinux_extra::MenuNode::Label(ref name) => {
+                    // Labels filtered out as unbootable are not shown.
+                    match entries.iter().any(|entry| entry.name == *name) {
+                        true => Some(MenuItem::Entry(name.clone())),
+                        false => None,
+                    }
+                },
+
+                syslinux_extra::MenuNode::Separator => {
+                    Some(MenuItem::Separator)
+                },
+
+                syslinux_extra::MenuNode::Submenu(ref block) => {
+                    Some(MenuItem::Submenu(Submenu{
+                        label: block.label.clone(),
+                        title: block.title.clone(),
+                        passwd: block.passwd.clone(),
+                        items: SyslinuxConf::menu_items(&block.items, entries),
+                    }))
+                },
+            })
+            .collect()
+    }
+}
+
+enum Detection {
+    Auto,
+    Type(syslinux_conf::LocalConfType),
+    ConfFilePath(std::path::PathBuf),
+}
+
+/// `ConfSource` for syslinux, isolinux and extlinux configuration files.
+pub struct SyslinuxSource {
+    detection: Detection,
+    verifier: signature::Verifier,
+}
+
+impl SyslinuxSource {
+    /// Autodetect configuration type and file.
+    pub fn new() -> SyslinuxSource {
+        SyslinuxSource{
+            detection: Detection::Auto,
+            verifier: signature::Verifier::default(),
+        }
+    }
+
+    /// Autodetect configuration file of the given type.
+    pub fn with_type(local_type: syslinux_conf::LocalConfType)
+            -> SyslinuxSource {
+        SyslinuxSource{
+            detection: Detection::Type(local_type),
+            verifier: signature::Verifier::default(),
+        }
+    }
+
+    /// Use configuration file at the given path.
+    pub fn with_conf_file_path(conf_file_path: std::path::PathBuf)
+            -> SyslinuxSource {
+        SyslinuxSource{
+            detection: Detection::ConfFilePath(conf_file_path),
+            verifier: signature::Verifier::default(),
+        }
+    }
+
+    /// Require detached signatures of the configuration file and all files
+    /// it includes, see `signature::Verifier::verify_file_data()`.
+    pub fn set_verifier(&mut self, verifier: signature::Verifier) {
+        self.verifier = verifier;
+    }
+
+    fn type_name(&self) -> Option<&'static str> {
+        match self.detection {
+            Detection::Type(syslinux_conf::LocalConfType::SysLinux) => {
+                Some("syslinux")
+            },
+            Detection::Type(syslinux_conf::LocalConfType::IsoLinux) => {
+                Some("isolinux")
+            },
+            Detection::Type(syslinux_conf::LocalConfType::ExtLinux) => {
+                Some("extlinux")
+            },
+            _ => None,
+        }
+    }
+
+    /// Path to the main configuration file on the root.
+    pub fn conf_file_path(&self, root: &std::path::Path)
+            -> Option<std::path::PathBuf> {
+        match self.detection {
+            Detection::ConfFilePath(ref conf_file_path) => {
+                match conf_file_path.strip_prefix(root) {
+                    Ok(_) => Some(conf_file_path.clone()),
+                    Err(_) => Some(syslinux_extra::resolve_path(
+                        root, root, &conf_file_path.to_string_lossy())),
+                }
+            },
+            _ => syslinux_extra::find_conf_file(root, self.type_name()),
+        }
+    }
+
+    fn read_directives(&self, root: &std::path::Path,
+                       verifier: &signature::Verifier)
+            -> Result<syslinux_extra::Directives, KexLinuxError> {
+        match self.conf_file_path(root) {
+            Some(conf_file_path) => {
+                syslinux_extra::read(root, &conf_file_path, verifier)
+            },
+            None => {
+                debug!("Configuration file not found on {:?}, additional \
+                        directives ignored", root);
+                Ok(syslinux_extra::Directives::default())
+            },
+        }
+    }
+
+    /// Check configuration, including labels that would be skipped.
+    pub fn check_syslinux_conf(&self, root: &std::path::Path)
+            -> Result<check::Report, KexLinuxError> {
+        use syslinux::syslinux_conf::ApplyDefaults;
+
+        let conf = try!(try!(self.reader(root)).read());
+        let directives = try!(self.read_directives(
+            root, &signature::Verifier::default()));
+        let mut report = check::Report::new();
+        self.check_signatures(&mut report, &directives);
+
+        let mut bootable = Vec::new();
+        for (name, label) in conf.labels.iter() {
+            let label = label.clone().apply_defaults(
+                &conf.global.label_defaults);
+            match label.kernel_or_config {
+                syslinux_conf::KernelOrConfig::Kernel(ref kernel) => {
+                    match kernel.kernel_file {
+                        Some(syslinux_conf::KernelFile::Linux(_)) => {
+                            bootable.push(name.clone())
+                        },
+                        Some(ref kernel_file) => report.warning(
+                            Some(name), format!(
+                                "Unsupported kernel type {:?}, label will be \
+                                 skipped", kernel_file)),
+                        None => report.warning(
+                            Some(name),
+                            String::from("No kernel, label will be skipped")),
+                    }
+                },
+            }
+        }
+
+        let references = vec![("DEFAULT", &conf.global.default),
+                              ("ONTIMEOUT", &conf.global.ontimeout),
+                              ("ONERROR", &conf.global.onerror)];
+        for (what, name) in references {
+            let name = match *name {
+                Some(ref name) => name,
+                None => continue,
+            };
+
+            if bootable.contains(name) {
+                continue;
+            } else if conf.labels.get(name).is_some() {
+                report.error(None, format!(
+                    "{} points to label \"{}\" that can not be booted",
+                    what, name));
+            } else if what == "DEFAULT" && name.ends_with(".c32") {
+                // Menu module, default label is set by MENU DEFAULT.
+                continue;
+            } else {
+                report.error(None, format!(
+                    "{} points to unknown label \"{}\"", what, name));
+            }
+        }
+
+        match SyslinuxConf::from_conf(conf, directives) {
+            Ok(conf) => {
+                for entry in &conf.to_boot_conf().entries {
+                    check::check_entry(&mut report, entry);
+                }
+            },
+            Err(_) => report.error(None, String::from("Nothing to boot")),
+        }
+
+        Ok(report)
+    }
+
+    // Report files that would be refused or warned about when reading.
+    fn check_signatures(&self, report: &mut check::Report,
+                        directives: &syslinux_extra::Directives) {
+        let policy = self.verifier.policy();
+        if policy == signature::Policy::Off {
+            return;
+        }
+
+        let mut statuses: Vec<(String, signature::Status)> =
+            directives.files.iter()
+                .map(|&(ref path, ref data)| {
+                    (format!("Configuration file {:?}", path),
+                     self.verifier.verify_file_data(path, data))
+                })
+                .collect();
+        if statuses.is_empty() {
+            statuses.push((String::from("Configuration"),
+                           signature::Status::Unsigned));
+        }
+        for (what, status) in statuses {
+            if status.is_valid() {
+                continue;
+            }
+            let message = format!("{}: {}", what, status);
+            match policy {
+                signature::Policy::Enforce => report.error(None, message),
+                _ => report.warning(None, message),
+            }
+        }
+    }
+
+    fn copy_type(local_type: &syslinux_conf::LocalConfType)
+            -> syslinux_conf::LocalConfType {
+        match *local_type {
+            syslinux_conf::LocalConfType::SysLinux => {
+                syslinux_conf::LocalConfType::SysLinux
+            },
+            syslinux_conf::LocalConfType::IsoLinux => {
+                syslinux_conf::LocalConfType::IsoLinux
+            },
+            syslinux_conf::LocalConfType::ExtLinux => {
+                syslinux_conf::LocalConfType::ExtLinux
+            },
+        }
+    }
+
+    fn reader(&self, root: &std::path::Path)
+            -> Result<syslinux_conf::Reader, KexLinuxError> {
+        let root = root.to_path_buf();
+        Ok(try!(match self.detection {
+            Detection::Auto => syslinux_conf::Reader::from_local(root),
+            Detection::Type(ref local_type) => {
+                syslinux_conf::Reader::from_local_type(
+                    root, SyslinuxSource::copy_type(local_type))
+            },
+            Detection::ConfFilePath(ref conf_file_path) => {
+                syslinux_conf::Reader::from_local_conf_file_path(
+                    root, conf_file_path.clone())
+            },
+        }))
+    }
+
+    pub fn read_syslinux_conf(&self, root: &std::path::Path)
+            -> Result<SyslinuxConf, KexLinuxError> {
+        let directives = try!(self.read_directives(root, &self.verifier));
+        // syslinux_conf reads files by itself, give it the verified copies
+        // so that it parses the same data.
+        let copy = match (self.verifier.policy(), directives.files.is_empty()) {
+            (signature::Policy::Off, _) => None,
+            (_, true) => {
+                try!(self.verifier.enforce("Configuration",
+                                           &signature::Status::Unsigned));
+                None
+            },
+            (_, false) => Some(try!(copy_files(root, &directives.files))),
+        };
+        let conf = match copy {
+            Some((ref copy, ref conf_file_path)) => {
+                try!(try!(syslinux_conf::Reader::from_local_conf_file_path(
+                    copy.path().to_path_buf(), conf_file_path.clone())).read())
+            },
+            None => try!(try!(self.reader(root)).read()),
+        };
+        let conf = try!(SyslinuxConf::from_conf(conf, directives));
+        Ok(match copy {
+            Some((ref copy, _)) => conf.rebase(copy.path(), root),
+            None => conf,
+        })
+    }
+}
+
+// Relative path with `.` and `..` resolved lexically, never above the top.
+fn normalize(path: &std::path::Path) -> std::path::PathBuf {
+    let mut normalized = std::path::PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(name) => normalized.push(name),
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            },
+            _ => (),
+        }
+    }
+    normalized
+}
+
+// Write contents of files on `root` to the same paths in a temporary
+// directory. Returns the directory and the copy of the first file.
+fn copy_files(root: &std::path::Path,
+              files: &[(std::path::PathBuf, Vec<u8>)])
+        -> Result<(tempdir::TempDir, std::path::PathBuf), KexLinuxError> {
+    use std::io::Write;
+
+    let copy = try!(tempdir::TempDir::new("kexlinux-conf"));
+    let mut copy_paths = Vec::new();
+    for &(ref path, ref data) in files {
+        let copy_path = match path.strip_prefix(root) {
+            Ok(relative) => copy.path().join(normalize(relative)),
+            Err(_) => {
+                error!("Configuration file {:?} is not on {:?}", path, root);
+                return Err(KexLinuxError{});
+            },
+        };
+        if let Some(parent) = copy_path.parent() {
+            try!(std::fs::create_dir_all(parent));
+        }
+        try!(try!(std::fs::File::create(&copy_path)).write_all(data));
+        copy_paths.push(copy_path);
+    }
+    match copy_paths.into_iter().next() {
+        Some(conf_file_path) => Ok((copy, conf_file_path)),
+        None => Err(KexLinuxError{}),
+    }
+}
+
+impl ConfSource for SyslinuxSource {
+    fn name(&self) -> &str {
+        "syslinux"
+    }
+
+    fn read(&self, root: &std::path::Path) -> Result<BootConf, KexLinuxError> {
+        Ok(try!(self.read_syslinux_conf(root)).to_boot_conf())
+    }
+
+    fn check(&self, root: &std::path::Path)
+            -> Result<check::Report, KexLinuxError> {
+        self.check_syslinux_conf(root)
+    }
+}
+
+#[test]
+fn reads_same_files_as_syslinux_conf() {
+    extern crate tempdir;
+    use std::io::Write;
+
+    // Relative paths are resolved against directory of the main file,
+    // decoys are where they would be found otherwise.
+    let files = [
+        ("boot/syslinux/syslinux.cfg", "main", "\
+            INCLUDE labels.cfg\n\
+            MENU INCLUDE /boot/menu.cfg\n"),
+        ("boot/syslinux/labels.cfg", "labels", "INCLUDE nested/more.cfg\n"),
+        ("boot/syslinux/nested/more.cfg", "nested", "INCLUDE other.cfg\n"),
+        ("boot/syslinux/other.cfg", "other", ""),
+        ("boot/menu.cfg", "menu", ""),
+        ("labels.cfg", "root_decoy", ""),
+        ("boot/syslinux/nested/other.cfg", "nested_decoy", ""),
+        ("boot/syslinux/boot/menu.cfg", "menu_decoy", ""),
+    ];
+    let root = tempdir::TempDir::new("kexlinux-test").unwrap();
+    for &(path, label, text) in &files {
+        let path = root.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write!(std::fs::File::create(&path).unwrap(),
+               "LABEL {}\n\tKERNEL /vmlinuz\n{}", label, text).unwrap();
+    }
+
+    let source = SyslinuxSource::with_type(
+        syslinux_conf::LocalConfType::SysLinux);
+    let directives = source.read_directives(
+        root.path(), &signature::Verifier::default()).unwrap();
+    let mut read: Vec<&str> = directives.files.iter()
+        .map(|&(ref path, _)| files.iter()
+             .find(|file| root.path().join(file.0) == *path)
+             .unwrap().1)
+        .collect();
+    read.sort();
+    assert_eq!(read, vec!["labels", "main", "menu", "nested", "other"]);
+
+    let conf = source.reader(root.path()).unwrap().read().unwrap();
+    let mut labels: Vec<&str> = conf.labels.iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    labels.sort();
+    assert_eq!(labels, read);
+}
+
+#[test]
+fn copies_verified_files() {
+    extern crate tempdir;
+    use std::io::Read;
+
+    let root = tempdir::TempDir::new("kexlinux-test").unwrap();
+    let files = vec![
+        (root.path().join("boot/syslinux.cfg"), b"verified".to_vec()),
+        (root.path().join("boot/../../../etc/more.cfg"), b"more".to_vec()),
+    ];
+    let (copy, conf_file_path) = copy_files(root.path(), &files).unwrap();
+    assert_eq!(conf_file_path, copy.path().join("boot/syslinux.cfg"));
+
+    let read = |path: &std::path::Path| {
+        let mut data = Vec::new();
+        std::fs::File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    };
+    assert_eq!(read(&conf_file_path), b"verified");
+    assert_eq!(read(&copy.path().join("etc/more.cfg")), b"more");
+
+    let elsewhere = vec![(std::path::PathBuf::from("/syslinux.cfg"),
+                          Vec::new())];
+    assert!(copy_files(root.path(), &elsewhere).is_err());
+}
@@ -0,0 +1,516 @@
+// Directives not handled by syslinux_conf are read here directly from
+// configuration files.
+
+use std;
+
+use kexlinux::KexLinuxError;
+use signature;
+
+const CONF_PATHS_SYSLINUX: &'static [&'static str] = &[
+    "boot/syslinux/syslinux.cfg",
+    "syslinux/syslinux.cfg",
+    "syslinux.cfg",
+];
+
+const CONF_PATHS_ISOLINUX: &'static [&'static str] = &[
+    "boot/isolinux/isolinux.cfg",
+    "isolinux/isolinux.cfg",
+    "isolinux.cfg",
+];
+
+const CONF_PATHS_EXTLINUX: &'static [&'static str] = &[
+    "boot/extlinux/extlinux.conf",
+    "extlinux/extlinux.conf",
+    "extlinux.conf",
+];
+
+// Protection against include loops.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Default)]
+pub struct LabelDirectives {
+    /// `MENU LABEL`
+    pub menu_label: Option<String>,
+    /// `MENU HIDE`
+    pub hidden: bool,
+    /// `MENU DISABLE`
+    pub disabled: bool,
+    /// `MENU DEFAULT`
+    pub menu_default: bool,
+    /// `TEXT HELP` ... `ENDTEXT`
+    pub help: Option<String>,
+    /// `MENU PASSWD`
+    pub passwd: Option<String>,
+    /// `IPAPPEND` or `SYSAPPEND` bitmask
+    pub sysappend: Option<u32>,
+    /// `FDT` or `DEVICETREE`
+    pub fdt: Option<std::path::PathBuf>,
+    /// `FDTDIR` or `DEVICETREEDIR`
+    pub fdt_dir: Option<std::path::PathBuf>,
+    /// `FDTOVERLAYS`
+    pub fdt_overlays: Vec<std::path::PathBuf>,
+    /// Comma-separated `INITRD` list
+    pub initrds: Vec<std::path::PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MenuNode {
+    Label(String),
+    Separator,
+    Submenu(MenuBlock),
+}
+
+/// Top-level menu or `MENU BEGIN` ... `MENU END` block.
+#[derive(Debug, Clone, Default)]
+pub struct MenuBlock {
+    pub tag: Option<String>,
+    pub label: Option<String>,
+    pub title: Option<String>,
+    /// `MENU PASSWD` inside the block
+    pub passwd: Option<String>,
+    pub items: Vec<MenuNode>,
+}
+
+/// `SERIAL port [baudrate [flowcontrol]]`
+#[derive(Debug, Clone)]
+pub struct Serial {
+    /// Port number, I/O port addresses of standard ports are converted.
+    pub port: u32,
+    pub baud: u32,
+    /// Flow control bitmask as in syslinux.
+    pub flow_control: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Directives {
+    /// Global `IPAPPEND` or `SYSAPPEND` bitmask
+    pub sysappend: Option<u32>,
+    /// Global comma-separated `INITRD` list
+    pub initrds: Vec<std::path::PathBuf>,
+    /// `MENU MASTER PASSWD`
+    pub master_passwd: Option<String>,
+    pub serial: Option<Serial>,
+    /// `CONSOLE` value, output to the local console is enabled by default.
+    pub console: Option<bool>,
+
+    pub menu: MenuBlock,
+    pub labels: std::collections::HashMap<String, LabelDirectives>,
+
+    /// Configuration file and included files with their contents as
+    /// verified, in order of reading.
+    pub files: Vec<(std::path::PathBuf, Vec<u8>)>,
+}
+
+/// Candidate configuration file paths relative to the root, in syslinux
+/// search order.
+pub fn conf_paths(type_name: Option<&str>) -> Vec<&'static str> {
+    match type_name {
+        Some("syslinux") => CONF_PATHS_SYSLINUX.to_vec(),
+        Some("isolinux") => CONF_PATHS_ISOLINUX.to_vec(),
+        Some("extlinux") => CONF_PATHS_EXTLINUX.to_vec(),
+        _ => CONF_PATHS_SYSLINUX.iter()
+            .chain(CONF_PATHS_ISOLINUX.iter())
+            .chain(CONF_PATHS_EXTLINUX.iter())
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Resolve path as seen by syslinux into path on the mounted root.
+pub fn resolve_path(root: &std::path::Path, base_dir: &std::path::Path,
+                    path: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(path);
+    match path.strip_prefix("/") {
+        Ok(relative) => root.join(relative),
+        Err(_) => base_dir.join(path),
+    }
+}
+
+/// Split line into lowercase keyword and the rest.
+pub fn split_keyword(line: &str) -> (String, &str) {
+    let line = line.trim_left();
+    match line.find(char::is_whitespace) {
+        Some(pos) => (line[..pos].to_lowercase(), line[pos..].trim()),
+        None => (line.to_lowercase(), ""),
+    }
+}
+
+/// Parse decimal or hexadecimal (with "0x" prefix) number.
+pub fn parse_number(value: &str) -> Option<u32> {
+    let value = value.trim();
+    if value.starts_with("0x") || value.starts_with("0X") {
+        u32::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse::<u32>().ok()
+    }
+}
+
+// Standard I/O port addresses of COM1-COM4.
+const SERIAL_IO_PORTS: &'static [u32] = &[0x3f8, 0x2f8, 0x3e8, 0x2e8];
+const SERIAL_DEFAULT_BAUD: u32 = 9600;
+
+fn parse_serial(args: &str) -> Option<Serial> {
+    let mut values = args.split_whitespace().map(parse_number);
+    let port = match values.next() {
+        Some(Some(port)) => {
+            match SERIAL_IO_PORTS.iter().position(|&address| address == port) {
+                Some(index) => index as u32,
+                None => port,
+            }
+        },
+        _ => return None,
+    };
+    let baud = match values.next() {
+        Some(Some(baud)) => baud,
+        Some(None) => return None,
+        None => SERIAL_DEFAULT_BAUD,
+    };
+    let flow_control = match values.next() {
+        Some(Some(flow_control)) => flow_control,
+        Some(None) => return None,
+        None => 0,
+    };
+
+    Some(Serial{
+        port: port,
+        baud: baud,
+        flow_control: flow_control,
+    })
+}
+
+fn strip_hotkey_marker(text: &str) -> String {
+    text.replace("^", "")
+}
+
+struct Parser<'a> {
+    root: &'a std::path::Path,
+    base_dir: std::path::PathBuf,
+    verifier: &'a signature::Verifier,
+
+    directives: Directives,
+    stack: Vec<MenuBlock>,
+    label: Option<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn current_block(&mut self) -> &mut MenuBlock {
+        match self.stack.last_mut() {
+            Some(block) => block,
+            None => &mut self.directives.menu,
+        }
+    }
+
+    fn current_label(&mut self) -> Option<&mut LabelDirectives> {
+        match self.label {
+            Some(ref name) => Some(self.directives.labels.entry(name.clone())
+                .or_insert_with(LabelDirectives::default)),
+            None => None,
+        }
+    }
+
+    fn parse_menu(&mut self, args: &str, depth: usize)
+            -> Result<(), KexLinuxError> {
+        let (keyword, args) = split_keyword(args);
+        match keyword.as_str() {
+            "label" => {
+                let text = strip_hotkey_marker(args);
+                if self.label.is_some() {
+                    self.current_label().unwrap().menu_label = Some(text);
+                } else if !self.stack.is_empty() {
+                    self.current_block().label = Some(text);
+                }
+            },
+
+            "title" => self.current_block().title = Some(String::from(args)),
+
+            "hide" => match self.current_label() {
+                Some(label) => label.hidden = true,
+                None => debug!("Global MENU HIDE ignored"),
+            },
+
+            "disable" => match self.current_label() {
+                Some(label) => label.disabled = true,
+                None => debug!("MENU DISABLE outside of label ignored"),
+            },
+
+            "default" => match self.current_label() {
+                Some(label) => label.menu_default = true,
+                None => debug!("MENU DEFAULT outside of label ignored"),
+            },
+
+            "passwd" => {
+                let passwd = Some(String::from(args));
+                if self.label.is_some() {
+                    self.current_label().unwrap().passwd = passwd;
+                } else if !self.stack.is_empty() {
+                    self.current_block().passwd = passwd;
+                } else {
+                    debug!("Global MENU PASSWD ignored");
+                }
+            },
+
+            "master" => match split_keyword(args) {
+                (ref keyword, passwd) if keyword == "passwd" => {
+                    self.directives.master_passwd = Some(String::from(passwd))
+                },
+                _ => warn!("Invalid MENU MASTER directive \"{}\"", args),
+            },
+
+            "separator" => self.current_block().items.push(MenuNode::Separator),
+
+            "begin" => {
+                self.label = None;
+                self.stack.push(MenuBlock{
+                    tag: match args {
+                        "" => None,
+                        tag => Some(String::from(tag)),
+                    },
+                    ..MenuBlock::default()
+                });
+            },
+
+            "end" => {
+                self.label = None;
+                match self.stack.pop() {
+                    Some(block) => {
+                        self.current_block().items.push(
+                            MenuNode::Submenu(block))
+                    },
+                    None => warn!("Unbalanced MENU END ignored"),
+                }
+            },
+
+            "include" => {
+                let path = args.split_whitespace().next().unwrap_or("");
+                try!(self.include(path, depth));
+            },
+
+            _ => (),  // Only affects appearance.
+        };
+        Ok(())
+    }
+
+    fn include(&mut self, path: &str, depth: usize)
+            -> Result<(), KexLinuxError> {
+        if depth >= MAX_INCLUDE_DEPTH {
+            error!("Too many nested includes at \"{}\"", path);
+            return Err(KexLinuxError{});
+        }
+
+        let path = resolve_path(self.root, &self.base_dir, path);
+        self.parse_file(&path, depth + 1)
+    }
+
+    fn parse_file(&mut self, path: &std::path::Path, depth: usize)
+            -> Result<(), KexLinuxError> {
+        debug!("Scanning {:?} for additional directives...", path);
+
+        let data = {
+            use std::io::prelude::*;
+            let mut data = Vec::new();
+            match std::fs::File::open(path)
+                    .and_then(|mut file| file.read_to_end(&mut data)) {
+                Ok(_) => data,
+                Err(err) => match depth {
+                    0 => {
+                        error!("Unable to read {:?}: {}", path, err);
+                        return Err(KexLinuxError::from(err));
+                    },
+                    // Skipped by syslinux too. Nothing is parsed, so there
+                    // is nothing to verify.
+                    _ => {
+                        warn!("Unable to read included {:?}, skipping: {}",
+                              path, err);
+                        return Ok(());
+                    },
+                },
+            }
+        };
+        // Verify what is parsed, the file could change after reading.
+        let status = self.verifier.verify_file_data(path, &data);
+        try!(self.verifier.enforce(
+            &format!("Configuration file {:?}", path), &status));
+        let text = String::from_utf8_lossy(&data).into_owned();
+        self.directives.files.push((path.to_path_buf(), data));
+
+        let mut lines = text.lines();
+        while let Some(line) = lines.next() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, args) = split_keyword(line);
+            match keyword.as_str() {
+                "label" => {
+                    let name = String::from(args);
+                    self.current_block().items.push(
+                        MenuNode::Label(name.clone()));
+                    self.label = Some(name);
+                },
+
+                "menu" => try!(self.parse_menu(args, depth)),
+
+                "text" => {
+                    let mut text = Vec::new();
+                    while let Some(line) = lines.next() {
+                        if split_keyword(line).0 == "endtext" {
+                            break;
+                        }
+                        text.push(line.trim());
+                    }
+
+                    if split_keyword(args).0 == "help" {
+                        match self.current_label() {
+                            Some(label) => label.help = Some(text.join("\n")),
+                            None => debug!("TEXT HELP outside of label ignored"),
+                        }
+                    }
+                },
+
+                "include" => {
+                    let path = args.split_whitespace().next().unwrap_or("");
+                    try!(self.include(path, depth));
+                },
+
+                "ipappend" | "sysappend" => match parse_number(args) {
+                    Some(flags) => match self.current_label() {
+                        Some(label) => label.sysappend = Some(flags),
+                        None => self.directives.sysappend = Some(flags),
+                    },
+                    None => warn!("Invalid {} value \"{}\"",
+                                  keyword.to_uppercase(), args),
+                },
+
+                "serial" => match parse_serial(args) {
+                    Some(serial) => self.directives.serial = Some(serial),
+                    None => warn!("Invalid SERIAL value \"{}\"", args),
+                },
+
+                "console" => match parse_number(args) {
+                    Some(value) => self.directives.console = Some(value != 0),
+                    None => warn!("Invalid CONSOLE value \"{}\"", args),
+                },
+
+                "initrd" => {
+                    let initrds: Vec<std::path::PathBuf> = args.split(',')
+                        .map(|path| path.trim())
+                        .filter(|path| !path.is_empty())
+                        .map(|path| resolve_path(self.root, &self.base_dir, path))
+                        .collect();
+                    match self.current_label() {
+                        Some(label) => label.initrds = initrds,
+                        None => self.directives.initrds = initrds,
+                    }
+                },
+
+                "fdt" | "devicetree" | "fdtdir" | "devicetreedir" |
+                "fdtoverlays" => {
+                    let paths: Vec<std::path::PathBuf> = args
+                        .split_whitespace()
+                        .map(|path| resolve_path(self.root, &self.base_dir, path))
+                        .collect();
+                    match self.current_label() {
+                        Some(label) => match keyword.as_str() {
+                            "fdt" | "devicetree" => {
+                                label.fdt = paths.into_iter().next()
+                            },
+                            "fdtdir" | "devicetreedir" => {
+                                label.fdt_dir = paths.into_iter().next()
+                            },
+                            _ => label.fdt_overlays = paths,
+                        },
+                        None => warn!("{} outside of label ignored",
+                                      keyword.to_uppercase()),
+                    }
+                },
+
+                _ => (),  // Handled by syslinux_conf.
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// Find configuration file on the root.
+pub fn find_conf_file(root: &std::path::Path, type_name: Option<&str>)
+        -> Option<std::path::PathBuf> {
+    conf_paths(type_name).into_iter()
+        .map(|path| root.join(path))
+        .find(|path| path.is_file())
+}
+
+/// Read additional directives from configuration file and all files it
+/// includes, checking signature of each file before parsing it.
+pub fn read(root: &std::path::Path, conf_file_path: &std::path::Path,
+            verifier: &signature::Verifier)
+        -> Result<Directives, KexLinuxError> {
+    let mut parser = Parser{
+        root: root,
+        base_dir: conf_file_path.parent()
+            .unwrap_or(root).to_path_buf(),
+        verifier: verifier,
+
+        directives: Directives::default(),
+        stack: Vec::new(),
+        label: None,
+    };
+    try!(parser.parse_file(conf_file_path, 0));
+
+    while let Some(block) = parser.stack.pop() {
+        warn!("MENU BEGIN without MENU END in {:?}", conf_file_path);
+        parser.current_block().items.push(MenuNode::Submenu(block));
+    }
+
+    Ok(parser.directives)
+}
+
+#[test]
+fn reads_menu_structure() {
+    extern crate tempdir;
+    use std::io::Write;
+
+    let root = tempdir::TempDir::new("kexlinux-test").unwrap();
+    let conf_file_path = root.path().join("syslinux.cfg");
+    std::fs::File::create(&conf_file_path).unwrap().write_all(b"\
+        # Not UTF-8: \xff\n\
+        INCLUDE missing.cfg\n\
+        MENU TITLE Boot menu\n\
+        LABEL linux\n\
+        \tMENU LABEL ^Linux\n\
+        \tMENU DEFAULT\n\
+        \tTEXT HELP\n\
+        \t\tFirst line\n\
+        \t\tSecond line\n\
+        \tENDTEXT\n\
+        MENU SEPARATOR\n\
+        MENU BEGIN tools\n\
+        \tMENU TITLE Tools\n\
+        \tLABEL rescue\n\
+        \t\tMENU HIDE\n\
+        MENU END\n").unwrap();
+
+    let directives = read(root.path(), &conf_file_path,
+                          &signature::Verifier::default()).unwrap();
+    assert_eq!(directives.menu.title, Some(String::from("Boot menu")));
+    assert_eq!(directives.menu.items.len(), 3);
+
+    let linux = &directives.labels["linux"];
+    assert_eq!(linux.menu_label, Some(String::from("Linux")));
+    assert!(linux.menu_default);
+    assert_eq!(linux.help, Some(String::from("First line\nSecond line")));
+    assert!(directives.labels["rescue"].hidden);
+    assert_eq!(directives.files.len(), 1);
+    assert_eq!(directives.files[0].0, conf_file_path);
+    assert!(directives.files[0].1.starts_with(b"# Not UTF-8: \xff\n"));
+
+    match directives.menu.items[2] {
+        MenuNode::Submenu(ref block) => {
+            assert_eq!(block.tag, Some(String::from("tools")));
+            assert_eq!(block.title, Some(String::from("Tools")));
+            assert_eq!(block.items.len(), 1);
+        },
+        _ => panic!("Submenu expected"),
+    }
+}